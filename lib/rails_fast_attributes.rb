@@ -2,6 +2,7 @@ require "helix_runtime"
 require "rails_fast_attributes/native"
 require "rails_fast_attributes/version"
 require "active_model"
+require "active_support/json"
 require "active_model/attribute"
 require "active_model/attribute_set"
 require "active_model/attribute/user_provided_default"
@@ -11,6 +12,21 @@ require "active_record/relation/query_attribute"
 
 module RailsFastAttributes
   ORIGINAL_ATTRIBUTE = ActiveModel::Attribute
+  ORIGINAL_ATTRIBUTE_SET = ActiveModel::AttributeSet
+
+  # An error raised while assigning `column` of the row at index `row` in
+  # `Builder#build_from_user_rows`.
+  RowError = Struct.new(:row, :column, :error)
+
+  # Ruby names a class after the first constant it is assigned to, and Marshal
+  # writes objects under that name. This briefly puts `klass` in place of
+  # `namespace::name` so that it's named after the stock class.
+  def self.name_after(namespace, name, klass)
+    original = namespace.send(:remove_const, name) if namespace.const_defined?(name, false)
+    namespace.const_set(name, klass)
+    namespace.send(:remove_const, name)
+    namespace.const_set(name, original) if original
+  end
 
   class Attribute
     UNINITIALIZED_ORIGINAL_VALUE = Object.new
@@ -23,7 +39,14 @@ module RailsFastAttributes
       ORIGINAL_ATTRIBUTE.null(name)
     end
 
-    module UserProvidedDefault
+    stock_classes.each do |name, klass|
+      RailsFastAttributes.name_after(ORIGINAL_ATTRIBUTE, name, klass)
+      const_set(name, klass)
+    end
+
+    Null = ORIGINAL_ATTRIBUTE::Null
+
+    class UserProvidedDefault
       def self.new(name, value, ty, original_attr = nil)
         Attribute.user_provided_default(name, value, ty, original_attr)
       end
@@ -39,7 +62,29 @@ module RailsFastAttributes
 
   class AttributeSet
     Builder = RailsFastAttributes::Builder
-    YAMLEncoder = ActiveModel::AttributeSet::YAMLEncoder
+
+    RailsFastAttributes.name_after(ActiveModel, :AttributeSet, stock_class)
+
+    # Used by Active Record's `encode_with` and `init_with`. Attributes whose
+    # type is the model's default type are written without one, as stock
+    # Rails does, so either can load the other's YAML.
+    class YAMLEncoder
+      def initialize(default_types)
+        @default_types = default_types
+      end
+
+      def encode(attribute_set, coder)
+        coder["concise_attributes"] = attribute_set.concise_attributes(default_types)
+      end
+
+      def decode(coder)
+        coder["attributes"] || AttributeSet.from_concise_attributes(coder["concise_attributes"], default_types)
+      end
+
+      private
+
+      attr_reader :default_types
+    end
   end
 end
 
@@ -47,3 +92,17 @@ ActiveModel.send(:remove_const, :Attribute)
 ActiveModel::Attribute = RailsFastAttributes::Attribute
 ActiveModel.send(:remove_const, :AttributeSet)
 ActiveModel::AttributeSet = RailsFastAttributes::AttributeSet
+
+# YAML written by Rails 4.2 through 5.1 refers to these under `ActiveRecord`,
+# and Rails 7 writes attribute sets as `ActiveModel::LazyAttributeSet`.
+legacy_yaml_classes = {
+  "ActiveRecord::AttributeSet" => "ActiveModel::AttributeSet",
+  "ActiveRecord::LazyAttributeHash" => "ActiveModel::LazyAttributeHash",
+  "ActiveModel::LazyAttributeSet" => "ActiveModel::AttributeSet",
+}
+RailsFastAttributes::Attribute.stock_classes.each_key do |name|
+  legacy_yaml_classes["ActiveRecord::Attribute::#{name}"] = "ActiveModel::Attribute::#{name}"
+end
+Psych.load_tags = Psych.load_tags.merge(
+  legacy_yaml_classes.map { |name, class_name| ["!ruby/object:#{name}", class_name] }.to_h,
+)
@@ -1,5 +1,20 @@
 module ActiveModel
   RSpec.describe AttributeSet do
+    def with_stock_active_model
+      ours = [ActiveModel::Attribute, ActiveModel::AttributeSet]
+      replace_active_model(RailsFastAttributes::ORIGINAL_ATTRIBUTE, RailsFastAttributes::ORIGINAL_ATTRIBUTE_SET)
+      yield
+    ensure
+      replace_active_model(*ours)
+    end
+
+    def replace_active_model(attribute, attribute_set)
+      ActiveModel.send(:remove_const, :Attribute)
+      ActiveModel.const_set(:Attribute, attribute)
+      ActiveModel.send(:remove_const, :AttributeSet)
+      ActiveModel.const_set(:AttributeSet, attribute_set)
+    end
+
     specify "building a new set from raw attributes" do
       builder = AttributeSet::Builder.new(foo: Type::Integer.new, bar: Type::Float.new)
       attributes = builder.build_from_database(foo: "1.1", bar: "2.2")
@@ -214,6 +229,60 @@ module ActiveModel
       expect(attributes.fetch_value(:foo)).to eq("value from user")
     end
 
+    specify "write_from_user leaves the attribute untouched when validation fails" do
+      type = Type::Integer.new
+      type.define_singleton_method(:assert_valid_value) do |value|
+        raise ArgumentError if value == "invalid"
+      end
+      builder = AttributeSet::Builder.new(foo: type)
+      attributes = builder.build_from_database(foo: "1")
+
+      expect { attributes.write_from_user(:foo, "invalid") }.to raise_error(ArgumentError)
+
+      expect(attributes[:foo].name).to eq(:foo)
+      expect(attributes[:foo].type).to eq(type)
+      expect(attributes[:foo].value_before_type_cast).to eq("1")
+      expect(attributes[:foo]).not_to be_changed
+      expect(attributes.fetch_value(:foo)).to eq(1)
+    end
+
+    specify "types can read other attributes of the set they're being cast for" do
+      attributes = nil
+      type = Type::Value.new
+      type.define_singleton_method(:deserialize) { |value| "#{value}-#{attributes.fetch_value(:bar)}" }
+      builder = AttributeSet::Builder.new(foo: type, bar: Type::Value.new)
+      attributes = builder.build_from_database(foo: "1", bar: "2")
+
+      expect(attributes.to_hash).to eq({ foo: "1-2", bar: "2" })
+    end
+
+    specify "modifying the set while it is being read raises" do
+      attributes = nil
+      type = Type::Value.new
+      type.define_singleton_method(:deserialize) { |value| attributes.write_from_user(:bar, value) }
+      builder = AttributeSet::Builder.new(foo: type, bar: Type::Value.new)
+      attributes = builder.build_from_database(foo: "1", bar: "2")
+
+      expect { attributes.to_hash }.to raise_error(RuntimeError, /while it is being read/)
+      expect {
+        attributes.each_value { attributes.write_from_database(:bar, "3") }
+      }.to raise_error(RuntimeError, /while it is being read/)
+      expect(attributes.fetch_value(:bar)).to eq("2")
+    end
+
+    specify "modifying the set while it is being modified raises" do
+      attributes = nil
+      type = Type::Value.new
+      type.define_singleton_method(:assert_valid_value) { |value| attributes.write_from_user(:bar, value) }
+      builder = AttributeSet::Builder.new(foo: type, bar: Type::Value.new)
+      attributes = builder.build_from_database(foo: "1", bar: "2")
+
+      expect {
+        attributes.write_from_user(:foo, "3")
+      }.to raise_error(RuntimeError, /while it is already being modified/)
+      expect(attributes.values_before_type_cast).to eq({ foo: "1", bar: "2" })
+    end
+
     def attributes_with_uninitialized_key
       builder = AttributeSet::Builder.new(foo: Type::Integer.new, bar: Type::Float.new)
       builder.build_from_database(foo: "1.1")
@@ -268,6 +337,420 @@ module ActiveModel
       expect(attributes).to eq(marshalled)
     end
 
+    specify "marshalling writes each type once" do
+      type = Type::Integer.new
+      builder = AttributeSet::Builder.new(foo: type, bar: type, baz: Type::Value.new)
+      attributes = builder.build_from_database(foo: "1", bar: "2")
+      attributes.write_from_user(:foo, "3")
+
+      header, version, types, _ = attributes._dump_data
+
+      expect(header).to eq("rails_fast_attributes")
+      expect(version).to eq(2)
+      expect(types.size).to eq(2)
+      expect(Marshal.load(Marshal.dump(attributes))).to eq(attributes)
+    end
+
+    specify "the version 1 marshal format can still be loaded" do
+      builder = AttributeSet::Builder.new(foo: Type::Integer.new)
+      attributes = builder.build_from_database(foo: "1")
+      loaded = AttributeSet.allocate
+      loaded._load_data([attributes[:foo]])
+
+      expect(loaded).to eq(attributes)
+    end
+
+    specify "marshal data from a newer version raises LoadError" do
+      data = ["rails_fast_attributes", 3, [], []]
+
+      expect { AttributeSet.allocate._load_data(data) }.to raise_error(RailsFastAttributes::LoadError, /version 3/)
+    end
+
+    specify "marshal data with too long a chain of original attributes raises LoadError" do
+      chain = ["foo", 0, 1, "x"] * 1001 + ["foo", 0, 2, "x"]
+      data = ["rails_fast_attributes", 2, [Type::Value.new], chain]
+
+      expect { AttributeSet.allocate._load_data(data) }.to raise_error(RailsFastAttributes::LoadError, /more than 1000/)
+      expect(AttributeSet.allocate.tap { |set| set._load_data(["rails_fast_attributes", 2, [Type::Value.new], chain.drop(4)]) }[:foo].value).to eq("x")
+    end
+
+    specify "attribute sets dumped by stock Rails can be loaded" do
+      dumped = with_stock_active_model do
+        builder = RailsFastAttributes::ORIGINAL_ATTRIBUTE_SET::Builder.new("foo" => Type::Integer.new, "bar" => Type::Integer.new)
+        attributes = builder.build_from_database("foo" => "1", "bar" => "2")
+        attributes.write_from_user("foo", "3")
+        Marshal.dump(attributes)
+      end
+
+      loaded = Marshal.load(dumped)
+
+      expect(loaded).to be_a(RailsFastAttributes::AttributeSet)
+      expect(loaded["foo"].value).to eq(3)
+      expect(loaded["foo"]).to be_changed
+      expect(loaded["bar"].value).to eq(2)
+    end
+
+    specify "attribute sets can be dumped in a format stock Rails can load" do
+      builder = AttributeSet::Builder.new("foo" => Type::Integer.new, "bar" => Type::Integer.new)
+      attributes = builder.build_from_database("foo" => "1")
+      attributes.write_from_user("foo", "2")
+
+      dumped = Marshal.dump(attributes.to_stock)
+      loaded = with_stock_active_model { Marshal.load(dumped) }
+
+      expect(loaded).to be_an_instance_of(RailsFastAttributes::ORIGINAL_ATTRIBUTE_SET)
+      expect(loaded["foo"].value).to eq(2)
+      expect(loaded["foo"]).to be_changed
+      expect(loaded.key?("bar")).not_to be
+      expect(Marshal.load(dumped)).to eq(attributes)
+      expect(Marshal.load(Marshal.dump(attributes))).to be_an_instance_of(AttributeSet)
+    end
+
+    specify "only sets returned by to_stock are dumped in the stock format" do
+      builder = AttributeSet::Builder.new("foo" => Type::Integer.new)
+      attributes = builder.build_from_database("foo" => "1")
+
+      expect(AttributeSet).to be(RailsFastAttributes::AttributeSet)
+      expect(attributes.to_stock).to be_a(AttributeSet)
+      expect(Marshal.dump(attributes)).not_to include("ActiveModel::AttributeSet")
+      expect(Marshal.dump(attributes.to_stock)).to include("ActiveModel::AttributeSet")
+      expect(ActiveRecord.const_defined?(:AttributeSet, false)).not_to be
+    end
+
+    it "can be yaml encoded" do
+      builder = AttributeSet::Builder.new("foo" => Type::Integer.new, "bar" => Type::Integer.new, "baz" => Type::Integer.new)
+      attributes = builder.build_from_database("foo" => "1", "bar" => "2")
+      attributes.write_from_user("foo", "3")
+
+      loaded = YAML.load(YAML.dump(attributes))
+
+      expect(loaded).to eq(attributes)
+      expect(loaded["foo"]).to be_changed
+    end
+
+    specify "yaml encoding writes the stock Rails structure" do
+      type = Type::Integer.new
+      builder = AttributeSet::Builder.new("foo" => type, "bar" => type)
+      attributes = builder.build_from_database("foo" => "1", "bar" => "2")
+      attributes.write_from_user("foo", "3")
+
+      yaml = YAML.dump(attributes)
+      loaded = with_stock_active_model { YAML.load(yaml) }
+
+      expect(yaml.scan("ActiveModel::Type::Integer").size).to eq(1)
+      expect(loaded).to be_an_instance_of(RailsFastAttributes::ORIGINAL_ATTRIBUTE_SET)
+      expect(loaded["foo"].value).to eq(3)
+      expect(loaded["bar"].value).to eq(2)
+    end
+
+    specify "yaml from stock Rails is loaded without materializing it in Ruby" do
+      yaml = with_stock_active_model do
+        builder = RailsFastAttributes::ORIGINAL_ATTRIBUTE_SET::Builder.new("foo" => Type::Integer.new, "bar" => Type::Integer.new)
+        attributes = builder.build_from_database("foo" => "1")
+        attributes.write_from_user("foo", "2")
+        YAML.dump(attributes)
+      end
+
+      expect_any_instance_of(LazyAttributeHash).not_to receive(:materialize)
+      loaded = YAML.load(yaml)
+
+      expect(loaded["foo"].value).to eq(2)
+      expect(loaded["foo"]).to be_changed
+      expect(loaded.key?("bar")).not_to be
+      expect(loaded.keys).to eq(["foo"])
+    end
+
+    specify "the yaml encoder leaves out default types" do
+      type = Type::Integer.new
+      builder = AttributeSet::Builder.new("foo" => type, "bar" => Type::Integer.new)
+      attributes = builder.build_from_database("foo" => "1", "bar" => "2")
+      attributes.write_from_user("foo", "3")
+      encoder = AttributeSet::YAMLEncoder.new("foo" => type, "bar" => type)
+
+      coder = {}
+      encoder.encode(attributes, coder)
+      yaml = YAML.dump(coder)
+      loaded = encoder.decode(YAML.load(yaml))
+      stock = with_stock_active_model do
+        RailsFastAttributes::ORIGINAL_ATTRIBUTE_SET::YAMLEncoder.new("foo" => type, "bar" => type).decode(YAML.load(yaml))
+      end
+
+      expect(coder["concise_attributes"].map(&:name)).to eq(["foo", "bar"])
+      expect(coder["concise_attributes"].map { |attr| attr.type.nil? }).to eq([true, false])
+      expect(loaded).to eq(attributes)
+      expect(loaded["foo"].type).to be(type)
+      expect(loaded["foo"]).to be_changed
+      expect(stock["foo"].value).to eq(3)
+      expect(stock["bar"].value).to eq(2)
+    end
+
+    Dir[File.expand_path("../fixtures/yaml/rails-*.yml", __FILE__)].sort.each do |path|
+      version = File.basename(path, ".yml").sub("rails-", "")
+
+      specify "yaml written by Rails #{version} can be loaded" do
+        attributes = YAML.load_file(path)
+
+        expect(attributes).to be_a(RailsFastAttributes::AttributeSet)
+        expect(attributes["foo"].value).to eq(3)
+        # Rails 4.2 didn't keep track of the original attribute
+        expect(attributes["foo"]).to be_changed unless version == "4.2"
+        expect(attributes["bar"].value).to eq(2)
+        expect(attributes["bar"]).not_to be_changed
+        expect(attributes.key?("baz")).not_to be
+        expect(attributes["qux"].value).to eq(4) if attributes.key?("qux")
+        expect(attributes["quux"].value).to eq(5) if attributes.key?("quux")
+      end
+    end
+
+    specify "to_json matches as_json of the cast values" do
+      builder = AttributeSet::Builder.new(
+        int: Type::Integer.new,
+        str: Type::String.new,
+        time: Type::DateTime.new,
+        dec: Type::Decimal.new,
+        float: Type::Float.new,
+        bool: Type::Boolean.new,
+        null: Type::Value.new,
+        nested: Type::Value.new,
+        missing: Type::Value.new,
+      )
+      attributes = builder.build_from_database(
+        int: "1",
+        str: "<a href=\"x\">\u2028\n",
+        time: "2018-01-02 03:04:05.678901",
+        dec: "1.50",
+        float: "1e20",
+        bool: "t",
+        null: nil,
+        nested: { a: [1, 2.5, nil, { b: :c }], "d" => Time.at(0).utc },
+      )
+      expected = ActiveSupport::JSON.encode(attributes.to_hash)
+
+      expect(attributes.to_json).to eq(expected)
+      expect(JSON.parse(attributes.to_json).keys).not_to include("missing")
+    end
+
+    specify "to_json handles time zones and settings like ActiveSupport" do
+      builder = AttributeSet::Builder.new(foo: Type::Value.new, bar: Type::Value.new, baz: Type::Value.new, qux: Type::Value.new, quux: Type::Value.new)
+      attributes = builder.build_from_database(
+        foo: Time.new(1969, 12, 31, 23, 59, 59.5r, "-05:30"),
+        bar: Time.at(1_500_000_000, 123_456, :usec).utc.in_time_zone("Tokyo"),
+        baz: Time.at(0).utc.in_time_zone("London"),
+        qux: Time.utc(2020, 1, 15, 12).in_time_zone("London"),
+        quux: Time.utc(2020, 1, 15, 12).in_time_zone("UTC"),
+      )
+
+      expect(attributes.to_json).to eq(ActiveSupport::JSON.encode(attributes.to_hash))
+      expect(JSON.parse(attributes.to_json)["qux"]).to eq("2020-01-15T12:00:00.000+00:00")
+      expect(JSON.parse(attributes.to_json)["quux"]).to eq("2020-01-15T12:00:00.000Z")
+
+      begin
+        ActiveSupport::JSON::Encoding.time_precision = 0
+        ActiveSupport.escape_html_entities_in_json = false
+        expect(attributes.to_json).to eq(ActiveSupport::JSON.encode(attributes.to_hash))
+      ensure
+        ActiveSupport::JSON::Encoding.time_precision = 3
+        ActiveSupport.escape_html_entities_in_json = true
+      end
+    end
+
+    specify "to_json supports only, except and methods_hash" do
+      builder = AttributeSet::Builder.new(foo: Type::Integer.new, bar: Type::Integer.new, baz: Type::Integer.new)
+      attributes = builder.build_from_database(foo: "1", bar: "2", baz: "3")
+
+      expect(attributes.to_json(only: [:foo, "bar"])).to eq('{"foo":1,"bar":2}')
+      expect(attributes.to_json(except: :bar)).to eq('{"foo":1,"baz":3}')
+      expect(attributes.to_json(only: :foo, methods_hash: { foo: "x", qux: [4] })).to eq('{"foo":"x","qux":[4]}')
+    end
+
+    specify "to_json ignores the state given by the json gem" do
+      builder = AttributeSet::Builder.new(foo: Type::Integer.new, bar: Type::Integer.new)
+      attributes = builder.build_from_database(foo: "1", bar: "2")
+
+      expect(JSON.generate(attributes)).to eq('{"foo":1,"bar":2}')
+      expect(JSON.generate([attributes])).to eq('[{"foo":1,"bar":2}]')
+    end
+
+    specify "dump_json writes an array of attribute sets to an IO" do
+      builder = AttributeSet::Builder.new(foo: Type::Integer.new, bar: Type::String.new)
+      sets = 3.times.map { |i| builder.build_from_database(foo: i.to_s, bar: "x" * 30_000) }
+      io = StringIO.new
+
+      expect(RailsFastAttributes.dump_json(sets, io)).to eq(io)
+      expect(io.string).to eq(ActiveSupport::JSON.encode(sets.map(&:to_hash)))
+      expect(RailsFastAttributes.dump_json([], StringIO.new).string).to eq("[]")
+      expect { RailsFastAttributes.dump_json([1], StringIO.new) }.to raise_error(TypeError)
+    end
+
+    specify "write_csv writes RFC 4180 CSV of cast values in attribute order" do
+      builder = AttributeSet::Builder.new(foo: Type::Integer.new, bar: Type::String.new, baz: Type::Float.new)
+      sets = [
+        builder.build_from_database(foo: "1", bar: "plain", baz: "1.5"),
+        builder.build_from_database(foo: "2", bar: "a \"quoted\", value\nwith a newline", baz: nil),
+        builder.build_from_database(foo: "3", bar: "", baz: nil),
+      ]
+      io = StringIO.new
+
+      expect(RailsFastAttributes.write_csv(sets, nil, io)).to eq(io)
+      expect(io.string).to eq("foo,bar,baz\r\n1,plain,1.5\r\n2,\"a \"\"quoted\"\", value\nwith a newline\",\r\n3,\"\",\r\n")
+      expect(CSV.parse(io.string)).to eq([%w[foo bar baz], %w[1 plain 1.5], ["2", "a \"quoted\", value\nwith a newline", nil], ["3", "", nil]])
+    end
+
+    specify "write_csv takes a column list and can write values before type cast" do
+      builder = AttributeSet::Builder.new(foo: Type::Integer.new, bar: Type::Boolean.new, baz: Type::Value.new)
+      sets = [builder.build_from_database(foo: "01", bar: "t")]
+      io = StringIO.new
+
+      RailsFastAttributes.write_csv(sets, [:bar, "foo", :baz, :missing], io)
+      RailsFastAttributes.write_csv(sets, [:bar, :foo], io, before_type_cast: true, headers: false)
+
+      expect(io.string).to eq("bar,foo,baz,missing\r\ntrue,1,,\r\nt,01\r\n")
+      expect { RailsFastAttributes.write_csv([1], nil, StringIO.new) }.to raise_error(TypeError)
+    end
+
+    specify "write_csv converts strings to UTF-8" do
+      latin1 = "caf\xE9".force_encoding(Encoding::ISO_8859_1)
+      builder = AttributeSet::Builder.new(foo: Type::Value.new, bar: Type::Value.new)
+      sets = [builder.build_from_database(foo: latin1, bar: Class.new { define_method(:to_s) { latin1 } }.new)]
+      io = StringIO.new
+
+      RailsFastAttributes.write_csv(sets, nil, io)
+
+      expect(io.string.b).to eq("foo,bar\r\ncaf\u00e9,caf\u00e9\r\n".b)
+    end
+
+    specify "write_arrow writes an Arrow IPC stream or file" do
+      builder = AttributeSet::Builder.new(
+        id: Type::Integer.new,
+        name: Type::String.new,
+        price: Type::Decimal.new(precision: 10, scale: 2),
+        created_at: Type::DateTime.new,
+      )
+      sets = [
+        builder.build_from_database(id: "1", name: "foo", price: "1.50", created_at: "2018-01-02 03:04:05"),
+        builder.build_from_database(id: "2", name: nil),
+      ]
+
+      stream = RailsFastAttributes.write_arrow(builder, sets, StringIO.new).string.b
+      file = RailsFastAttributes.write_arrow(builder, sets, StringIO.new, format: :file, batch_size: 1).string.b
+
+      expect(stream).to start_with("\xFF\xFF\xFF\xFF".b)
+      expect(stream).to end_with("\xFF\xFF\xFF\xFF\x00\x00\x00\x00".b)
+      expect(file).to start_with("ARROW1\x00\x00".b)
+      expect(file).to end_with("ARROW1")
+
+      created_at = (sets[0][:created_at].value.to_r * 1_000_000).to_i
+      expected = { "id" => [1, 2], "name" => ["foo", nil], "price" => [150, nil], "created_at" => [created_at, nil] }
+      [[ArrowReader.read_stream(stream), 1], [ArrowReader.read_file(file), 2]].each do |result, batch_count|
+        fields = result.fields
+        expect(fields.map { |field| field.string(0) }).to eq(%w[id name price created_at])
+        expect(fields.map { |field| field.int(2, "C", 1) }).to eq([2, 5, 7, 10])
+        expect(fields.map { |field| field.int(1, "C", 1) }).to all(eq(1))
+        expect([fields[0].table(3).int(0, "l<", 4), fields[0].table(3).int(1, "C", 1)]).to eq([64, 1])
+        expect([fields[2].table(3).int(0, "l<", 4), fields[2].table(3).int(1, "l<", 4)]).to eq([10, 2])
+        expect([fields[3].table(3).int(0, "s<", 2), fields[3].table(3).string(1)]).to eq([2, "UTC"])
+        expect(result.batches.size).to eq(batch_count)
+        expect(result.columns).to eq(expected)
+      end
+      expect { RailsFastAttributes.write_arrow(builder, sets, StringIO.new, format: :csv) }.to raise_error(ArgumentError)
+    end
+
+    specify "msgpack round trips raw values against the builder's types" do
+      builder = AttributeSet::Builder.new(foo: Type::Integer.new, bar: Type::String.new, baz: Type::Float.new, qux: Type::Value.new)
+      attributes = builder.build_from_database(foo: "1", bar: "b\u00e9", baz: 2.5)
+
+      loaded = AttributeSet.from_msgpack(builder, attributes.to_msgpack(builder))
+
+      expect(loaded).to eq(attributes)
+      expect(loaded[:foo].value).to eq(1)
+      expect(loaded[:bar].value).to eq("b\u00e9")
+      expect(loaded[:baz].value).to eq(2.5)
+      expect(loaded.key?(:qux)).not_to be
+    end
+
+    specify "msgpack keeps integers, nil, booleans and binary strings" do
+      builder = AttributeSet::Builder.new(foo: Type::Value.new, bar: Type::Value.new, baz: Type::Value.new, qux: Type::Value.new)
+      attributes = builder.build_from_database(foo: 2**40, bar: nil, baz: false, qux: "\xFF".b)
+
+      loaded = AttributeSet.from_msgpack(builder, attributes.to_msgpack(builder))
+
+      expect(loaded[:foo].value_before_type_cast).to eq(2**40)
+      expect(loaded[:bar].value_before_type_cast).to be_nil
+      expect(loaded[:baz].value_before_type_cast).to eq(false)
+      expect(loaded[:qux].value_before_type_cast).to eq("\xFF".b)
+      expect(loaded[:qux].value_before_type_cast.encoding).to eq(Encoding::BINARY)
+    end
+
+    specify "msgpack writes columns the builder has no type for by name" do
+      builder = AttributeSet::Builder.new({ foo: Type::Integer.new, bar: Type::String.new }, {}, fallback_type: Type::Value.new)
+      attributes = builder.build_from_database({ foo: "1", baz: "2", "qux" => "3", corge: "4" }, baz: Type::Integer.new, quux: Type::Float.new)
+
+      bytes = attributes.to_msgpack(builder)
+      loaded = AttributeSet.from_msgpack(builder, bytes, baz: Type::Integer.new, quux: Type::Float.new)
+
+      expect(loaded).to eq(attributes)
+      expect(loaded.keys).to eq(attributes.keys)
+      expect(loaded[:baz].value).to eq(2)
+      expect(loaded[:qux].value).to eq("3")
+      expect(loaded[:qux].name).to eq("qux")
+      expect(loaded[:corge].name).to eq(:corge)
+      expect(AttributeSet.from_msgpack(builder, bytes)[:baz].value).to eq("2")
+    end
+
+    specify "msgpack writes integers up to 64 bits" do
+      builder = AttributeSet::Builder.new(foo: Type::Value.new, bar: Type::Value.new, baz: Type::Value.new)
+      attributes = builder.build_from_database(foo: 2**63, bar: 2**64 - 1, baz: -2**63)
+
+      loaded = AttributeSet.from_msgpack(builder, attributes.to_msgpack(builder))
+
+      expect(loaded.values_before_type_cast).to eq(foo: 2**63, bar: 2**64 - 1, baz: -2**63)
+      expect { builder.build_from_database(foo: 2**64).to_msgpack(builder) }.to raise_error(RangeError, /#{2**64}/)
+      expect { builder.build_from_database(foo: -2**63 - 1).to_msgpack(builder) }.to raise_error(RangeError)
+    end
+
+    specify "msgpack loaded with a different schema raises SchemaMismatchError" do
+      builder = AttributeSet::Builder.new(foo: Type::Integer.new, bar: Type::String.new)
+      bytes = builder.build_from_database(foo: "1", bar: "2").to_msgpack(builder)
+
+      retyped = AttributeSet::Builder.new(foo: Type::String.new, bar: Type::String.new)
+      renamed = AttributeSet::Builder.new(foo: Type::Integer.new, baz: Type::String.new)
+      reordered = AttributeSet::Builder.new(bar: Type::String.new, foo: Type::Integer.new)
+
+      expect { AttributeSet.from_msgpack(retyped, bytes) }.to raise_error(RailsFastAttributes::SchemaMismatchError)
+      expect { AttributeSet.from_msgpack(renamed, bytes) }.to raise_error(RailsFastAttributes::SchemaMismatchError)
+      expect { AttributeSet.from_msgpack(reordered, bytes) }.to raise_error(RailsFastAttributes::SchemaMismatchError)
+      expect(RailsFastAttributes::SchemaMismatchError.ancestors).to include(RailsFastAttributes::LoadError)
+    end
+
+    specify "msgpack only writes attributes loaded from the database" do
+      builder = AttributeSet::Builder.new(foo: Type::Integer.new, bar: Type::Value.new)
+      attributes = builder.build_from_database(foo: "1", bar: Object.new)
+
+      expect { attributes.to_msgpack(builder) }.to raise_error(TypeError, /Object/)
+
+      attributes = builder.build_from_database(foo: "1")
+      attributes.write_from_user(:foo, "2")
+
+      expect { attributes.to_msgpack(builder) }.to raise_error(ArgumentError, /foo/)
+    end
+
+    specify "malformed msgpack data raises LoadError" do
+      builder = AttributeSet::Builder.new(foo: Type::Integer.new)
+      bytes = builder.build_from_database(foo: "1").to_msgpack(builder)
+
+      expect { AttributeSet.from_msgpack(builder, bytes[0..-2]) }.to raise_error(RailsFastAttributes::LoadError)
+      expect { AttributeSet.from_msgpack(builder, bytes + "\x00") }.to raise_error(RailsFastAttributes::LoadError)
+      expect { AttributeSet.from_msgpack(builder, "") }.to raise_error(RailsFastAttributes::LoadError)
+    end
+
+    specify "malformed marshal data raises LoadError" do
+      attr = Attribute.from_database(:foo, "1", Type::Integer.new)
+
+      expect { AttributeSet.allocate._load_data(nil) }.to raise_error(RailsFastAttributes::LoadError)
+      expect { AttributeSet.allocate._load_data([1]) }.to raise_error(RailsFastAttributes::LoadError)
+      expect { AttributeSet.allocate._load_data([attr, "foo"]) }.to raise_error(RailsFastAttributes::LoadError)
+    end
+
     specify "write_from_database raises on missing attributes" do
       builder = AttributeSet::Builder.new({})
       attributes = builder.build_from_database
@@ -298,5 +781,386 @@ module ActiveModel
       expect { attributes.write_cast_value(:foo, 1) }.to raise_error(RuntimeError)
       expect { attributes.reset(:foo) }.to raise_error(RuntimeError)
     end
+
+    specify "exceptions raised by types propagate" do
+      type = Type::Value.new
+      type.define_singleton_method(:deserialize) { |_| raise ArgumentError, "bad value" }
+      builder = AttributeSet::Builder.new(foo: type)
+      attributes = builder.build_from_database(foo: "1")
+
+      expect { attributes.to_hash }.to raise_error(ArgumentError, "bad value")
+      expect { attributes.fetch_value(:foo) }.to raise_error(ArgumentError, "bad value")
+      expect(attributes.values_before_type_cast).to eq({ foo: "1" })
+    end
+
+    specify "breaking out of a block" do
+      builder = AttributeSet::Builder.new(foo: Type::Value.new, bar: Type::Value.new)
+      attributes = builder.build_from_database(foo: "1", bar: "2")
+
+      names = []
+      attributes.each_value do |attr|
+        names << attr.name
+        break
+      end
+
+      expect(names).to eq([:foo])
+      expect { attributes.map { raise "nope" } }.to raise_error(RuntimeError, "nope")
+    end
+
+    specify "build_from_database takes columns and rows" do
+      builder = AttributeSet::Builder.new(foo: Type::Integer.new, bar: Type::Float.new, baz: Type::Integer.new)
+
+      sets = builder.build_from_database(["foo", "bar", "qux"], [["1", "2.5", "x"], ["3", nil, "y"]], "qux" => Type::String.new)
+
+      expect(sets.size).to eq(2)
+      expect(sets).to eq([
+        builder.build_from_database({ "foo" => "1", "bar" => "2.5", "qux" => "x" }, "qux" => Type::String.new),
+        builder.build_from_database({ "foo" => "3", "bar" => nil, "qux" => "y" }, "qux" => Type::String.new),
+      ])
+      expect(sets.first[:foo].value).to eq(1)
+      expect(sets.first.key?(:baz)).not_to be
+      expect(builder.build_from_database([:foo], [])).to eq([])
+      expect { builder.build_from_database([:foo], [["1", "2"]]) }.to raise_error(ArgumentError, /expected 1 values/)
+    end
+
+    specify "additional types are merged once per query shape" do
+      builder = AttributeSet::Builder.new(foo: Type::Integer.new)
+      additional_types = { "bar" => Type::Integer.new }
+
+      first = builder.build_from_database({ "foo" => "1", "bar" => "2" }, additional_types)
+      second = builder.build_from_database({ "foo" => "3", "bar" => "4" }, additional_types)
+      additional_types["bar"] = Type::String.new
+      changed = builder.build_from_database({ "foo" => "5", "bar" => "6" }, additional_types)
+      additional_types["baz"] = Type::Integer.new
+      added = builder.build_from_database({ "foo" => "7", "baz" => "8" }, additional_types)
+
+      expect(first.fetch_value("bar")).to eq(2)
+      expect(second.fetch_value("bar")).to eq(4)
+      expect(changed.fetch_value("bar")).to eq("6")
+      expect(added.keys).to eq([:foo, "baz"])
+      expect(added.fetch_value("baz")).to eq(8)
+    end
+
+    specify "build_from_database with rows works with ActiveRecord::Result" do
+      result = ActiveRecord::Result.new(["foo", "bar"], [["1", "2"], ["3", "4"]])
+      builder = AttributeSet::Builder.new(foo: Type::Integer.new, bar: Type::Integer.new)
+
+      sets = builder.build_from_database(result.columns, result.rows)
+
+      expect(sets.map { |set| set.fetch_value(:bar) }).to eq([2, 4])
+    end
+
+    specify "builders can be introspected" do
+      integer = Type::Integer.new
+      default = Attribute.from_database("foo", nil, integer)
+      builder = AttributeSet::Builder.new({ "foo" => integer, bar: Type::String.new }, "foo" => default)
+
+      expect(builder.attribute_names).to eq(["foo", :bar])
+      expect(builder.types).to eq("foo" => integer, bar: Type::String.new)
+      expect(builder.default_attributes).to eq("foo" => default)
+    end
+
+    specify "with_additional_types derives a new builder" do
+      builder = AttributeSet::Builder.new(foo: Type::Integer.new)
+
+      derived = builder.with_additional_types(bar: Type::Integer.new, foo: Type::String.new)
+
+      expect(derived.attribute_names).to eq([:foo, :bar])
+      expect(derived.types[:foo]).to eq(Type::String.new)
+      expect(derived.build_from_database(bar: "1").fetch_value(:bar)).to eq(1)
+      expect(builder.attribute_names).to eq([:foo])
+    end
+
+    specify "build_defaults builds the default attributes for a new record" do
+      type = Type::String.new
+      calls = 0
+      defaults = {
+        foo: Attribute.user_provided_default(:foo, "a", type, nil),
+        bar: Attribute.user_provided_default(:bar, -> { calls += 1; "b#{calls}" }, type, nil),
+      }
+      builder = AttributeSet::Builder.new({ foo: type, bar: type, baz: type }, defaults)
+
+      first = builder.build_defaults
+      second = builder.build_defaults
+
+      expect(first.to_hash).to eq(foo: "a", bar: "b1")
+      expect(second.to_hash).to eq(foo: "a", bar: "b2")
+      expect(first.key?(:baz)).not_to be
+      expect(first.fetch_value(:foo)).not_to equal(second.fetch_value(:foo))
+      expect(first.values_before_type_cast[:foo]).to equal(second.values_before_type_cast[:foo])
+    end
+
+    specify "builders can be marshaled" do
+      type = Type::String.new
+      defaults = { foo: Attribute.user_provided_default(:foo, "a", type, nil) }
+      builder = AttributeSet::Builder.new({ foo: type, bar: Type::Integer.new }, defaults, strict: true)
+
+      loaded = Marshal.load(Marshal.dump(builder))
+
+      expect(loaded).to eq(builder)
+      expect(loaded.build_defaults.to_hash).to eq(foo: "a")
+      expect(loaded.build_from_database("bar" => "1").fetch_value(:bar)).to eq(1)
+      expect { loaded.build_from_database("baz" => "1") }.to raise_error(RailsFastAttributes::UnknownColumnError)
+    end
+
+    specify "builders with proc defaults or strict callbacks can't be marshaled" do
+      type = Type::String.new
+      defaults = { foo: Attribute.user_provided_default(:foo, -> { "a" }, type, nil) }
+      with_proc = AttributeSet::Builder.new({ foo: type }, defaults)
+      with_callback = AttributeSet::Builder.new({ foo: type }, nil, strict: ->(name) {})
+
+      expect { Marshal.dump(with_proc) }.to raise_error(TypeError, /proc default for `foo`/)
+      expect { Marshal.dump(with_callback) }.to raise_error(TypeError, /strict callback/)
+    end
+
+    specify "loading malformed builder data raises LoadError" do
+      builder = AttributeSet::Builder.allocate
+
+      expect { builder._load_data([1]) }.to raise_error(RailsFastAttributes::LoadError)
+      expect { builder._load_data([2, [], [], nil, false, nil]) }.to raise_error(RailsFastAttributes::LoadError, /version 2/)
+      expect { builder._load_data([1, [], [], nil, false]) }.to raise_error(RailsFastAttributes::LoadError, /6 elements/)
+      expect { builder._load_data([1, [:foo], [], nil, false, nil]) }.to raise_error(RailsFastAttributes::LoadError)
+    end
+
+    specify "an identity map returns copies of rows which were already loaded" do
+      builder = AttributeSet::Builder.new(id: Type::Integer.new, name: Type::String.new)
+      identity_map = RailsFastAttributes::IdentityMap.new("id")
+
+      first = builder.build_from_database({ "id" => "1", "name" => "foo" }, nil, identity_map)
+      first[:name].value
+      first.write_from_user(:name, "changed")
+      second = builder.build_from_database({ "id" => "1", "name" => "foo" }, nil, identity_map)
+      rows = builder.build_from_database(["id", "name"], [["1", "foo"], ["2", "qux"]], nil, identity_map)
+
+      expect(second.fetch_value(:name)).to eq("foo")
+      expect(second[:name]).to be_has_been_read
+      expect(second.fetch_value(:name)).not_to equal(first[:name].original_value)
+      expect(rows.map { |set| set.fetch_value(:name) }).to eq(["foo", "qux"])
+      expect(identity_map.size).to eq(2)
+    end
+
+    specify "an identity map replaces rows which changed in the database" do
+      builder = AttributeSet::Builder.new(id: Type::Integer.new, name: Type::String.new)
+      identity_map = RailsFastAttributes::IdentityMap.new("id")
+
+      builder.build_from_database({ "id" => "1", "name" => "foo" }, nil, identity_map)[:name].value
+      changed = builder.build_from_database({ "id" => "1", "name" => "bar" }, nil, identity_map)
+      rows = builder.build_from_database(["id", "name"], [["1", "bar"], ["1", "baz"]], nil, identity_map)
+
+      expect(changed.fetch_value(:name)).to eq("bar")
+      expect(rows.map { |set| set.fetch_value(:name) }).to eq(["bar", "baz"])
+      expect(builder.build_from_database({ "id" => "1", "name" => "baz" }, nil, identity_map).fetch_value(:name)).to eq("baz")
+      expect(identity_map.size).to eq(1)
+    end
+
+    specify "an identity map only reuses rows loaded with the same columns" do
+      builder = AttributeSet::Builder.new(id: Type::Integer.new, name: Type::String.new)
+      identity_map = RailsFastAttributes::IdentityMap.new(:id)
+
+      builder.build_from_database({ "id" => "1" }, nil, identity_map)
+      full = builder.build_from_database({ "id" => "1", "name" => "foo" }, nil, identity_map)
+      without_id = builder.build_from_database({ "name" => "bar" }, nil, identity_map)
+
+      expect(full.fetch_value(:name)).to eq("foo")
+      expect(without_id.fetch_value(:name)).to eq("bar")
+      expect(identity_map.clear.size).to eq(0)
+    end
+
+    specify "interned columns share their raw and cast values between rows" do
+      builder = AttributeSet::Builder.new({ status: Type::ImmutableString.new, name: Type::String.new }, nil, intern: [:status])
+
+      first, second = builder.build_from_database(["status", "name"], [["active", "foo"], ["active", "foo"]])
+      third = builder.build_from_database("status" => "active".dup, "name" => "foo")
+
+      expect([second, third].map { |set| set.values_before_type_cast[:status] }).to all(equal(first.values_before_type_cast[:status]))
+      expect(first.values_before_type_cast[:status]).to be_frozen
+      expect(third.fetch_value(:status)).to equal(first.fetch_value(:status))
+      expect(second.values_before_type_cast[:name]).not_to equal(first.values_before_type_cast[:name])
+    end
+
+    specify "interning every column stops once a column has too many values" do
+      builder = AttributeSet::Builder.new({ name: Type::String.new }, nil, intern: true)
+
+      few = builder.build_from_database(["name"], [["a"], ["a"]])
+      many = builder.build_from_database(["name"], (0..300).map { |i| [i.to_s] } + [["0"]])
+
+      expect(few[1].values_before_type_cast[:name]).to equal(few[0].values_before_type_cast[:name])
+      expect(few[1].fetch_value(:name)).not_to equal(few[0].fetch_value(:name))
+      expect(many[-1].values_before_type_cast[:name]).not_to equal(many[0].values_before_type_cast[:name])
+      expect(builder._dump_data.values_at(0, 5)).to eq([1, true])
+      expect(Marshal.load(Marshal.dump(builder)).build_from_database(name: "a").fetch_value(:name)).to eq("a")
+    end
+
+    specify "interning survives the GC running while values are added" do
+      builder = AttributeSet::Builder.new({ status: Type::ImmutableString.new }, nil, intern: true)
+
+      begin
+        GC.stress = true
+        sets = builder.build_from_database(["status"], [["active"], ["inactive"], ["active"]])
+      ensure
+        GC.stress = false
+      end
+      GC.start
+
+      expect(sets.map { |set| set.fetch_value(:status) }).to eq(%w[active inactive active])
+      expect(sets[2].values_before_type_cast[:status]).to equal(sets[0].values_before_type_cast[:status])
+      expect(builder.build_from_database(status: "inactive").fetch_value(:status)).to eq("inactive")
+    end
+
+    specify "build_from_buffer builds rows from values packed into one string" do
+      builder = AttributeSet::Builder.new(id: Type::Integer.new, name: Type::String.new)
+
+      sets = builder.build_from_buffer(["id", "name"], "1foo-20", [1, 3, 3, nil])
+
+      expect(sets.map(&:to_hash)).to eq([{ id: 1, name: "foo" }, { id: -20, name: nil }])
+      expect(sets[0].values_before_type_cast).to eq(id: "1", name: "foo")
+      expect(sets[0].values_before_type_cast[:name].encoding).to eq(Encoding::UTF_8)
+      expect(builder.build_from_buffer(["name"], "\xFF".b, [1]).first.values_before_type_cast[:name].encoding).to eq(Encoding::BINARY)
+      expect(builder.build_from_buffer(["id"], "007", [3]).first.fetch_value(:id)).to eq(7)
+      expect { builder.build_from_buffer(["id", "name"], "1", [1]) }.to raise_error(ArgumentError, /multiple of 2/)
+      expect { builder.build_from_buffer(["id"], "1", [2]) }.to raise_error(ArgumentError, /out of bounds/)
+      expect { builder.build_from_buffer(["id"], "12", [1]) }.to raise_error(ArgumentError, /add up to 1/)
+    end
+
+    specify "sets built from a buffer can be written to msgpack and yaml" do
+      builder = AttributeSet::Builder.new(id: Type::Integer.new, name: Type::String.new)
+      attributes = builder.build_from_buffer(["id", "name"], "12foo", [2, 3]).first
+
+      from_msgpack = AttributeSet.from_msgpack(builder, attributes.to_msgpack(builder))
+      from_yaml = YAML.load(YAML.dump(attributes))
+
+      expect(from_msgpack).to eq(attributes)
+      expect(from_msgpack.values_before_type_cast).to eq(id: "12", name: "foo")
+      expect(from_yaml).to eq(attributes)
+      expect(from_yaml.to_hash).to eq(id: 12, name: "foo")
+    end
+
+    specify "build_from_sqlite3 builds rows straight from a statement" do
+      db = SQLite3::Database.new(":memory:")
+      db.execute("CREATE TABLE users (id INTEGER, name TEXT, score REAL, avatar BLOB)")
+      db.execute("INSERT INTO users VALUES (1, 'foo', 1.5, x'ff00'), (2, NULL, NULL, NULL)")
+      builder = AttributeSet::Builder.new(id: Type::Integer.new, name: Type::String.new)
+      statement = db.prepare("SELECT * FROM users WHERE id > ? ORDER BY id")
+      statement.bind_params(0)
+
+      sets = builder.build_from_sqlite3(statement, "score" => Type::Float.new)
+
+      expect(sets.map(&:to_hash)).to eq([
+        { id: 1, name: "foo", "score" => 1.5, "avatar" => "\xFF\x00".b },
+        { id: 2, name: nil, "score" => nil, "avatar" => nil },
+      ])
+      expect(sets[0].values_before_type_cast[:id]).to eq(1)
+      expect(sets[0].values_before_type_cast["avatar"].encoding).to eq(Encoding::BINARY)
+      expect(statement).not_to be_done
+      expect(statement.step).to eq([1, "foo", 1.5, "\xFF\x00".b])
+      statement.close
+
+      db.execute("CREATE TABLE emails (address TEXT UNIQUE)")
+      insert = db.prepare("INSERT INTO emails VALUES ('a'), ('a')")
+      expect { builder.build_from_sqlite3(insert) }.to raise_error(SQLite3::ConstraintException, /UNIQUE/)
+      expect(db.execute("SELECT COUNT(*) FROM emails")).to eq([[0]])
+      insert.close
+      expect { builder.build_from_sqlite3(statement) }.to raise_error(SQLite3::Exception, /closed/)
+      expect { builder.build_from_sqlite3(Object.new) }.to raise_error(TypeError, /SQLite3::Statement/)
+    end
+
+    specify "cast_rows casts values without building attribute sets" do
+      builder = AttributeSet::Builder.new(id: Type::Integer.new, name: Type::String.new)
+
+      expect(builder.cast_rows(["id", "name"], [["1", "foo"], [nil, "bar"]])).to eq([[1, "foo"], [nil, "bar"]])
+      expect(builder.cast_rows(["id"], [["2"]])).to eq([[2]])
+      expect(builder.cast_rows(["total"], [["3"]], "total" => Type::Integer.new)).to eq([[3]])
+      expect { builder.cast_rows(["id", "name"], [["1"]]) }.to raise_error(ArgumentError, /expected 2 values in row 0/)
+    end
+
+    specify "builders with the same attributes are equal" do
+      builder = AttributeSet::Builder.new(foo: Type::Integer.new, bar: Type::String.new)
+      same = AttributeSet::Builder.new(foo: Type::Integer.new, bar: Type::String.new)
+      reordered = AttributeSet::Builder.new(bar: Type::String.new, foo: Type::Integer.new)
+      retyped = AttributeSet::Builder.new(foo: Type::Integer.new, bar: Type::Integer.new)
+
+      expect(builder).to eq(same)
+      expect(builder.hash).to eq(same.hash)
+      expect({ builder => 1 }[same]).to eq(1)
+      expect(builder).not_to eq(reordered)
+      expect(builder).not_to eq(retyped)
+      expect(builder).not_to eq(Object.new)
+    end
+
+    specify "unknown columns use the fallback type" do
+      fallback = Type::Integer.new
+      builder = AttributeSet::Builder.new({ foo: Type::Integer.new }, nil, fallback_type: fallback)
+
+      attributes = builder.build_from_database("foo" => "1", "bar" => "2")
+      sets = builder.build_from_database(["bar"], [["3"]])
+
+      expect(attributes["bar"].type).to equal(fallback)
+      expect(attributes.fetch_value("bar")).to eq(2)
+      expect(sets.first.fetch_value("bar")).to eq(3)
+      expect(AttributeSet::Builder.new({}).build_from_database("bar" => "2")["bar"].type).to eq(ActiveRecord::Type.default_value)
+    end
+
+    specify "strict builders raise or report unknown columns" do
+      strict = AttributeSet::Builder.new({ foo: Type::Integer.new }, nil, strict: true)
+      reported = []
+      reporting = AttributeSet::Builder.new({ foo: Type::Integer.new }, nil, strict: reported.method(:push))
+
+      expect { strict.build_from_database("foo" => "1", "bar" => "2") }.to raise_error(RailsFastAttributes::UnknownColumnError, /`bar`/)
+      expect { strict.build_from_database(["foo", "bar"], [["1", "2"]]) }.to raise_error(RailsFastAttributes::UnknownColumnError)
+      expect(strict.build_from_database({ "foo" => "1", "bar" => "2" }, "bar" => Type::Value.new).fetch_value("bar")).to eq("2")
+      expect(reporting.build_from_database("foo" => "1", "bar" => "2").fetch_value("bar")).to eq("2")
+      reporting.build_from_database("foo" => "3", "bar" => "4", "baz" => "5")
+      reporting.build_from_database(["bar", "baz"], [["6", "7"], ["8", "9"]])
+      expect(reported).to eq(["bar", "baz"])
+    end
+
+    specify "build_from_user_rows assigns each value as if by the user" do
+      builder = AttributeSet::Builder.new(foo: Type::Integer.new, bar: Type::String.new, baz: Type::Value.new)
+
+      sets, errors = builder.build_from_user_rows([["1", "a"], ["2", 3]], [:foo, "bar"])
+
+      expect(errors).to eq([])
+      expect(sets.map(&:to_hash)).to eq([{ foo: 1, bar: "a" }, { foo: 2, bar: "3" }])
+      expect(sets.first[:foo]).to be_changed
+      expect(sets.first[:foo].value_before_type_cast).to eq("1")
+      expect(sets.first.key?(:baz)).not_to be
+    end
+
+    specify "build_from_user_rows collects errors instead of raising" do
+      type = Class.new(Type::Integer) do
+        def cast_value(value)
+          raise ArgumentError, "not a number" if value == "x"
+          super
+        end
+      end.new
+      builder = AttributeSet::Builder.new(foo: type, bar: Type::Integer.new)
+
+      sets, errors = builder.build_from_user_rows([["1", "2"], ["x", "2"], ["3"], ["4", "5"]], [:foo, :bar])
+
+      expect(sets.map { |set| set && set[:foo].value }).to eq([1, nil, nil, 4])
+      expect(errors.map { |e| [e.row, e.column, e.error.class] }).to eq([[1, :foo, ArgumentError], [2, nil, ArgumentError]])
+      expect(errors.first.error.message).to eq("not a number")
+      expect { builder.build_from_user_rows([["1"]], [:nope]) }.to raise_error(ArgumentError, /nope/)
+    end
+
+    specify "exceptions while building leave no partial state behind" do
+      builder = AttributeSet::Builder.new(foo: Type::Value.new)
+
+      expect { builder.build_from_database(foo: "1", 1 => "2") }.to raise_error(TypeError)
+      expect(builder.build_from_database(foo: "1").keys).to eq([:foo])
+    end
+
+    specify "arguments of the wrong type raise TypeError" do
+      builder = AttributeSet::Builder.new(foo: Type::Value.new)
+      attributes = builder.build_from_database(foo: nil)
+
+      expect { AttributeSet.new([]) }.to raise_error(TypeError, /expected Hash/)
+      expect { AttributeSet.new(foo: 1) }.to raise_error(TypeError, /expected RailsFastAttributes::Attribute/)
+      expect { attributes[:foo] = "foo" }.to raise_error(TypeError, /expected RailsFastAttributes::Attribute/)
+      expect { attributes.map { 1 } }.to raise_error(TypeError, /wrong argument type Integer/)
+      expect { builder.build_from_database([]) }.to raise_error(TypeError, /expected Hash/)
+      expect { builder.build_from_database([], nil) }.to raise_error(TypeError, /expected Array/)
+      expect { AttributeSet::Builder.new({}, foo: 1) }.to raise_error(TypeError)
+    end
   end
 end
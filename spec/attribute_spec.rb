@@ -266,6 +266,37 @@ module ActiveModel
       expect(attr).to eq(YAML.load(YAML.dump(attr)))
     end
 
+    specify "yaml round trips keep where each attribute came from" do
+      type = Type::Integer.new
+      from_database = Attribute.from_database(:foo, "1", type)
+      attributes = [
+        from_database,
+        from_database.with_value_from_user("2"),
+        Attribute.with_cast_value(:foo, 3, type),
+        Attribute.user_provided_default(:foo, "4", type, from_database),
+        Attribute.uninitialized(:foo, type),
+      ]
+
+      attributes.each do |attr|
+        loaded = YAML.load(YAML.dump(attr))
+
+        expect(loaded).to eq(attr)
+        expect(loaded.came_from_user?).to eq(attr.came_from_user?)
+        expect(loaded.changed?).to eq(attr.changed?)
+      end
+    end
+
+    specify "malformed marshal data raises LoadError" do
+      type = Type::Value.new
+
+      expect { Attribute.allocate._load_data(nil) }.to raise_error(RailsFastAttributes::LoadError)
+      expect { Attribute.allocate._load_data([:foo]) }.to raise_error(RailsFastAttributes::LoadError)
+      expect { Attribute.allocate._load_data([:foo, type, 1, :bar]) }.to raise_error(RailsFastAttributes::LoadError)
+      expect { Attribute.allocate._load_data([:foo, type, 1, [2**40, nil]]) }.to raise_error(RailsFastAttributes::LoadError)
+      expect { Attribute.allocate._load_data([:foo, type, 1, [1, nil]]) }.to raise_error(RailsFastAttributes::LoadError)
+      expect { Attribute.allocate._load_data([:foo, type, 1, [4, "bar"]]) }.to raise_error(RailsFastAttributes::LoadError)
+    end
+
     specify "uninitialized attributes are always dirty when assigned" do
       type = Type::String.new
       attribute = Attribute.uninitialized(:foo, type)
@@ -281,6 +312,15 @@ module ActiveModel
       expect(attribute).to be_changed
     end
 
+    specify "original attributes must be attributes" do
+      expect {
+        Attribute.user_provided_default(:foo, 1, type, "not an attribute")
+      }.to raise_error(TypeError, /expected RailsFastAttributes::Attribute/)
+      expect {
+        Attribute.from_user(:foo, 1, type, nil)
+      }.to raise_error(TypeError, /expected RailsFastAttributes::Attribute/)
+    end
+
     def attribute_from_user(name, value, type)
       Attribute.from_user(name, value, type, Attribute.uninitialized(name, type))
     end
@@ -0,0 +1,64 @@
+# Writes `rails-VERSION.yml` next to this file, which is `YAML.dump` of an
+# attribute set built by the given version of Rails. Run it outside of this
+# bundle, since it installs its own copy of Active Record:
+#
+#   ruby spec/fixtures/yaml/generate.rb 6.1
+#
+# Without a version it regenerates every fixture, each in its own process
+# since only one version of Active Record can be loaded at a time.
+#
+# The set has the same contents for every version, so that one spec can check
+# all of them. `qux` and `quux` are left out for Rails 4.2, which can't write
+# cast values or take default attributes in its builder.
+
+VERSIONS = %w[4.2 5.1 5.2 6.1 7.0]
+
+if ARGV.empty?
+  require "rbconfig"
+  VERSIONS.each do |version|
+    system(RbConfig.ruby, __FILE__, version) || abort("generating #{version} failed")
+  end
+  exit
+end
+
+version = ARGV[0]
+
+require "bundler/inline"
+
+gemfile(true) do
+  source "https://rubygems.org"
+  gem "activerecord", "~> #{version}.0"
+end
+
+require "active_record"
+require "yaml"
+
+rails_4_2 = ActiveRecord.version < Gem::Version.new("5.0")
+if ActiveRecord.version >= Gem::Version.new("5.2")
+  require "active_model/attribute/user_provided_default"
+  namespace = ActiveModel
+else
+  require "active_record/attribute/user_provided_default" unless rails_4_2
+  namespace = ActiveRecord
+end
+type = (rails_4_2 ? ActiveRecord::Type : ActiveModel::Type)::Integer.new
+
+types = { "foo" => type, "bar" => type, "baz" => type }
+builder_args = [types]
+unless rails_4_2
+  types["qux"] = type
+  types["quux"] = type
+  database_default = namespace::Attribute.from_database("quux", nil, type)
+  builder_args << {
+    "quux" => namespace::Attribute::UserProvidedDefault.new("quux", "5", type, database_default),
+  }
+end
+
+attributes = namespace::AttributeSet::Builder.new(*builder_args)
+  .build_from_database("foo" => "1", "bar" => "2")
+attributes.write_from_user("foo", "3")
+attributes.write_cast_value("qux", 4) unless rails_4_2
+
+path = File.expand_path("rails-#{version}.yml", __dir__)
+File.write(path, YAML.dump(attributes))
+puts "Wrote #{path} with Active Record #{ActiveRecord.version}"
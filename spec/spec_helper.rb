@@ -1,6 +1,10 @@
 require "bundler/setup"
 require "active_record"
 require "rails_fast_attributes"
+require "csv"
+require "stringio"
+require "sqlite3"
+require_relative "support/arrow_reader"
 
 RSpec.configure do |config|
   # Enable flags like --only-failures and --next-failure
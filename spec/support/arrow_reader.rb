@@ -0,0 +1,151 @@
+# Reads back the parts of the Arrow IPC format which `write_arrow` writes, so
+# that specs can check the schema and values rather than just the framing.
+# It follows `Schema.fbs`, `Message.fbs` and `File.fbs`, and only decodes the
+# column types the specs use.
+module ArrowReader
+  TYPE_UTF8 = 5
+  TYPE_DECIMAL = 7
+  HEADER_SCHEMA = 1
+  HEADER_RECORD_BATCH = 3
+
+  # A FlatBuffers table at `pos` in `bytes`.
+  class Table
+    def initialize(bytes, pos)
+      @bytes = bytes
+      @pos = pos
+      @vtable = pos - unpack(pos, "l<", 4)
+    end
+
+    def int(field, format, size, default = 0)
+      at = offset(field)
+      at ? unpack(at, format, size) : default
+    end
+
+    def table(field)
+      at = indirect(field)
+      at && Table.new(@bytes, at)
+    end
+
+    def string(field)
+      at = indirect(field)
+      at && @bytes[at + 4, unpack(at, "L<", 4)]
+    end
+
+    def tables(field)
+      start, len = vector(field)
+      Array.new(len) do |i|
+        at = start + i * 4
+        Table.new(@bytes, at + unpack(at, "L<", 4))
+      end
+    end
+
+    def structs(field, format, size)
+      start, len = vector(field)
+      Array.new(len) { |i| @bytes[start + i * size, size].unpack(format) }
+    end
+
+    private
+
+    def unpack(at, format, size)
+      @bytes[at, size].unpack(format).first
+    end
+
+    def offset(field)
+      return if 4 + field * 2 >= unpack(@vtable, "S<", 2)
+      offset = unpack(@vtable + 4 + field * 2, "S<", 2)
+      @pos + offset unless offset.zero?
+    end
+
+    def indirect(field)
+      at = offset(field)
+      at && at + unpack(at, "L<", 4)
+    end
+
+    def vector(field)
+      at = indirect(field)
+      at ? [at + 4, unpack(at, "L<", 4)] : [0, 0]
+    end
+  end
+
+  Result = Struct.new(:schema, :batches) do
+    def fields
+      schema.tables(1)
+    end
+
+    # The values of each column by name, across all batches, with `nil`
+    # for nulls. Decimals are unscaled and timestamps are integers.
+    def columns
+      batches.map { |batch, body| ArrowReader.columns(schema, batch, body) }
+        .reduce { |result, columns| result.merge(columns) { |_, a, b| a + b } }
+    end
+  end
+
+  def self.read_stream(bytes)
+    messages = []
+    pos = 0
+    while (message = read_message(bytes, pos))
+      header, body, pos = message
+      messages << [header, body]
+    end
+    schema, *batches = messages
+    raise "expected a schema message first" unless schema[0].int(1, "C", 1) == HEADER_SCHEMA
+    batches.each do |batch, _|
+      raise "expected a record batch" unless batch.int(1, "C", 1) == HEADER_RECORD_BATCH
+    end
+    Result.new(schema[0].table(2), batches.map { |batch, body| [batch.table(2), body] })
+  end
+
+  def self.read_file(bytes)
+    raise "missing magic bytes" unless bytes.start_with?("ARROW1") && bytes.end_with?("ARROW1")
+    footer_len = bytes[-10, 4].unpack("L<").first
+    footer = root(bytes[-10 - footer_len, footer_len])
+    batches = footer.structs(3, "q<l<x4q<", 24).map do |offset, metadata_len, body_len|
+      header, body, = read_message(bytes, offset)
+      raise "block doesn't match its message" unless body == bytes[offset + metadata_len, body_len]
+      [header.table(2), body]
+    end
+    Result.new(footer.table(1), batches)
+  end
+
+  def self.root(bytes)
+    Table.new(bytes, bytes[0, 4].unpack("L<").first)
+  end
+
+  # The header and body of the message at `pos`, and the position after it,
+  # or `nil` at the end of stream marker.
+  def self.read_message(bytes, pos)
+    continuation, len = bytes[pos, 8].unpack("L<L<")
+    raise "expected a continuation marker at #{pos}" unless continuation == 0xFFFFFFFF
+    return if len.zero?
+    header = root(bytes[pos + 8, len])
+    body_len = header.int(3, "q<", 8)
+    body_pos = pos + 8 + len
+    [header, bytes[body_pos, body_len], body_pos + body_len]
+  end
+
+  def self.columns(schema, batch, body)
+    nodes = batch.structs(1, "q<q<", 16)
+    buffers = batch.structs(2, "q<q<", 16).map { |offset, len| body[offset, len] }
+    schema.tables(1).each_with_index.map do |field, i|
+      len, = nodes[i]
+      validity = buffers.shift
+      values =
+        case field.int(2, "C", 1)
+        when TYPE_UTF8
+          offsets = buffers.shift.unpack("l<*")
+          data = buffers.shift
+          Array.new(len) { |j| data[offsets[j]...offsets[j + 1]].force_encoding(Encoding::UTF_8) }
+        when TYPE_DECIMAL
+          data = buffers.shift
+          Array.new(len) do |j|
+            low, high = data[j * 16, 16].unpack("Q<q<")
+            (high << 64) | low
+          end
+        else
+          buffers.shift.unpack("q<#{len}")
+        end
+      valid = Array.new(len) { |j| validity.getbyte(j / 8)[j % 8] == 1 }
+      [field.string(0), values.zip(valid).map { |value, is_valid| value if is_valid }]
+    end.to_h
+  end
+end
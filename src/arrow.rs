@@ -0,0 +1,444 @@
+//! Apache Arrow IPC output, in either the streaming or the file format.
+//!
+//! Each column is built from Ruby values into Arrow's in memory layout (a
+//! validity bitmap followed by the values, or offsets and bytes for strings),
+//! and written as record batches after a schema message. The metadata is
+//! encoded with the bits of FlatBuffers in `flatbuffers.rs`, following
+//! `Schema.fbs`, `Message.fbs` and `File.fbs` from the Arrow format.
+
+use ffi;
+use flatbuffers::{self, le_bytes, Table, Value};
+use libc;
+use protect::protect;
+use util::*;
+
+extern "C" {
+    fn rb_time_timespec(time: ffi::VALUE) -> libc::timespec;
+}
+
+const CONTINUATION: [u8; 4] = [0xff; 4];
+const MAGIC: &[u8] = b"ARROW1";
+const METADATA_VERSION_V5: i16 = 4;
+
+const HEADER_SCHEMA: u8 = 1;
+const HEADER_RECORD_BATCH: u8 = 3;
+
+const TYPE_INT: u8 = 2;
+const TYPE_FLOATING_POINT: u8 = 3;
+const TYPE_UTF8: u8 = 5;
+const TYPE_BOOL: u8 = 6;
+const TYPE_DECIMAL: u8 = 7;
+const TYPE_DATE: u8 = 8;
+const TYPE_TIME: u8 = 9;
+const TYPE_TIMESTAMP: u8 = 10;
+
+const PRECISION_DOUBLE: i16 = 2;
+const DATE_UNIT_DAY: i16 = 0;
+const TIME_UNIT_MICROSECOND: i16 = 2;
+const MAX_DECIMAL_PRECISION: i32 = 38;
+/// The Julian day of 1970-01-01
+const UNIX_EPOCH_JD: i64 = 2_440_588;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    Int64,
+    Float64,
+    Bool,
+    Decimal { precision: i32, scale: i32 },
+    /// Microseconds since the epoch, in UTC
+    Timestamp,
+    /// Days since the epoch
+    Date32,
+    /// Microseconds since midnight
+    Time64,
+    /// Anything else, written as `to_s`
+    Utf8,
+}
+
+impl ColumnType {
+    /// Picks the column type from `ty.type`. Decimals without a scale, or
+    /// with more digits than Arrow supports, are written as strings.
+    pub fn from_attribute_type(ty: ffi::VALUE) -> Self {
+        unsafe {
+            let name = protect(|| ffi::rb_funcall(ty, id!("type"), 0));
+            if !ffi::RB_TYPE_P(name, ffi::T_SYMBOL) {
+                return ColumnType::Utf8;
+            }
+            match ffi::rb_sym2id(name) {
+                id if id == id!("integer") || id == id!("big_integer") => ColumnType::Int64,
+                id if id == id!("float") => ColumnType::Float64,
+                id if id == id!("boolean") => ColumnType::Bool,
+                id if id == id!("datetime") => ColumnType::Timestamp,
+                id if id == id!("date") => ColumnType::Date32,
+                id if id == id!("time") => ColumnType::Time64,
+                id if id == id!("decimal") => {
+                    let precision = protect(|| ffi::rb_funcall(ty, id!("precision"), 0));
+                    let scale = protect(|| ffi::rb_funcall(ty, id!("scale"), 0));
+                    if ffi::RB_NIL_P(scale) {
+                        return ColumnType::Utf8;
+                    }
+                    let precision = if ffi::RB_NIL_P(precision) {
+                        MAX_DECIMAL_PRECISION
+                    } else {
+                        protect(|| ffi::NUM2I32(precision))
+                    };
+                    let scale = protect(|| ffi::NUM2I32(scale));
+                    if precision > MAX_DECIMAL_PRECISION || scale < 0 || scale > precision {
+                        ColumnType::Utf8
+                    } else {
+                        ColumnType::Decimal { precision, scale }
+                    }
+                }
+                _ => ColumnType::Utf8,
+            }
+        }
+    }
+
+    fn to_flatbuffer(&self) -> (u8, Table) {
+        match *self {
+            ColumnType::Int64 => (
+                TYPE_INT,
+                Table::new().field(0, Value::I32(64)).field(1, Value::U8(1)),
+            ),
+            ColumnType::Float64 => (
+                TYPE_FLOATING_POINT,
+                Table::new().field(0, Value::I16(PRECISION_DOUBLE)),
+            ),
+            ColumnType::Bool => (TYPE_BOOL, Table::new()),
+            ColumnType::Decimal { precision, scale } => (
+                TYPE_DECIMAL,
+                Table::new()
+                    .field(0, Value::I32(precision))
+                    .field(1, Value::I32(scale))
+                    .field(2, Value::I32(128)),
+            ),
+            ColumnType::Timestamp => (
+                TYPE_TIMESTAMP,
+                Table::new()
+                    .field(0, Value::I16(TIME_UNIT_MICROSECOND))
+                    .field(1, Value::String(b"UTC".to_vec())),
+            ),
+            ColumnType::Date32 => (TYPE_DATE, Table::new().field(0, Value::I16(DATE_UNIT_DAY))),
+            ColumnType::Time64 => (
+                TYPE_TIME,
+                Table::new()
+                    .field(0, Value::I16(TIME_UNIT_MICROSECOND))
+                    .field(1, Value::I32(64)),
+            ),
+            ColumnType::Utf8 => (TYPE_UTF8, Table::new()),
+        }
+    }
+}
+
+pub struct Column {
+    ty: ColumnType,
+    len: usize,
+    null_count: usize,
+    validity: Vec<u8>,
+    values: Vec<u8>,
+    /// Only used for `Utf8`, where `values` holds the string data
+    offsets: Vec<u8>,
+}
+
+impl Column {
+    pub fn new(ty: ColumnType) -> Self {
+        Column {
+            ty,
+            len: 0,
+            null_count: 0,
+            validity: Vec::new(),
+            values: Vec::new(),
+            offsets: if ty == ColumnType::Utf8 { vec![0; 4] } else { Vec::new() },
+        }
+    }
+
+    /// Appends a Ruby value, which should be a cast value of an attribute
+    /// with the type this column was created from.
+    pub fn push(&mut self, value: ffi::VALUE) {
+        unsafe {
+            if ffi::RB_NIL_P(value) {
+                return self.push_null();
+            }
+
+            match self.ty {
+                ColumnType::Int64 => {
+                    let value = protect(|| ffi::NUM2I64(value));
+                    self.values.extend_from_slice(&le_bytes(value as u64, 8));
+                }
+                ColumnType::Float64 => {
+                    let value = protect(|| ffi::NUM2F64(value));
+                    self.values.extend_from_slice(&le_bytes(value.to_bits(), 8));
+                }
+                ColumnType::Bool => push_bit(&mut self.values, self.len, ffi::RTEST(value)),
+                ColumnType::Decimal { scale, .. } => match unscaled_decimal(value, scale) {
+                    Some(value) => {
+                        self.values.extend_from_slice(&le_bytes(value as u64, 8));
+                        self.values.extend_from_slice(&le_bytes((value >> 64) as u64, 8));
+                    }
+                    None => return self.push_null(),
+                },
+                ColumnType::Timestamp => {
+                    let (seconds, nanoseconds) = timespec(value);
+                    let micros = seconds * 1_000_000 + nanoseconds / 1000;
+                    self.values.extend_from_slice(&le_bytes(micros as u64, 8));
+                }
+                ColumnType::Date32 => {
+                    let jd = protect(|| ffi::rb_funcall(value, id!("jd"), 0));
+                    let days = protect(|| ffi::NUM2I64(jd)) - UNIX_EPOCH_JD;
+                    self.values.extend_from_slice(&le_bytes(days as u64, 4));
+                }
+                ColumnType::Time64 => {
+                    let (seconds, nanoseconds) = timespec(value);
+                    let offset = protect(|| ffi::rb_funcall(value, id!("utc_offset"), 0));
+                    let seconds = (seconds + protect(|| ffi::NUM2I64(offset))).rem_euclid(86_400);
+                    let micros = seconds * 1_000_000 + nanoseconds / 1000;
+                    self.values.extend_from_slice(&le_bytes(micros as u64, 8));
+                }
+                ColumnType::Utf8 => {
+                    let string = if ffi::RB_TYPE_P(value, ffi::T_STRING) {
+                        value
+                    } else {
+                        protect(|| ffi::rb_funcall(value, id!("to_s"), 0))
+                    };
+                    check_type(string, ffi::T_STRING, ffi::rb_cString);
+                    let string = to_utf8(string);
+                    self.values.extend_from_slice(string_bytes(string));
+                    let offset = self.values.len() as u64;
+                    self.offsets.extend_from_slice(&le_bytes(offset, 4));
+                }
+            }
+        }
+        push_bit(&mut self.validity, self.len, true);
+        self.len += 1;
+    }
+
+    fn push_null(&mut self) {
+        match self.ty {
+            ColumnType::Bool => push_bit(&mut self.values, self.len, false),
+            ColumnType::Utf8 => {
+                let offset = self.values.len() as u64;
+                self.offsets.extend_from_slice(&le_bytes(offset, 4));
+            }
+            _ => {
+                let width = self.width();
+                self.values.resize(self.values.len() + width, 0);
+            }
+        }
+        push_bit(&mut self.validity, self.len, false);
+        self.len += 1;
+        self.null_count += 1;
+    }
+
+    fn width(&self) -> usize {
+        match self.ty {
+            ColumnType::Decimal { .. } => 16,
+            ColumnType::Date32 => 4,
+            _ => 8,
+        }
+    }
+
+    fn buffers(&self) -> Vec<&[u8]> {
+        if self.ty == ColumnType::Utf8 {
+            vec![&self.validity, &self.offsets, &self.values]
+        } else {
+            vec![&self.validity, &self.values]
+        }
+    }
+}
+
+fn push_bit(bitmap: &mut Vec<u8>, index: usize, set: bool) {
+    if index % 8 == 0 {
+        bitmap.push(0);
+    }
+    if set {
+        *bitmap.last_mut().unwrap() |= 1 << (index % 8);
+    }
+}
+
+/// Seconds and nanoseconds since the epoch of a `Time`, or anything with a
+/// `to_time` such as `ActiveSupport::TimeWithZone`.
+unsafe fn timespec(value: ffi::VALUE) -> (i64, i64) {
+    let time_class = ffi::rb_const_get(ffi::rb_cObject, id!("Time"));
+    let time = if is_kind_of(value, time_class) {
+        value
+    } else {
+        protect(|| ffi::rb_funcall(value, id!("to_time"), 0))
+    };
+    let timespec = protect(|| rb_time_timespec(time));
+    (timespec.tv_sec as i64, timespec.tv_nsec as i64)
+}
+
+/// `value * 10 ** scale` as an integer, or `None` for `NaN` and infinity.
+unsafe fn unscaled_decimal(value: ffi::VALUE, scale: i32) -> Option<i128> {
+    let string = if ffi::RB_TYPE_P(value, ffi::T_FIXNUM) || ffi::RB_TYPE_P(value, ffi::T_BIGNUM) {
+        protect(|| ffi::rb_funcall(value, id!("to_s"), 0))
+    } else {
+        protect(|| ffi::rb_funcall(value, id!("to_s"), 1, rstr!("F")))
+    };
+    let string = to_rust_string(string);
+    let (negative, digits) = if string.starts_with('-') {
+        (true, &string[1..])
+    } else {
+        (false, &string[..])
+    };
+    let mut parts = digits.splitn(2, '.');
+    let whole = parts.next().unwrap_or("");
+    let fraction = parts.next().unwrap_or("");
+    if !whole.bytes().chain(fraction.bytes()).all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let fraction = fraction
+        .chars()
+        .chain(::std::iter::repeat('0'))
+        .take(scale as usize)
+        .collect::<String>();
+    let unscaled = format!("{}{}", whole, fraction).parse::<i128>().unwrap_or_else(|_| {
+        raise(
+            ffi::rb_const_get(ffi::rb_cObject, id!("RangeError")),
+            format!("{} is out of range for a decimal column", string),
+        )
+    });
+    Some(if negative { -unscaled } else { unscaled })
+}
+
+pub struct Writer {
+    schema: Table,
+    file: bool,
+    position: usize,
+    blocks: Vec<u8>,
+    block_count: usize,
+}
+
+impl Writer {
+    /// `fields` are the names and types of the columns. When `file` is true
+    /// the output is in the random access file format, otherwise it's a
+    /// stream.
+    pub fn new(fields: &[(ffi::VALUE, ColumnType)], file: bool) -> Self {
+        let fields = fields
+            .iter()
+            .map(|&(name, ty)| {
+                let name = unsafe {
+                    let name = if ffi::RB_TYPE_P(name, ffi::T_SYMBOL) {
+                        ffi::rb_id2str(ffi::rb_sym2id(name))
+                    } else {
+                        name
+                    };
+                    check_type(name, ffi::T_STRING, ffi::rb_cString);
+                    string_bytes(to_utf8(name)).to_vec()
+                };
+                let (type_type, type_table) = ty.to_flatbuffer();
+                Table::new()
+                    .field(0, Value::String(name))
+                    .field(1, Value::U8(1))
+                    .field(2, Value::U8(type_type))
+                    .field(3, Value::Table(type_table))
+                    .field(5, Value::Tables(Vec::new()))
+            })
+            .collect();
+
+        Writer {
+            schema: Table::new().field(1, Value::Tables(fields)),
+            file,
+            position: 0,
+            blocks: Vec::new(),
+            block_count: 0,
+        }
+    }
+
+    /// The magic bytes of the file format if needed, and the schema message.
+    pub fn start(&mut self) -> Vec<u8> {
+        let mut result = Vec::new();
+        if self.file {
+            result.extend_from_slice(MAGIC);
+            result.extend_from_slice(&[0; 2]);
+        }
+        let schema = self.schema.clone();
+        result.extend(message(HEADER_SCHEMA, schema, &[]).0);
+        self.position += result.len();
+        result
+    }
+
+    pub fn write_batch(&mut self, columns: &[Column]) -> Vec<u8> {
+        let rows = columns.first().map(|column| column.len).unwrap_or(0);
+        let mut body = Vec::new();
+        let mut nodes = Vec::new();
+        let mut buffers = Vec::new();
+        let mut buffer_count = 0;
+        for column in columns {
+            nodes.extend(le_bytes(column.len as u64, 8));
+            nodes.extend(le_bytes(column.null_count as u64, 8));
+            for buffer in column.buffers() {
+                buffers.extend(le_bytes(body.len() as u64, 8));
+                buffers.extend(le_bytes(buffer.len() as u64, 8));
+                buffer_count += 1;
+                body.extend_from_slice(buffer);
+                while body.len() % 8 != 0 {
+                    body.push(0);
+                }
+            }
+        }
+
+        let header = Table::new()
+            .field(0, Value::I64(rows as i64))
+            .field(1, structs(columns.len(), nodes))
+            .field(2, structs(buffer_count, buffers));
+        let (result, metadata_len) = message(HEADER_RECORD_BATCH, header, &body);
+
+        if self.file {
+            self.blocks.extend(le_bytes(self.position as u64, 8));
+            self.blocks.extend(le_bytes(metadata_len as u64, 4));
+            self.blocks.extend_from_slice(&[0; 4]);
+            self.blocks.extend(le_bytes(body.len() as u64, 8));
+            self.block_count += 1;
+        }
+        self.position += result.len();
+        result
+    }
+
+    /// The end of stream marker, and the footer of the file format.
+    pub fn finish(self) -> Vec<u8> {
+        let mut result = CONTINUATION.to_vec();
+        result.extend_from_slice(&[0; 4]);
+
+        if self.file {
+            let footer = Table::new()
+                .field(0, Value::I16(METADATA_VERSION_V5))
+                .field(1, Value::Table(self.schema))
+                .field(2, structs(0, Vec::new()))
+                .field(3, structs(self.block_count, self.blocks));
+            let footer = flatbuffers::finish(&footer);
+            result.extend_from_slice(&footer);
+            result.extend(le_bytes(footer.len() as u64, 4));
+            result.extend_from_slice(MAGIC);
+        }
+        result
+    }
+}
+
+/// An encapsulated message, and the length of everything before its body.
+fn message(header_type: u8, header: Table, body: &[u8]) -> (Vec<u8>, usize) {
+    let metadata = flatbuffers::finish(
+        &Table::new()
+            .field(0, Value::I16(METADATA_VERSION_V5))
+            .field(1, Value::U8(header_type))
+            .field(2, Value::Table(header))
+            .field(3, Value::I64(body.len() as i64)),
+    );
+
+    let mut result = CONTINUATION.to_vec();
+    result.extend(le_bytes(metadata.len() as u64, 4));
+    result.extend_from_slice(&metadata);
+    let metadata_len = result.len();
+    result.extend_from_slice(body);
+    (result, metadata_len)
+}
+
+/// `FieldNode`, `Buffer` and `Block` are all 8 byte aligned.
+fn structs(len: usize, bytes: Vec<u8>) -> Value {
+    Value::Structs {
+        len,
+        alignment: 8,
+        bytes,
+    }
+}
@@ -2,9 +2,13 @@ use std::cell::Cell;
 
 use ffi;
 use into_ruby::IntoRuby;
+use protect::protect;
+use raw_bytes::RawBytes;
 
 mod ruby_glue;
 
+pub use self::ruby_glue::into_stock;
+
 #[derive(Clone, Eq)]
 pub enum Attribute {
     Populated {
@@ -44,6 +48,13 @@ pub enum MaybeProc {
         block: ffi::VALUE,
         memo: Cell<Option<ffi::VALUE>>,
     },
+    /// A raw value from the database which is still in the buffer it was
+    /// read into. The Ruby `String` is created (and memoized) the first time
+    /// it's needed.
+    Bytes {
+        bytes: RawBytes,
+        memo: Cell<Option<ffi::VALUE>>,
+    },
 }
 
 impl Attribute {
@@ -57,6 +68,19 @@ impl Attribute {
         }
     }
 
+    pub fn from_database_bytes(name: ffi::VALUE, bytes: RawBytes, ty: ffi::VALUE) -> Self {
+        Attribute::Populated {
+            name,
+            raw_value: MaybeProc::Bytes {
+                bytes,
+                memo: Cell::new(None),
+            },
+            ty,
+            source: Source::FromDatabase,
+            value: Cell::new(None),
+        }
+    }
+
     fn from_user(
         name: ffi::VALUE,
         raw_value: ffi::VALUE,
@@ -122,12 +146,14 @@ impl Attribute {
                     ..
                 } => {
                     if value.get().is_none() {
-                        value.set(Some(cast_value(source, ty, raw_value.value())));
+                        let cast = native_cast(source, ty, raw_value)
+                            .unwrap_or_else(|| cast_value(source, ty, raw_value.value()));
+                        value.set(Some(cast));
                     }
                     value.get().unwrap()
                 }
                 Uninitialized { name, .. } => if ffi::rb_block_given_p() {
-                    ffi::rb_yield(name)
+                    protect(|| ffi::rb_yield(name))
                 } else {
                     ffi::Qnil
                 },
@@ -138,7 +164,7 @@ impl Attribute {
     fn value_for_database(&self) -> ffi::VALUE {
         let value = self.value();
         let ty = self.ty();
-        unsafe { ffi::rb_funcall(ty, id!("serialize"), 1, value) }
+        unsafe { protect(|| ffi::rb_funcall(ty, id!("serialize"), 1, value)) }
     }
 
     fn is_changed(&self) -> bool {
@@ -153,13 +179,10 @@ impl Attribute {
         unsafe {
             let orig = self.original_value_for_database();
             let value = self.value();
-            ffi::RTEST(ffi::rb_funcall(
-                self.ty(),
-                id!("changed_in_place?"),
-                2,
-                orig,
-                value,
-            ))
+            let ty = self.ty();
+            ffi::RTEST(protect(|| {
+                ffi::rb_funcall(ty, id!("changed_in_place?"), 2, orig, value)
+            }))
         }
     }
 
@@ -174,10 +197,21 @@ impl Attribute {
     }
 
     pub fn with_value_from_user(self, value: ffi::VALUE) -> Self {
+        self.assert_valid_value(value);
+        self.with_valid_value_from_user(value)
+    }
+
+    pub fn assert_valid_value(&self, value: ffi::VALUE) {
         let ty = self.ty();
         unsafe {
-            ffi::rb_funcall(ty, id!("assert_valid_value"), 1, value);
+            protect(|| ffi::rb_funcall(ty, id!("assert_valid_value"), 1, value));
         }
+    }
+
+    /// Same as `with_value_from_user`, but assumes `assert_valid_value` has
+    /// already been called. This never calls into Ruby.
+    pub fn with_valid_value_from_user(self, value: ffi::VALUE) -> Self {
+        let ty = self.ty();
         Self::from_user(self.name(), value, ty, self)
     }
 
@@ -185,11 +219,29 @@ impl Attribute {
         Self::from_database(self.name(), value, self.ty())
     }
 
+    pub fn with_bytes_from_database(&self, bytes: RawBytes) -> Self {
+        Self::from_database_bytes(self.name(), bytes, self.ty())
+    }
+
+    /// Like `with_value_from_database`, when we already know what `value`
+    /// casts to.
+    pub fn with_value_from_database_cast_as(
+        &self,
+        value: ffi::VALUE,
+        cast_value: Option<ffi::VALUE>,
+    ) -> Self {
+        let attr = self.with_value_from_database(value);
+        if let Attribute::Populated { value: ref cell, .. } = attr {
+            cell.set(cast_value);
+        }
+        attr
+    }
+
     pub fn with_cast_value(&self, value: ffi::VALUE) -> Self {
         Self::from_cast_value(self.name(), value, self.ty())
     }
 
-    fn with_type(&self, ty: ffi::VALUE) -> Self {
+    pub fn with_type(&self, ty: ffi::VALUE) -> Self {
         use self::Attribute::*;
 
         if self.is_changed_in_place() {
@@ -221,13 +273,12 @@ impl Attribute {
             ..
         } = *self
         {
+            let raw_value = raw_value.value();
             unsafe {
-                !ffi::RTEST(ffi::rb_funcall(
-                    ty,
-                    id!("value_constructed_by_mass_assignment?"),
-                    1,
-                    raw_value.value(),
-                ))
+                !ffi::RTEST(protect(|| {
+                    let method = id!("value_constructed_by_mass_assignment?");
+                    ffi::rb_funcall(ty, method, 1, raw_value)
+                }))
             }
         } else {
             false
@@ -264,7 +315,7 @@ impl Attribute {
                 raw_value: raw_value.clone(),
                 ty,
                 source: source.clone(),
-                value: Cell::new(value.get().map(|v| unsafe { ffi::rb_obj_dup(v) })),
+                value: Cell::new(value.get().map(|v| protect(|| unsafe { ffi::rb_obj_dup(v) }))),
             },
             _ => other.clone(),
         }
@@ -277,7 +328,7 @@ impl Attribute {
         }
     }
 
-    fn ty(&self) -> ffi::VALUE {
+    pub fn ty(&self) -> ffi::VALUE {
         match *self {
             Attribute::Populated { ty, .. } => ty,
             Attribute::Uninitialized { ty, .. } => ty,
@@ -295,14 +346,9 @@ impl Attribute {
         let raw_value = self.value_before_type_cast();
 
         unsafe {
-            ffi::RTEST(ffi::rb_funcall(
-                ty,
-                id!("changed?"),
-                3,
-                orig,
-                value,
-                raw_value,
-            ))
+            ffi::RTEST(protect(|| {
+                ffi::rb_funcall(ty, id!("changed?"), 3, orig, value, raw_value)
+            }))
         }
     }
 
@@ -337,7 +383,8 @@ impl Attribute {
                 UserProvidedDefault(Some(ref orig)) => orig.original_value(),
             },
             Uninitialized { .. } => unsafe {
-                ffi::rb_const_get(Self::class(), id!("UNINITIALIZED_ORIGINAL_VALUE"))
+                let class = Self::class();
+                protect(|| ffi::rb_const_get(class, id!("UNINITIALIZED_ORIGINAL_VALUE")))
             },
         }
     }
@@ -368,7 +415,7 @@ impl Attribute {
                 ..
             } => {
                 let value = self.original_value();
-                unsafe { ffi::rb_funcall(ty, id!("serialize"), 1, value) }
+                unsafe { protect(|| ffi::rb_funcall(ty, id!("serialize"), 1, value)) }
             }
             Uninitialized { .. } => unsafe { ffi::Qnil },
         }
@@ -391,12 +438,68 @@ impl Attribute {
         }
     }
 
+    /// The attribute as it was last loaded from (or saved to) the database,
+    /// looking through any values assigned over it.
+    pub fn database_attribute(&self) -> Option<&Attribute> {
+        match *self {
+            Attribute::Populated {
+                source: Source::FromDatabase,
+                ..
+            } => Some(self),
+            _ => self.original_attribute()
+                .and_then(Attribute::database_attribute),
+        }
+    }
+
     pub fn deep_dup(&self) -> Self {
         let mut result = Self::default();
         result.initialize_dup(self);
         result
     }
 
+    /// A copy of a default attribute for a new record. Unlike `deep_dup`,
+    /// values which can't be mutated are shared rather than duplicated, and
+    /// procs are called again rather than reusing their result.
+    pub fn dup_default(&self) -> Self {
+        match *self {
+            Attribute::Populated {
+                name,
+                ref raw_value,
+                ty,
+                ref source,
+                ref value,
+            } => {
+                let value = match *raw_value {
+                    MaybeProc::Proc { .. } => None,
+                    MaybeProc::NotProc(_) | MaybeProc::Bytes { .. } => {
+                        value.get().map(dup_if_mutable)
+                    }
+                };
+                Attribute::Populated {
+                    name,
+                    raw_value: raw_value.without_memo(),
+                    ty,
+                    source: source.clone(),
+                    value: Cell::new(value),
+                }
+            }
+            Attribute::Uninitialized { .. } => self.clone(),
+        }
+    }
+
+    /// Whether this attribute, or any attribute it was assigned over, has a
+    /// default which is a proc. Those can't be dumped without calling them.
+    pub fn has_proc(&self) -> bool {
+        let is_proc = match *self {
+            Attribute::Populated {
+                raw_value: MaybeProc::Proc { .. },
+                ..
+            } => true,
+            _ => false,
+        };
+        is_proc || self.original_attribute().map_or(false, Attribute::has_proc)
+    }
+
     pub fn without_cast_value(&self) -> Self {
         let result = self.clone();
         if let Attribute::Populated { ref value, .. } = result {
@@ -443,25 +546,64 @@ impl PartialEq for Attribute {
 }
 
 impl MaybeProc {
-    fn value(&self) -> ffi::VALUE {
+    fn without_memo(&self) -> Self {
+        match *self {
+            MaybeProc::Proc { block, .. } => MaybeProc::Proc {
+                block,
+                memo: Cell::new(None),
+            },
+            MaybeProc::NotProc(value) => MaybeProc::NotProc(value),
+            MaybeProc::Bytes { ref bytes, .. } => MaybeProc::Bytes {
+                bytes: bytes.clone(),
+                memo: Cell::new(None),
+            },
+        }
+    }
+
+    pub fn value(&self) -> ffi::VALUE {
         use self::MaybeProc::*;
 
         match *self {
             NotProc(value) => value,
             Proc { block, ref memo } => {
                 if memo.get().is_none() {
-                    let value = unsafe { ffi::rb_funcall(block, id!("call"), 0) };
+                    let value = unsafe { protect(|| ffi::rb_funcall(block, id!("call"), 0)) };
                     memo.set(Some(value));
                 }
                 memo.get().unwrap()
             }
+            Bytes {
+                ref bytes,
+                ref memo,
+            } => {
+                if memo.get().is_none() {
+                    memo.set(Some(bytes.to_ruby()));
+                }
+                memo.get().unwrap()
+            }
+        }
+    }
+}
+
+fn dup_if_mutable(value: ffi::VALUE) -> ffi::VALUE {
+    unsafe {
+        if ffi::OBJ_FROZEN(value) {
+            value
+        } else {
+            protect(|| ffi::rb_obj_dup(value))
         }
     }
 }
 
 impl PartialEq for MaybeProc {
     fn eq(&self, other: &Self) -> bool {
-        ruby_equals(self.value(), other.value())
+        match (self, other) {
+            (
+                &MaybeProc::Bytes { bytes: ref a, .. },
+                &MaybeProc::Bytes { bytes: ref b, .. },
+            ) => a == b,
+            _ => ruby_equals(self.value(), other.value()),
+        }
     }
 }
 
@@ -469,17 +611,62 @@ pub unsafe fn init() {
     self::ruby_glue::init();
 }
 
+/// Casts raw values which are still in a buffer without creating a Ruby
+/// `String` for them, for the types whose `deserialize` we know the result
+/// of. `None` means the value has to be cast by Ruby.
+fn native_cast(source: &Source, ty: ffi::VALUE, raw_value: &MaybeProc) -> Option<ffi::VALUE> {
+    let bytes = match (source, raw_value) {
+        (&Source::FromDatabase, &MaybeProc::Bytes { ref bytes, .. }) => bytes,
+        _ => return None,
+    };
+    unsafe {
+        // Only the exact class, since subclasses may override `deserialize`
+        if ffi::rb_obj_class(ty) == integer_type() {
+            bytes.parse_integer().map(|value| ffi::I642NUM(value))
+        } else {
+            None
+        }
+    }
+}
+
+/// `ActiveModel::Type::Integer`, which is looked up the first time it's
+/// needed since ActiveModel is loaded after us.
+fn integer_type() -> ffi::VALUE {
+    unsafe {
+        if let Some(ty) = INTEGER_TYPE {
+            return ty;
+        }
+        let ty = protect(|| {
+            let active_model = ffi::rb_const_get(ffi::rb_cObject, id!("ActiveModel"));
+            let type_module = ffi::rb_const_get(active_model, id!("Type"));
+            ffi::rb_const_get(type_module, id!("Integer"))
+        });
+        INTEGER_TYPE = Some(ty);
+        ty
+    }
+}
+
+static mut INTEGER_TYPE: Option<ffi::VALUE> = None;
+
+/// Casts a raw value from the database, as `Attribute#value` would for an
+/// attribute loaded with it.
+pub fn deserialize(ty: ffi::VALUE, raw_value: ffi::VALUE) -> ffi::VALUE {
+    unsafe { protect(|| ffi::rb_funcall(ty, id!("deserialize"), 1, raw_value)) }
+}
+
 fn cast_value(source: &Source, ty: ffi::VALUE, raw_value: ffi::VALUE) -> ffi::VALUE {
     use self::Source::*;
     unsafe {
         match *source {
-            FromDatabase => ffi::rb_funcall(ty, id!("deserialize"), 1, raw_value),
-            FromUser(_) | UserProvidedDefault(_) => ffi::rb_funcall(ty, id!("cast"), 1, raw_value),
+            FromDatabase => deserialize(ty, raw_value),
+            FromUser(_) | UserProvidedDefault(_) => {
+                protect(|| ffi::rb_funcall(ty, id!("cast"), 1, raw_value))
+            }
             PreCast => raw_value,
         }
     }
 }
 
 fn ruby_equals(lhs: ffi::VALUE, rhs: ffi::VALUE) -> bool {
-    unsafe { ffi::RTEST(ffi::rb_funcall(lhs, id!("=="), 1, rhs)) }
+    unsafe { ffi::RTEST(protect(|| ffi::rb_funcall(lhs, id!("=="), 1, rhs))) }
 }
@@ -2,6 +2,9 @@ use std::cell::Cell;
 
 use ffi;
 use into_ruby::*;
+use load::*;
+use protect::{protect, ruby_boundary};
+use stock;
 use super::{Attribute, MaybeProc, Source};
 use util::*;
 
@@ -53,11 +56,19 @@ impl MaybeProc {
                     ffi::rb_gc_mark(memo);
                 }
             }
+            Bytes { ref memo, .. } => if let Some(memo) = memo.get() {
+                ffi::rb_gc_mark(memo);
+            },
         }
     }
 }
 
 static mut ATTRIBUTE: Option<ffi::VALUE> = None;
+static mut FROM_DATABASE: Option<ffi::VALUE> = None;
+static mut FROM_USER: Option<ffi::VALUE> = None;
+static mut WITH_CAST_VALUE: Option<ffi::VALUE> = None;
+static mut USER_PROVIDED_DEFAULT: Option<ffi::VALUE> = None;
+static mut UNINITIALIZED: Option<ffi::VALUE> = None;
 
 pub unsafe fn init() {
     let attribute = ffi::rb_define_class_under(::module(), cstr!("Attribute"), ffi::rb_cObject);
@@ -180,7 +191,10 @@ pub unsafe fn init() {
         1,
     );
 
-    let from_database = ffi::rb_define_class_under(attribute, cstr!("FromDatabase"), attribute);
+    // These are named after their stock counterparts by the Ruby side of the
+    // gem, see `stock.rs`
+    let from_database = stock::define_class::<Attribute>(attribute, dump_stock, load_stock);
+    FROM_DATABASE = Some(from_database);
     ffi::rb_define_method(
         from_database,
         cstr!("init_with"),
@@ -188,7 +202,8 @@ pub unsafe fn init() {
         1,
     );
 
-    let from_user = ffi::rb_define_class_under(attribute, cstr!("FromUser"), attribute);
+    let from_user = stock::define_class::<Attribute>(attribute, dump_stock, load_stock);
+    FROM_USER = Some(from_user);
     ffi::rb_define_method(
         from_user,
         cstr!("init_with"),
@@ -196,22 +211,44 @@ pub unsafe fn init() {
         1,
     );
 
+    let uninitialized = stock::define_class::<Attribute>(attribute, dump_stock, load_stock);
+    UNINITIALIZED = Some(uninitialized);
     ffi::rb_define_method(
-        ffi::rb_define_class_under(attribute, cstr!("Uninitialized"), attribute),
+        uninitialized,
         cstr!("init_with"),
         init_with_uninitialized as *const _,
         1,
     );
+
+    let with_cast_value = stock::define_class::<Attribute>(attribute, dump_stock, load_stock);
+    WITH_CAST_VALUE = Some(with_cast_value);
+    ffi::rb_define_method(
+        with_cast_value,
+        cstr!("init_with"),
+        init_with_precast as *const _,
+        1,
+    );
+
+    let user_provided_default =
+        stock::define_class::<Attribute>(from_user, dump_stock, load_stock);
+    USER_PROVIDED_DEFAULT = Some(user_provided_default);
+    ffi::rb_define_method(
+        user_provided_default,
+        cstr!("init_with"),
+        init_with_user_provided_default as *const _,
+        1,
+    );
+
+    ffi::rb_define_singleton_method(
+        attribute,
+        cstr!("stock_classes"),
+        stock_classes as *const _,
+        0,
+    );
 }
 
 fn from_value(value: ffi::VALUE) -> Attribute {
-    unsafe {
-        if ffi::rb_obj_class(value) == Attribute::class() {
-            get_struct::<Attribute>(value).clone()
-        } else {
-            ffi::rb_raise(ffi::rb_eRuntimeError, cstr!("Expected an `Attribute`"))
-        }
-    }
+    unsafe { get_struct::<Attribute>(value).clone() }
 }
 
 extern "C" fn from_database(
@@ -220,7 +257,7 @@ extern "C" fn from_database(
     value: ffi::VALUE,
     ty: ffi::VALUE,
 ) -> ffi::VALUE {
-    Attribute::from_database(name, value, ty).into_ruby()
+    ruby_boundary(|| Attribute::from_database(name, value, ty).into_ruby())
 }
 
 extern "C" fn from_user(
@@ -230,8 +267,10 @@ extern "C" fn from_user(
     ty: ffi::VALUE,
     original_attribute: ffi::VALUE,
 ) -> ffi::VALUE {
-    let original_attribute = from_value(original_attribute);
-    Attribute::from_user(name, value, ty, original_attribute).into_ruby()
+    ruby_boundary(|| {
+        let original_attribute = from_value(original_attribute);
+        Attribute::from_user(name, value, ty, original_attribute).into_ruby()
+    })
 }
 
 extern "C" fn from_cast_value(
@@ -240,11 +279,11 @@ extern "C" fn from_cast_value(
     value: ffi::VALUE,
     ty: ffi::VALUE,
 ) -> ffi::VALUE {
-    Attribute::from_cast_value(name, value, ty).into_ruby()
+    ruby_boundary(|| Attribute::from_cast_value(name, value, ty).into_ruby())
 }
 
 extern "C" fn uninitialized(_class: ffi::VALUE, name: ffi::VALUE, ty: ffi::VALUE) -> ffi::VALUE {
-    Attribute::uninitialized(name, ty).into_ruby()
+    ruby_boundary(|| Attribute::uninitialized(name, ty).into_ruby())
 }
 
 extern "C" fn user_provided_default(
@@ -254,248 +293,293 @@ extern "C" fn user_provided_default(
     ty: ffi::VALUE,
     original_attribute: ffi::VALUE,
 ) -> ffi::VALUE {
-    let proc_c = unsafe { ffi::rb_const_get(ffi::rb_cObject, id!("Proc")) };
-    let value = unsafe {
-        if ffi::RTEST(ffi::rb_funcall(value, id!("is_a?"), 1, proc_c)) {
-            MaybeProc::Proc {
-                block: value,
-                memo: Default::default(),
+    ruby_boundary(|| {
+        let proc_c = unsafe { protect(|| ffi::rb_const_get(ffi::rb_cObject, id!("Proc"))) };
+        let value = unsafe {
+            if ffi::RTEST(protect(|| ffi::rb_funcall(value, id!("is_a?"), 1, proc_c))) {
+                MaybeProc::Proc {
+                    block: value,
+                    memo: Default::default(),
+                }
+            } else {
+                MaybeProc::NotProc(value)
             }
-        } else {
-            MaybeProc::NotProc(value)
-        }
-    };
+        };
 
-    let original_attribute = unsafe {
-        if ffi::RB_NIL_P(original_attribute) {
-            None
-        } else {
-            Some(get_struct::<Attribute>(original_attribute).clone())
-        }
-    };
-    Attribute::user_provided_default(name, value, ty, original_attribute).into_ruby()
+        let original_attribute = unsafe {
+            if ffi::RB_NIL_P(original_attribute) {
+                None
+            } else {
+                Some(from_value(original_attribute))
+            }
+        };
+        Attribute::user_provided_default(name, value, ty, original_attribute).into_ruby()
+    })
 }
 
 extern "C" fn value_before_type_cast(this: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<Attribute>(this) };
-    this.value_before_type_cast()
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Attribute>(this) };
+        this.value_before_type_cast()
+    })
 }
 
 extern "C" fn name(this: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<Attribute>(this) };
-    this.name()
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Attribute>(this) };
+        this.name()
+    })
 }
 
 extern "C" fn ty(this: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<Attribute>(this) };
-    this.ty()
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Attribute>(this) };
+        this.ty()
+    })
 }
 
 extern "C" fn value(this: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<Attribute>(this) };
-    this.value()
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Attribute>(this) };
+        this.value()
+    })
 }
 
 extern "C" fn original_value(this: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<Attribute>(this) };
-    this.original_value()
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Attribute>(this) };
+        this.original_value()
+    })
 }
 
 extern "C" fn value_for_database(this: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<Attribute>(this) };
-    this.value_for_database()
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Attribute>(this) };
+        this.value_for_database()
+    })
 }
 
 extern "C" fn changed_eh(this: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<Attribute>(this) };
-    to_ruby_bool(this.is_changed())
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Attribute>(this) };
+        to_ruby_bool(this.is_changed())
+    })
 }
 
 extern "C" fn changed_in_place_eh(this: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<Attribute>(this) };
-    to_ruby_bool(this.is_changed_in_place())
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Attribute>(this) };
+        to_ruby_bool(this.is_changed_in_place())
+    })
 }
 
 extern "C" fn forgetting_assignment(this: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<Attribute>(this) };
-    this.forgetting_assignment().into_ruby()
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Attribute>(this) };
+        this.forgetting_assignment().into_ruby()
+    })
 }
 
 extern "C" fn with_value_from_user(this: ffi::VALUE, value: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<Attribute>(this) };
-    this.clone().with_value_from_user(value).into_ruby()
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Attribute>(this) };
+        this.clone().with_value_from_user(value).into_ruby()
+    })
 }
 
 extern "C" fn with_value_from_database(this: ffi::VALUE, value: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<Attribute>(this) };
-    this.with_value_from_database(value).into_ruby()
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Attribute>(this) };
+        this.with_value_from_database(value).into_ruby()
+    })
 }
 
 extern "C" fn with_cast_value(this: ffi::VALUE, value: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<Attribute>(this) };
-    this.with_cast_value(value).into_ruby()
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Attribute>(this) };
+        this.with_cast_value(value).into_ruby()
+    })
 }
 
 extern "C" fn with_type(this: ffi::VALUE, ty: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<Attribute>(this) };
-    this.with_type(ty).into_ruby()
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Attribute>(this) };
+        this.with_type(ty).into_ruby()
+    })
 }
 
 extern "C" fn initialized_eh(this: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<Attribute>(this) };
-    to_ruby_bool(this.is_initialized())
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Attribute>(this) };
+        to_ruby_bool(this.is_initialized())
+    })
 }
 
 extern "C" fn came_from_user(this: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<Attribute>(this) };
-    to_ruby_bool(this.came_from_user())
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Attribute>(this) };
+        to_ruby_bool(this.came_from_user())
+    })
 }
 
 extern "C" fn has_been_read(this: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<Attribute>(this) };
-    to_ruby_bool(this.has_been_read())
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Attribute>(this) };
+        to_ruby_bool(this.has_been_read())
+    })
 }
 
 extern "C" fn equals(this: ffi::VALUE, other: ffi::VALUE) -> ffi::VALUE {
-    unsafe {
+    ruby_boundary(|| unsafe {
         if !ffi::RB_TYPE_P(other, ffi::T_DATA) {
             return ffi::Qfalse;
         }
-        if ffi::rb_obj_class(other) != Attribute::class() {
+        if !is_kind_of(other, Attribute::class()) {
             return ffi::Qfalse;
         }
 
         let this = get_struct::<Attribute>(this);
         let other = get_struct::<Attribute>(other);
         to_ruby_bool(this == other)
-    }
+    })
 }
 
 extern "C" fn hash(this: ffi::VALUE) -> ffi::VALUE {
-    use self::Attribute::*;
-    use self::Source::*;
+    ruby_boundary(|| {
+        use self::Attribute::*;
+        use self::Source::*;
 
-    unsafe {
-        let this = get_struct::<Attribute>(this);
-        let discriminant = match *this {
-            Uninitialized { .. } => 0,
-            Populated {
-                source: FromUser(_),
-                ..
-            } => 1,
-            Populated {
-                source: FromDatabase,
-                ..
-            } => 2,
-            Populated {
-                source: PreCast, ..
-            } => 3,
-            Populated {
-                source: UserProvidedDefault(_),
-                ..
-            } => 4,
-        };
-        let discriminant = ffi::I322NUM(discriminant);
-        let name = this.name();
-        let value = this.value_before_type_cast();
-        let ty = this.ty();
+        unsafe {
+            let this = get_struct::<Attribute>(this);
+            let discriminant = match *this {
+                Uninitialized { .. } => 0,
+                Populated {
+                    source: FromUser(_),
+                    ..
+                } => 1,
+                Populated {
+                    source: FromDatabase,
+                    ..
+                } => 2,
+                Populated {
+                    source: PreCast, ..
+                } => 3,
+                Populated {
+                    source: UserProvidedDefault(_),
+                    ..
+                } => 4,
+            };
+            let discriminant = ffi::I322NUM(discriminant);
+            let name = this.name();
+            let value = this.value_before_type_cast();
+            let ty = this.ty();
 
-        let ary = to_ruby_array(4, vec![discriminant, name, value, ty]);
-        ffi::rb_funcall(ary, id!("hash"), 0)
-    }
+            let ary = to_ruby_array(4, vec![discriminant, name, value, ty]);
+            protect(|| ffi::rb_funcall(ary, id!("hash"), 0))
+        }
+    })
 }
 
 extern "C" fn initialize_dup(this: ffi::VALUE, other: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct_mut::<Attribute>(this) };
-    let other = unsafe { get_struct::<Attribute>(other) };
-    this.initialize_dup(other);
-    unsafe { ffi::Qnil }
+    ruby_boundary(|| {
+        let this = unsafe { get_struct_mut::<Attribute>(this) };
+        let other = unsafe { get_struct::<Attribute>(other) };
+        this.initialize_dup(other);
+        unsafe { ffi::Qnil }
+    })
 }
 
 extern "C" fn dump_data(this: ffi::VALUE) -> ffi::VALUE {
-    use self::Attribute::*;
-    let this = unsafe { get_struct::<Attribute>(this) };
+    ruby_boundary(|| {
+        use self::Attribute::*;
+        let this = unsafe { get_struct::<Attribute>(this) };
 
-    return match *this {
-        Populated {
-            name,
-            ref raw_value,
-            ty,
-            ref source,
-            value: ref _value,
-        } => to_ruby_array(4, vec![name, ty, raw_value.value(), dump_source(source)]),
-        Uninitialized { name, ty } => to_ruby_array(2, vec![name, ty]),
-    };
+        return match *this {
+            Populated {
+                name,
+                ref raw_value,
+                ty,
+                ref source,
+                value: ref _value,
+            } => to_ruby_array(4, vec![name, ty, raw_value.value(), dump_source(source)]),
+            Uninitialized { name, ty } => to_ruby_array(2, vec![name, ty]),
+        };
+    })
 }
 
 extern "C" fn load_data(this: ffi::VALUE, data: ffi::VALUE) -> ffi::VALUE {
-    use self::Attribute::*;
-    use self::MaybeProc::*;
+    ruby_boundary(|| {
+        use self::Attribute::*;
+        use self::MaybeProc::*;
 
-    unsafe {
-        let this = get_struct_mut::<Attribute>(this);
-        let name = ffi::rb_ary_entry(data, 0);
-        let ty = ffi::rb_ary_entry(data, 1);
-        let raw_value = NotProc(ffi::rb_ary_entry(data, 2));
-        let source = ffi::rb_ary_entry(data, 3);
+        unsafe {
+            let len = expect_array(data, 2, 4, "attribute data");
+            let name = ffi::rb_ary_entry(data, 0);
+            let ty = ffi::rb_ary_entry(data, 1);
+            let raw_value = NotProc(ffi::rb_ary_entry(data, 2));
+            let source = ffi::rb_ary_entry(data, 3);
 
-        if ffi::RB_NIL_P(source) {
-            *this = Uninitialized { name, ty };
-        } else {
-            let source = load_source(source);
-            *this = Populated {
-                name,
-                ty,
-                raw_value,
-                source,
-                value: Cell::new(None),
+            let attr = if ffi::RB_NIL_P(source) {
+                if len > 2 {
+                    load_error("expected attribute data with a value to have a source");
+                }
+                Uninitialized { name, ty }
+            } else {
+                let source = load_source(source);
+                Populated {
+                    name,
+                    ty,
+                    raw_value,
+                    source,
+                    value: Cell::new(None),
+                }
             };
-        }
+            *get_struct_mut::<Attribute>(this) = attr;
 
-        ffi::Qnil
-    }
+            ffi::Qnil
+        }
+    })
 }
 
 extern "C" fn encode_with(this: ffi::VALUE, coder: ffi::VALUE) -> ffi::VALUE {
-    unsafe {
+    ruby_boundary(|| unsafe {
         let this = get_struct::<Attribute>(this);
         let value_before_type_cast = this.value_before_type_cast();
         let ty = this.ty();
 
-        ffi::rb_funcall(coder, id!("[]="), 2, rstr!("name"), this.name());
+        let name = this.name();
+        protect(|| ffi::rb_funcall(coder, id!("[]="), 2, rstr!("name"), name));
 
         if !ffi::RB_NIL_P(ty) {
-            ffi::rb_funcall(coder, id!("[]="), 2, rstr!("type"), ty);
+            protect(|| ffi::rb_funcall(coder, id!("[]="), 2, rstr!("type"), ty));
         }
 
         if !ffi::RB_NIL_P(value_before_type_cast) {
-            ffi::rb_funcall(
-                coder,
-                id!("[]="),
-                2,
-                rstr!("value_before_type_cast"),
-                value_before_type_cast,
-            );
+            protect(|| {
+                ffi::rb_funcall(
+                    coder,
+                    id!("[]="),
+                    2,
+                    rstr!("value_before_type_cast"),
+                    value_before_type_cast,
+                )
+            });
         }
 
         if let Some(orig) = this.original_attribute() {
-            ffi::rb_funcall(
-                coder,
-                id!("[]="),
-                2,
-                rstr!("original_attribute"),
-                orig.as_ruby(),
-            );
+            let orig = orig.as_ruby();
+            protect(|| ffi::rb_funcall(coder, id!("[]="), 2, rstr!("original_attribute"), orig));
         }
 
         if this.has_been_read() {
-            ffi::rb_funcall(coder, id!("[]="), 2, rstr!("value"), this.value());
+            let value = this.value();
+            protect(|| ffi::rb_funcall(coder, id!("[]="), 2, rstr!("value"), value));
         }
 
         lie_about_our_class(this, coder);
 
         ffi::Qnil
-    }
+    })
 }
 
 fn lie_about_our_class(this: &Attribute, coder: ffi::VALUE) {
@@ -525,17 +609,18 @@ fn lie_about_our_class(this: &Attribute, coder: ffi::VALUE) {
     // but it's the only method in the public API that lets us do it without
     // other side effects
     unsafe {
-        ffi::rb_funcall(coder, id!("map"), 1, rstr!(&tag));
+        protect(|| ffi::rb_funcall(coder, id!("map"), 1, rstr!(&tag)));
     }
 }
 
 /// Contains the common logic of `init_with` for all populated variants.
 /// Source will be set to `Source::PreCast`.
 unsafe fn init_with_populated(this: &mut Attribute, coder: ffi::VALUE) {
-    let name = ffi::rb_funcall(coder, id!("[]"), 1, rstr!("name"));
-    let ty = ffi::rb_funcall(coder, id!("[]"), 1, rstr!("type"));
-    let raw_value = ffi::rb_funcall(coder, id!("[]"), 1, rstr!("value_before_type_cast"));
-    let value = ffi::rb_funcall(coder, id!("[]"), 1, rstr!("value"));
+    let name = protect(|| ffi::rb_funcall(coder, id!("[]"), 1, rstr!("name")));
+    let ty = protect(|| ffi::rb_funcall(coder, id!("[]"), 1, rstr!("type")));
+    let raw_value =
+        protect(|| ffi::rb_funcall(coder, id!("[]"), 1, rstr!("value_before_type_cast")));
+    let value = protect(|| ffi::rb_funcall(coder, id!("[]"), 1, rstr!("value")));
 
     let value = if ffi::RB_NIL_P(value) {
         None
@@ -553,7 +638,7 @@ unsafe fn init_with_populated(this: &mut Attribute, coder: ffi::VALUE) {
 }
 
 extern "C" fn init_with_from_database(this: ffi::VALUE, coder: ffi::VALUE) -> ffi::VALUE {
-    unsafe {
+    ruby_boundary(|| unsafe {
         let this = get_struct_mut::<Attribute>(this);
         init_with_populated(this, coder);
         match *this {
@@ -561,54 +646,85 @@ extern "C" fn init_with_from_database(this: ffi::VALUE, coder: ffi::VALUE) -> ff
             _ => unreachable!(),
         }
         ffi::Qnil
-    }
+    })
 }
 
 extern "C" fn init_with_from_user(this: ffi::VALUE, coder: ffi::VALUE) -> ffi::VALUE {
-    unsafe {
+    ruby_boundary(|| unsafe {
         let this = get_struct_mut::<Attribute>(this);
         init_with_populated(this, coder);
-        let original_attribute = ffi::rb_funcall(coder, id!("[]"), 1, rstr!("original_attribute"));
-        let original_attribute = if ffi::RB_NIL_P(original_attribute) {
-            None
-        } else {
-            Some(Box::new(
-                get_struct::<Attribute>(original_attribute).clone(),
-            ))
-        };
+        let original_attribute = init_with_original_attribute(coder);
         // Even though this was a `FromUser` subclass, if this YAML was
         // dumped in Rails 4.2, `original_attribute` won't be there.
         // `UserProvidedDefault` is the only thing that can have no original.
+        let new_source = match original_attribute {
+            Some(original_attribute) => Source::FromUser(original_attribute),
+            None => Source::UserProvidedDefault(None),
+        };
+        match *this {
+            Attribute::Populated { ref mut source, .. } => *source = new_source,
+            _ => unreachable!(),
+        }
+
+        ffi::Qnil
+    })
+}
+
+extern "C" fn init_with_user_provided_default(
+    this: ffi::VALUE,
+    coder: ffi::VALUE,
+) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        let this = get_struct_mut::<Attribute>(this);
+        init_with_populated(this, coder);
+        let original_attribute = init_with_original_attribute(coder);
+        // Before Rails 5.2 there was no `encode_with`, so the YAML holds
+        // instance variables, and the value is in `@user_provided_value`
+        let user_provided_value =
+            protect(|| ffi::rb_funcall(coder, id!("[]"), 1, rstr!("user_provided_value")));
         match *this {
-            Attribute::Populated { ref mut source, .. } => {
-                *source = Source::UserProvidedDefault(original_attribute)
+            Attribute::Populated {
+                ref mut source,
+                ref mut raw_value,
+                ..
+            } => {
+                *source = Source::UserProvidedDefault(original_attribute);
+                if ffi::RB_NIL_P(raw_value.value()) {
+                    *raw_value = MaybeProc::NotProc(user_provided_value);
+                }
             }
             _ => unreachable!(),
         }
 
         ffi::Qnil
-    }
+    })
+}
+
+unsafe fn init_with_original_attribute(coder: ffi::VALUE) -> Option<Box<Attribute>> {
+    let original_attribute =
+        protect(|| ffi::rb_funcall(coder, id!("[]"), 1, rstr!("original_attribute")));
+    expect_optional_attribute(original_attribute, "original_attribute").map(Box::new)
 }
 
 extern "C" fn init_with_precast(this: ffi::VALUE, coder: ffi::VALUE) -> ffi::VALUE {
-    unsafe {
+    ruby_boundary(|| unsafe {
         let this = get_struct_mut::<Attribute>(this);
         init_with_populated(this, coder);
 
         ffi::Qnil
-    }
+    })
 }
 
 extern "C" fn init_with_uninitialized(this: ffi::VALUE, coder: ffi::VALUE) -> ffi::VALUE {
-    unsafe {
+    ruby_boundary(|| unsafe {
         let this = get_struct_mut::<Attribute>(this);
-        let name = ffi::rb_funcall(coder, id!("[]"), 1, rstr!("name"));
-        let ty = ffi::rb_funcall(coder, id!("[]"), 1, rstr!("type"));
+        let name = protect(|| ffi::rb_funcall(coder, id!("[]"), 1, rstr!("name")));
+        let ty = protect(|| ffi::rb_funcall(coder, id!("[]"), 1, rstr!("type")));
 
         *this = Attribute::Uninitialized { name, ty };
 
         ffi::Qnil
-    }
+    })
 }
 
 fn dump_source(source: &'static Source) -> ffi::VALUE {
@@ -630,24 +746,152 @@ fn dump_source(source: &'static Source) -> ffi::VALUE {
 fn load_source(source: ffi::VALUE) -> Source {
     use self::Source::*;
 
-    fn error() -> ! {
-        unsafe { ffi::rb_raise(ffi::rb_eRuntimeError, cstr!("Unrecognized attribute")) };
-    }
-
     unsafe {
+        expect_array(source, 2, 2, "attribute source");
         let discriminant = ffi::rb_ary_entry(source, 0);
         let attr = ffi::rb_ary_entry(source, 1);
-        let attr = if ffi::RB_NIL_P(attr) {
-            None
-        } else {
-            Some(Box::new(get_struct::<Attribute>(attr).clone()))
-        };
-        match ffi::NUM2I32(discriminant) {
-            1 => FromUser(attr.unwrap()),
+        let attr = expect_optional_attribute(attr, "original attribute").map(Box::new);
+        match expect_integer(discriminant, 1, 4, "attribute source") {
+            1 => FromUser(attr.unwrap_or_else(|| {
+                load_error("expected a user assigned attribute to have an original attribute")
+            })),
             2 => FromDatabase,
             3 => PreCast,
-            4 => UserProvidedDefault(attr),
-            _ => error(),
+            _ => UserProvidedDefault(attr),
         }
     }
 }
+
+extern "C" fn stock_classes(_class: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        let result = ffi::rb_hash_new();
+        let classes = [
+            ("FromDatabase", FROM_DATABASE),
+            ("FromUser", FROM_USER),
+            ("WithCastValue", WITH_CAST_VALUE),
+            ("UserProvidedDefault", USER_PROVIDED_DEFAULT),
+            ("Uninitialized", UNINITIALIZED),
+        ];
+        for &(name, class) in &classes {
+            let name = ffi::rb_id2sym(ffi::rb_intern_str(rstr!(name)));
+            ffi::rb_hash_aset(result, name, class.unwrap());
+        }
+        result
+    })
+}
+
+/// Wraps a copy of `attr` in the stock class matching its source, which
+/// Marshal will dump in the format stock ActiveModel expects.
+pub fn into_stock(attr: &Attribute) -> ffi::VALUE {
+    use self::Source::*;
+
+    unsafe {
+        let class = match *attr {
+            Attribute::Uninitialized { .. } => UNINITIALIZED,
+            Attribute::Populated { ref source, .. } => match *source {
+                FromUser(_) => FROM_USER,
+                FromDatabase => FROM_DATABASE,
+                PreCast => WITH_CAST_VALUE,
+                UserProvidedDefault(_) => USER_PROVIDED_DEFAULT,
+            },
+        };
+        attr.clone().into_ruby_as(class.unwrap())
+    }
+}
+
+extern "C" fn dump_stock(this: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| {
+        use self::Source::*;
+
+        let this = unsafe { get_struct::<Attribute>(this) };
+        let nil = unsafe { ffi::Qnil };
+        let result = stock::new_object();
+        let (name, ty, raw_value, original) = match *this {
+            Attribute::Uninitialized { name, ty } => (name, ty, nil, nil),
+            Attribute::Populated {
+                name,
+                ty,
+                ref raw_value,
+                ref source,
+                ref value,
+            } => {
+                let raw_value = raw_value.value();
+                let original = match *source {
+                    FromUser(ref orig) | UserProvidedDefault(Some(ref orig)) => into_stock(orig),
+                    _ => nil,
+                };
+                if let UserProvidedDefault(_) = *source {
+                    stock::set_ivar(result, id!("@user_provided_value"), raw_value);
+                }
+                if let Some(value) = value.get() {
+                    stock::set_ivar(result, id!("@value"), value);
+                }
+                (name, ty, raw_value, original)
+            }
+        };
+        stock::set_ivar(result, id!("@name"), name);
+        stock::set_ivar(result, id!("@value_before_type_cast"), raw_value);
+        stock::set_ivar(result, id!("@type"), ty);
+        stock::set_ivar(result, id!("@original_attribute"), original);
+        result
+    })
+}
+
+extern "C" fn load_stock(this: ffi::VALUE, data: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        let nil = ffi::Qnil;
+        let (name, raw_value, ty, original, value) = match stock::marshal_data(data) {
+            // `UserProvidedDefault#marshal_dump`
+            Some(data) => {
+                let len = expect_array(data, 4, 5, "attribute data");
+                let value = if len == 5 {
+                    Some(ffi::rb_ary_entry(data, 4))
+                } else {
+                    None
+                };
+                (
+                    ffi::rb_ary_entry(data, 0),
+                    ffi::rb_ary_entry(data, 1),
+                    ffi::rb_ary_entry(data, 2),
+                    ffi::rb_ary_entry(data, 3),
+                    value,
+                )
+            }
+            None => (
+                stock::ivar(data, id!("@name")).unwrap_or(nil),
+                stock::ivar(data, id!("@value_before_type_cast")).unwrap_or(nil),
+                stock::ivar(data, id!("@type")).unwrap_or(nil),
+                stock::ivar(data, id!("@original_attribute")).unwrap_or(nil),
+                stock::ivar(data, id!("@value")),
+            ),
+        };
+
+        let class = Some(ffi::rb_obj_class(this));
+        let attr = if class == UNINITIALIZED {
+            Attribute::Uninitialized { name, ty }
+        } else {
+            let original = expect_optional_attribute(original, "original attribute").map(Box::new);
+            let source = if class == FROM_DATABASE {
+                Source::FromDatabase
+            } else if class == WITH_CAST_VALUE {
+                Source::PreCast
+            } else if class == USER_PROVIDED_DEFAULT {
+                Source::UserProvidedDefault(original)
+            } else {
+                Source::FromUser(original.unwrap_or_else(|| {
+                    load_error("expected a user assigned attribute to have an original attribute")
+                }))
+            };
+            Attribute::Populated {
+                name,
+                ty,
+                raw_value: MaybeProc::NotProc(raw_value),
+                source,
+                value: Cell::new(value),
+            }
+        };
+        *get_struct_mut::<Attribute>(this) = attr;
+
+        ffi::Qnil
+    })
+}
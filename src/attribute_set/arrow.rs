@@ -0,0 +1,48 @@
+//! `RailsFastAttributes.write_arrow`, which writes attribute sets built by
+//! the same `Builder` as Arrow record batches, with one column per attribute
+//! of the builder.
+
+use arrow::{Column, ColumnType};
+use builder::Builder;
+use ffi;
+use super::AttributeSet;
+
+pub struct Schema(Vec<(ffi::ID, ffi::VALUE, ColumnType)>);
+
+impl Schema {
+    pub fn new(builder: &Builder) -> Self {
+        let columns = builder
+            .attributes()
+            .iter()
+            .map(|(&key, attr)| (key, attr.name(), ColumnType::from_attribute_type(attr.ty())))
+            .collect();
+        Schema(columns)
+    }
+
+    pub fn fields(&self) -> Vec<(ffi::VALUE, ColumnType)> {
+        self.0.iter().map(|&(_, name, ty)| (name, ty)).collect()
+    }
+
+    /// The cast values of `sets`, column by column. Attributes which are
+    /// missing or uninitialized are null.
+    pub fn columns<'a, I>(&self, sets: I) -> Vec<Column>
+    where
+        I: IntoIterator<Item = &'a AttributeSet>,
+    {
+        let mut columns = self.0
+            .iter()
+            .map(|&(_, _, ty)| Column::new(ty))
+            .collect::<Vec<_>>();
+        for set in sets {
+            let _guard = set.borrow.read();
+            for (column, &(key, _, _)) in columns.iter_mut().zip(&self.0) {
+                let value = match set.get(key) {
+                    Some(attr) if attr.is_initialized() => attr.value(),
+                    _ => unsafe { ffi::Qnil },
+                };
+                column.push(value);
+            }
+        }
+        columns
+    }
+}
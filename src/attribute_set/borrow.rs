@@ -0,0 +1,79 @@
+use std::cell::Cell;
+
+use ffi;
+use util::raise;
+
+/// Tracks the operations which are in progress on an `AttributeSet`.
+///
+/// Most operations call back into Ruby (a type's `cast`, the block given to
+/// `map`, etc), and that Ruby code can call methods on the very same set.
+/// Reading from the set during those callbacks is fine, since we never hold a
+/// mutable reference into the map while calling into Ruby. Modifying it would
+/// invalidate whatever the outer operation is holding, so that raises instead.
+#[derive(Default)]
+pub struct BorrowFlag {
+    readers: Cell<usize>,
+    writing: Cell<bool>,
+}
+
+impl BorrowFlag {
+    pub fn read<'a>(&'a self) -> ReadGuard<'a> {
+        self.readers.set(self.readers.get() + 1);
+        ReadGuard { flag: self }
+    }
+
+    pub fn write<'a>(&'a self) -> WriteGuard<'a> {
+        if self.writing.get() {
+            raise(
+                unsafe { ffi::rb_eRuntimeError },
+                "can't modify an AttributeSet while it is already being modified",
+            );
+        }
+        if self.readers.get() > 0 {
+            raise(
+                unsafe { ffi::rb_eRuntimeError },
+                "can't modify an AttributeSet while it is being read",
+            );
+        }
+        self.writing.set(true);
+        WriteGuard { flag: self }
+    }
+}
+
+/// Copies of a set start out with nothing in progress.
+impl Clone for BorrowFlag {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+
+    fn clone_from(&mut self, _: &Self) {}
+}
+
+/// The borrow state has no bearing on equality.
+impl PartialEq for BorrowFlag {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for BorrowFlag {}
+
+pub struct ReadGuard<'a> {
+    flag: &'a BorrowFlag,
+}
+
+impl<'a> Drop for ReadGuard<'a> {
+    fn drop(&mut self) {
+        self.flag.readers.set(self.flag.readers.get() - 1);
+    }
+}
+
+pub struct WriteGuard<'a> {
+    flag: &'a BorrowFlag,
+}
+
+impl<'a> Drop for WriteGuard<'a> {
+    fn drop(&mut self) {
+        self.flag.writing.set(false);
+    }
+}
@@ -0,0 +1,61 @@
+//! `RailsFastAttributes.write_csv`, which writes one row per attribute set.
+
+use csv::Writer;
+use ffi;
+use super::AttributeSet;
+use util::*;
+
+pub struct Columns(Vec<(ffi::ID, ffi::VALUE)>);
+
+impl Columns {
+    /// The given column names, or when `columns` is `nil`, the initialized
+    /// attributes of `first` in order.
+    pub unsafe fn new(columns: ffi::VALUE, first: Option<&AttributeSet>) -> Self {
+        if ffi::RB_NIL_P(columns) {
+            let columns = first
+                .map(|set| {
+                    let _guard = set.borrow.read();
+                    set.attributes
+                        .iter()
+                        .filter(|&(_, attr)| attr.is_initialized())
+                        .map(|(&key, attr)| (key, attr.name()))
+                        .collect()
+                })
+                .unwrap_or_else(Vec::new);
+            Columns(columns)
+        } else {
+            check_type(columns, ffi::T_ARRAY, ffi::rb_const_get(ffi::rb_cObject, id!("Array")));
+            let columns = (0..ffi::RARRAY_LEN(columns))
+                .map(|i| {
+                    let name = ffi::rb_ary_entry(columns, i);
+                    (string_or_symbol_to_id(name), name)
+                })
+                .collect();
+            Columns(columns)
+        }
+    }
+
+    pub fn write_header(&self, writer: &mut Writer) {
+        for &(_, name) in &self.0 {
+            writer.write_value(name);
+        }
+        writer.end_row();
+    }
+
+    /// Writes the values of `set` for each column. Columns which are missing
+    /// or uninitialized are written as empty fields, like `nil`.
+    pub fn write_row(&self, writer: &mut Writer, set: &AttributeSet, before_type_cast: bool) {
+        let _guard = set.borrow.read();
+        for &(key, _) in &self.0 {
+            let value = match set.get(key) {
+                Some(attr) if attr.is_initialized() && before_type_cast => {
+                    attr.value_before_type_cast()
+                }
+                Some(attr) if attr.is_initialized() => attr.value(),
+                _ => unsafe { ffi::Qnil },
+            };
+            writer.write_value(value);
+        }
+        writer.end_row();
+    }
+}
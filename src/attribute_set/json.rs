@@ -0,0 +1,100 @@
+//! `AttributeSet#to_json` and `RailsFastAttributes.dump_json`, which write
+//! the cast values of initialized attributes as a JSON object.
+
+use ffi;
+use json::Encoder;
+use protect::{hash_foreach, protect};
+use super::AttributeSet;
+use util::*;
+
+pub struct Options {
+    only: Option<Vec<ffi::ID>>,
+    except: Vec<ffi::ID>,
+    methods: Vec<(ffi::ID, ffi::VALUE, ffi::VALUE)>,
+}
+
+impl Options {
+    /// Reads `only:`, `except:` and `methods_hash:` from an options Hash.
+    /// Anything else, such as `nil` or the `JSON::State` given by
+    /// `JSON.generate`, means no options. `methods_hash` maps names to already
+    /// computed values, which take the place of an attribute with the same
+    /// name like in `serializable_hash`.
+    pub unsafe fn from_ruby(options: ffi::VALUE) -> Self {
+        let mut result = Options {
+            only: None,
+            except: Vec::new(),
+            methods: Vec::new(),
+        };
+        if !ffi::RB_TYPE_P(options, ffi::T_HASH) {
+            return result;
+        }
+
+        let option = |name| protect(|| ffi::rb_hash_aref(options, ffi::rb_id2sym(name)));
+        let only = option(id!("only"));
+        if !ffi::RB_NIL_P(only) {
+            result.only = Some(names(only));
+        }
+        result.except = names(option(id!("except")));
+        let methods = option(id!("methods_hash"));
+        if !ffi::RB_NIL_P(methods) {
+            check_hash(methods);
+            hash_foreach(methods, |key, value| {
+                result.methods.push((string_or_symbol_to_id(key), key, value));
+            });
+        }
+        result
+    }
+
+    fn includes(&self, key: ffi::ID) -> bool {
+        self.only.as_ref().map(|only| only.contains(&key)).unwrap_or(true)
+            && !self.except.contains(&key)
+    }
+}
+
+pub fn write(encoder: &mut Encoder, set: &AttributeSet, options: &Options) {
+    let _guard = set.borrow.read();
+    let mut written_methods = vec![false; options.methods.len()];
+    let mut first = true;
+    let mut separate = |encoder: &mut Encoder| {
+        if !first {
+            encoder.write_raw(b",");
+        }
+        first = false;
+    };
+
+    encoder.write_raw(b"{");
+    for (&key, attr) in &set.attributes {
+        if !attr.is_initialized() || !options.includes(key) {
+            continue;
+        }
+        separate(encoder);
+        encoder.write_key(attr.name());
+        match options.methods.iter().position(|&(id, _, _)| id == key) {
+            Some(i) => {
+                written_methods[i] = true;
+                encoder.write_value(options.methods[i].2);
+            }
+            None => encoder.write_value(attr.value()),
+        }
+    }
+    for (&(_, key, value), &written) in options.methods.iter().zip(&written_methods) {
+        if !written {
+            separate(encoder);
+            encoder.write_key(key);
+            encoder.write_value(value);
+        }
+    }
+    encoder.write_raw(b"}");
+}
+
+unsafe fn names(value: ffi::VALUE) -> Vec<ffi::ID> {
+    if ffi::RB_NIL_P(value) {
+        Vec::new()
+    } else if ffi::RB_TYPE_P(value, ffi::T_ARRAY) {
+        (0..ffi::RARRAY_LEN(value))
+            .map(|i| string_or_symbol_to_id(ffi::rb_ary_entry(value, i)))
+            .collect()
+    } else {
+        vec![string_or_symbol_to_id(value)]
+    }
+}
@@ -0,0 +1,261 @@
+//! The format written by `AttributeSet#_dump_data`.
+//!
+//! Version 1 was an `Array` of `Attribute` objects, each of which dumped its
+//! own type. Since every row of a query shares the same types, the same type
+//! objects were written over and over again.
+//!
+//! Version 2 is an `Array` of the form:
+//!
+//! ```text
+//! [HEADER, 2, types, attributes]
+//! ```
+//!
+//! where `types` holds each distinct type once, and `attributes` is a flat
+//! list of every attribute in order. An attribute is written as its name, the
+//! index of its type, a tag, and then depending on the tag its raw value and
+//! original attribute (which is written the same way).
+//!
+//! Loading accepts every version up to `VERSION`.
+
+use std::cell::Cell;
+use std::slice;
+
+use attribute::{Attribute, MaybeProc, Source};
+use ffi;
+use indexmap::IndexMap;
+use load::*;
+
+const HEADER: &str = "rails_fast_attributes";
+const VERSION: i32 = 2;
+
+const UNINITIALIZED: i32 = 0;
+const FROM_USER: i32 = 1;
+const FROM_DATABASE: i32 = 2;
+const PRE_CAST: i32 = 3;
+const USER_PROVIDED_DEFAULT: i32 = 4;
+const USER_PROVIDED_DEFAULT_WITHOUT_ORIGINAL: i32 = 5;
+
+/// How long a chain of original attributes may be. Marking, cloning and
+/// dropping an attribute all recurse through the chain, so corrupt data must
+/// not be able to build an arbitrarily long one.
+const MAX_DEPTH: usize = 1000;
+
+pub fn dump<'a, I>(attributes: I) -> ffi::VALUE
+where
+    I: IntoIterator<Item = &'a Attribute>,
+{
+    let mut encoder = Encoder {
+        types: Vec::new(),
+        types_ary: unsafe { ffi::rb_ary_new() },
+        attributes: unsafe { ffi::rb_ary_new() },
+    };
+    for attr in attributes {
+        encoder.push_attribute(attr);
+    }
+
+    unsafe {
+        let result = ffi::rb_ary_new_capa(4);
+        ffi::rb_ary_push(result, rstr!(HEADER));
+        ffi::rb_ary_push(result, ffi::I322NUM(VERSION));
+        ffi::rb_ary_push(result, encoder.types_ary);
+        ffi::rb_ary_push(result, encoder.attributes);
+        result
+    }
+}
+
+pub unsafe fn load(data: ffi::VALUE) -> IndexMap<ffi::ID, Attribute> {
+    let len = expect_array(data, 0, usize::max_value(), "attribute set data");
+    if len == 0 || !is_header(ffi::rb_ary_entry(data, 0)) {
+        return load_version_1(data, len);
+    }
+
+    expect_array(data, 4, 4, "attribute set data");
+    match expect_integer(ffi::rb_ary_entry(data, 1), 1, i32::max_value(), "format version") {
+        2 => load_version_2(ffi::rb_ary_entry(data, 2), ffi::rb_ary_entry(data, 3)),
+        version => load_error(format!(
+            "unsupported attribute set format version {} (expected at most {})",
+            version, VERSION
+        )),
+    }
+}
+
+struct Encoder {
+    // The Ruby array is what keeps the types alive, `types` is only there
+    // so we don't have to read them back out of it to find an index.
+    types: Vec<ffi::VALUE>,
+    types_ary: ffi::VALUE,
+    attributes: ffi::VALUE,
+}
+
+impl Encoder {
+    fn type_index(&mut self, ty: ffi::VALUE) -> i32 {
+        let index = self.types.iter().position(|&t| t == ty).unwrap_or_else(|| {
+            self.types.push(ty);
+            unsafe { ffi::rb_ary_push(self.types_ary, ty) };
+            self.types.len() - 1
+        });
+        index as i32
+    }
+
+    fn push(&mut self, value: ffi::VALUE) {
+        unsafe { ffi::rb_ary_push(self.attributes, value) };
+    }
+
+    fn push_int(&mut self, value: i32) {
+        self.push(unsafe { ffi::I322NUM(value) });
+    }
+
+    fn push_attribute(&mut self, attr: &Attribute) {
+        use self::Source::*;
+
+        match *attr {
+            Attribute::Uninitialized { name, ty } => {
+                let ty = self.type_index(ty);
+                self.push(name);
+                self.push_int(ty);
+                self.push_int(UNINITIALIZED);
+            }
+            Attribute::Populated {
+                name,
+                ty,
+                ref raw_value,
+                ref source,
+                ..
+            } => {
+                let ty = self.type_index(ty);
+                let tag = match *source {
+                    FromUser(_) => FROM_USER,
+                    FromDatabase => FROM_DATABASE,
+                    PreCast => PRE_CAST,
+                    UserProvidedDefault(Some(_)) => USER_PROVIDED_DEFAULT,
+                    UserProvidedDefault(None) => USER_PROVIDED_DEFAULT_WITHOUT_ORIGINAL,
+                };
+                self.push(name);
+                self.push_int(ty);
+                self.push_int(tag);
+                self.push(raw_value.value());
+                match *source {
+                    FromUser(ref orig) | UserProvidedDefault(Some(ref orig)) => {
+                        self.push_attribute(orig)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+struct Decoder {
+    types: ffi::VALUE,
+    attributes: ffi::VALUE,
+    pos: usize,
+}
+
+impl Decoder {
+    unsafe fn next(&mut self) -> ffi::VALUE {
+        if self.done() {
+            load_error("attribute set data ended in the middle of an attribute");
+        }
+        self.pos += 1;
+        ffi::rb_ary_entry(self.attributes, self.pos as isize - 1)
+    }
+
+    unsafe fn done(&self) -> bool {
+        self.pos >= ffi::RARRAY_LEN(self.attributes) as usize
+    }
+
+    /// Reads an attribute and the chain of original attributes it was
+    /// assigned over. The chain is read from the outside in and then built
+    /// from the inside out, so corrupt data can't recurse arbitrarily deep.
+    unsafe fn next_attribute(&mut self) -> Attribute {
+        let mut outer = Vec::new();
+        let innermost = loop {
+            let name = self.next();
+            let max_index = ffi::RARRAY_LEN(self.types) as i32 - 1;
+            let index = expect_integer(self.next(), 0, max_index, "type index");
+            let ty = ffi::rb_ary_entry(self.types, index as isize);
+            let tag = expect_integer(self.next(), 0, 5, "attribute tag");
+            if tag == UNINITIALIZED {
+                break Attribute::Uninitialized { name, ty };
+            }
+
+            let raw_value = self.next();
+            let source = match tag {
+                FROM_USER | USER_PROVIDED_DEFAULT => {
+                    outer.push((name, ty, tag, raw_value));
+                    if outer.len() > MAX_DEPTH {
+                        load_error(format!(
+                            "attribute has more than {} original attributes",
+                            MAX_DEPTH
+                        ));
+                    }
+                    continue;
+                }
+                FROM_DATABASE => Source::FromDatabase,
+                PRE_CAST => Source::PreCast,
+                _ => Source::UserProvidedDefault(None),
+            };
+            break populated(name, ty, raw_value, source);
+        };
+
+        outer
+            .into_iter()
+            .rev()
+            .fold(innermost, |original, (name, ty, tag, raw_value)| {
+                let original = Box::new(original);
+                let source = if tag == FROM_USER {
+                    Source::FromUser(original)
+                } else {
+                    Source::UserProvidedDefault(Some(original))
+                };
+                populated(name, ty, raw_value, source)
+            })
+    }
+}
+
+fn populated(name: ffi::VALUE, ty: ffi::VALUE, raw_value: ffi::VALUE, source: Source) -> Attribute {
+    Attribute::Populated {
+        name,
+        ty,
+        raw_value: MaybeProc::NotProc(raw_value),
+        source,
+        value: Cell::new(None),
+    }
+}
+
+unsafe fn is_header(value: ffi::VALUE) -> bool {
+    if !ffi::RB_TYPE_P(value, ffi::T_STRING) {
+        return false;
+    }
+    let ptr = ffi::RSTRING_PTR(value) as *const u8;
+    let len = ffi::RSTRING_LEN(value) as usize;
+    slice::from_raw_parts(ptr, len) == HEADER.as_bytes()
+}
+
+unsafe fn load_version_1(data: ffi::VALUE, len: usize) -> IndexMap<ffi::ID, Attribute> {
+    let mut attributes = IndexMap::with_capacity(len);
+    for i in 0..len {
+        let attr = expect_attribute(ffi::rb_ary_entry(data, i as _), "attribute");
+        let key = expect_key(attr.name(), "attribute name");
+        attributes.insert(key, attr.clone());
+    }
+    attributes
+}
+
+unsafe fn load_version_2(types: ffi::VALUE, data: ffi::VALUE) -> IndexMap<ffi::ID, Attribute> {
+    expect_array(types, 0, usize::max_value(), "attribute types");
+    expect_array(data, 0, usize::max_value(), "attributes");
+
+    let mut decoder = Decoder {
+        types,
+        attributes: data,
+        pos: 0,
+    };
+    let mut attributes = IndexMap::new();
+    while !decoder.done() {
+        let attr = decoder.next_attribute();
+        let key = expect_key(attr.name(), "attribute name");
+        attributes.insert(key, attr);
+    }
+    attributes
+}
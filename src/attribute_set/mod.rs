@@ -1,23 +1,40 @@
 use indexmap::IndexMap;
-use std::ffi::CString;
 
 use attribute::Attribute;
 use ffi;
-use util::to_ruby_array;
-
+use protect::protect;
+use util::{raise, to_ruby_array, to_rust_string};
+use self::borrow::BorrowFlag;
+
+mod arrow;
+mod borrow;
+mod csv;
+mod json;
+pub mod marshal;
+mod msgpack;
 mod ruby_glue;
+mod yaml;
 
 #[derive(Default, Clone, PartialEq, Eq)]
 pub struct AttributeSet {
     attributes: IndexMap<ffi::ID, Attribute>,
+    borrow: BorrowFlag,
 }
 
 impl AttributeSet {
     pub fn new(attributes: IndexMap<ffi::ID, Attribute>) -> Self {
-        Self { attributes }
+        Self {
+            attributes,
+            borrow: BorrowFlag::default(),
+        }
+    }
+
+    pub fn attributes(&self) -> &IndexMap<ffi::ID, Attribute> {
+        &self.attributes
     }
 
     fn each_value<'a, F: Fn(&'a Attribute)>(&'a self, f: F) {
+        let _guard = self.borrow.read();
         for attr in self.attributes.values() {
             f(attr)
         }
@@ -28,26 +45,35 @@ impl AttributeSet {
     }
 
     fn set(&mut self, key: ffi::ID, attr: Attribute) {
+        let _guard = self.borrow.write();
         self.attributes.insert(key, attr);
     }
 
+    fn replace(&mut self, attributes: IndexMap<ffi::ID, Attribute>) {
+        let _guard = self.borrow.write();
+        self.attributes = attributes;
+    }
+
     fn values_before_type_cast(&self) -> ffi::VALUE {
+        let _guard = self.borrow.read();
         let result = unsafe { ffi::rb_hash_new() };
         for attr in self.attributes.values() {
             let name = attr.name();
             let value = attr.value_before_type_cast();
-            unsafe { ffi::rb_hash_aset(result, name, value) };
+            protect(|| unsafe { ffi::rb_hash_aset(result, name, value) });
         }
         result
     }
 
     fn to_hash(&self) -> ffi::VALUE {
+        let _guard = self.borrow.read();
         let result = unsafe { ffi::rb_hash_new() };
         let attributes = self.attributes
             .values()
             .filter(|attr| attr.is_initialized());
         for attr in attributes {
-            unsafe { ffi::rb_hash_aset(result, attr.name(), attr.value()) };
+            let (name, value) = (attr.name(), attr.value());
+            protect(|| unsafe { ffi::rb_hash_aset(result, name, value) });
         }
         result
     }
@@ -68,10 +94,12 @@ impl AttributeSet {
     }
 
     fn fetch_value(&self, key: ffi::ID) -> Option<ffi::VALUE> {
+        let _guard = self.borrow.read();
         self.get(key).map(Attribute::value)
     }
 
     fn write_from_database(&mut self, key: ffi::ID, value: ffi::VALUE) {
+        let _guard = self.borrow.write();
         let new_attr = self.get(key).map(|a| a.with_value_from_database(value));
         if let Some(attr) = new_attr {
             self.attributes.insert(key, attr);
@@ -81,25 +109,33 @@ impl AttributeSet {
     }
 
     fn write_from_user(&mut self, key: ffi::ID, value: ffi::VALUE) {
-        use indexmap::map::Entry::*;
         use std::mem::swap;
 
-        match self.attributes.entry(key) {
-            Vacant(_) => missing_attribute(key),
-            Occupied(mut entry) => {
-                // `with_value_from_user` requires ownership, so we need to
-                // temporarily pull the value out of the map. We don't want
-                // to use `remove`, because that would mess with the order.
-                let nil = unsafe { ffi::Qnil };
-                let mut tmp = Attribute::uninitialized(nil, nil);
-                swap(entry.get_mut(), &mut tmp);
-                tmp = tmp.with_value_from_user(value);
-                swap(entry.get_mut(), &mut tmp);
-            }
+        let _guard = self.borrow.write();
+
+        // Validate before touching the map, so that if the type raises, the
+        // existing attribute is left exactly as it was.
+        match self.get(key) {
+            Some(attr) => attr.assert_valid_value(value),
+            None => missing_attribute(key),
+        }
+
+        if let Some(attr) = self.attributes.get_mut(&key) {
+            // `with_valid_value_from_user` requires ownership, so we need
+            // to temporarily pull the value out of the map. We don't want
+            // to use `remove`, because that would mess with the order.
+            // Nothing between the two swaps calls into Ruby, so the
+            // placeholder can never be observed.
+            let nil = unsafe { ffi::Qnil };
+            let mut tmp = Attribute::uninitialized(nil, nil);
+            swap(attr, &mut tmp);
+            tmp = tmp.with_valid_value_from_user(value);
+            swap(attr, &mut tmp);
         }
     }
 
     fn write_cast_value(&mut self, key: ffi::ID, value: ffi::VALUE) {
+        let _guard = self.borrow.write();
         let new_attr = self.get(key).map(|a| a.with_cast_value(value));
         if let Some(attr) = new_attr {
             self.attributes.insert(key, attr);
@@ -109,6 +145,7 @@ impl AttributeSet {
     }
 
     fn deep_dup(&self) -> Self {
+        let _guard = self.borrow.read();
         let attributes = self.attributes
             .iter()
             .map(|(&k, v)| (k, v.deep_dup()))
@@ -131,29 +168,25 @@ impl AttributeSet {
     }
 
     fn map<'a, F: Fn(&'a Attribute) -> Attribute>(&'a self, f: F) -> Self {
+        let _guard = self.borrow.read();
         let attributes = self.attributes.iter().map(|(&k, v)| (k, f(v))).collect();
         Self::new(attributes)
     }
 }
 
 pub unsafe fn init() {
+    self::msgpack::init();
     self::ruby_glue::init();
 }
 
 fn missing_attribute(key: ffi::ID) -> ! {
-    use std::{slice, str};
-
     unsafe {
-        let active_model = ffi::rb_const_get(ffi::rb_cObject, id!("ActiveModel"));
-        let missing_attribute = ffi::rb_const_get(active_model, id!("MissingAttributeError"));
-        let attr_name = ffi::rb_id2str(key);
-        let attr_name_bytes = slice::from_raw_parts(
-            ffi::RSTRING_PTR(attr_name) as *const u8,
-            ffi::RSTRING_LEN(attr_name) as usize,
-        );
-        let attr_name = str::from_utf8_unchecked(attr_name_bytes);
+        let missing_attribute = protect(|| {
+            let active_model = ffi::rb_const_get(ffi::rb_cObject, id!("ActiveModel"));
+            ffi::rb_const_get(active_model, id!("MissingAttributeError"))
+        });
+        let attr_name = to_rust_string(ffi::rb_id2str(key));
         let message = format!("can't write unknown attribute `{}`", attr_name);
-        let c_message = CString::new(message).unwrap();
-        ffi::rb_raise(missing_attribute, c_message.as_ptr());
+        raise(missing_attribute, message);
     }
 }
@@ -0,0 +1,299 @@
+//! `AttributeSet#to_msgpack` and `AttributeSet.from_msgpack`.
+//!
+//! Only the raw values from the database are written, followed by a
+//! fingerprint of the column names and type classes of the `Builder` the set
+//! was built with. The types themselves come from the `Builder` given when
+//! loading, and if its fingerprint doesn't match (e.g. because a migration
+//! changed a column) we raise `SchemaMismatchError` rather than casting
+//! values with the wrong types.
+//!
+//! The format is:
+//!
+//! ```text
+//! [VERSION, fingerprint, [raw_value, ...], [[name, symbol?, raw_value], ...]]
+//! ```
+//!
+//! where the first list has one value per column of the builder, in order,
+//! and the second has the columns the set has beyond those, such as ones
+//! from `additional_types` or which the builder had no type for, along with
+//! whether their name is a `Symbol` or a `String`. When loading, those are
+//! typed the way `Builder#build_from_database` would type them.
+//! Uninitialized attributes are written as an extension value. Integers are
+//! limited to the 64 bits MessagePack has room for.
+
+use indexmap::IndexMap;
+
+use attribute::{Attribute, MaybeProc, Source};
+use builder::Builder;
+use ffi;
+use into_ruby::IntoRuby;
+use load::*;
+use msgpack::{Fingerprint, Reader, Value, Writer};
+use protect::protect;
+use super::AttributeSet;
+use util::*;
+
+extern "C" {
+    static rb_eRangeError: ffi::VALUE;
+}
+
+const VERSION: i64 = 1;
+const UNINITIALIZED: i8 = 0;
+
+pub fn dump(set: &AttributeSet, builder: &Builder) -> ffi::VALUE {
+    let _guard = set.borrow.read();
+    let columns = builder.attributes();
+    let mut writer = Writer::new();
+    writer.write_array_len(4);
+    writer.write_int(VERSION);
+    writer.write_uint(fingerprint(columns));
+    writer.write_array_len(columns.len());
+    for (key, column) in columns {
+        match set.attributes.get(key) {
+            Some(attr) => write_attribute(&mut writer, attr),
+            None => unsafe {
+                raise(
+                    ffi::rb_eArgError,
+                    format!(
+                        "can't write an attribute set without the builder's column `{}`",
+                        to_rust_string(column.name())
+                    ),
+                )
+            },
+        }
+    }
+
+    let extra = set.attributes
+        .iter()
+        .filter(|&(key, _)| !columns.contains_key(key))
+        .map(|(_, attr)| attr)
+        .collect::<Vec<_>>();
+    writer.write_array_len(extra.len());
+    for attr in extra {
+        writer.write_array_len(3);
+        unsafe {
+            let name = protect(|| ffi::rb_funcall(attr.name(), id!("to_s"), 0));
+            writer.write_str(string_bytes(name));
+            writer.write_bool(ffi::RB_TYPE_P(attr.name(), ffi::T_SYMBOL));
+        }
+        write_attribute(&mut writer, attr);
+    }
+
+    let bytes = writer.into_bytes();
+    unsafe { ffi::rb_str_new(bytes.as_ptr() as *const _, bytes.len() as _) }
+}
+
+pub unsafe fn load(
+    builder: &Builder,
+    bytes: ffi::VALUE,
+    additional_types: Option<ffi::VALUE>,
+) -> ffi::VALUE {
+    check_type(bytes, ffi::T_STRING, ffi::rb_cString);
+    let mut reader = Reader::new(string_bytes(bytes));
+    let columns = builder.attributes();
+
+    if read(&mut reader) != Value::Array(4) {
+        load_error("expected MessagePack data to be an Array of 4 elements");
+    }
+    match read(&mut reader) {
+        Value::Int(VERSION) | Value::UInt(1) => {}
+        _ => load_error("unsupported MessagePack attribute set version"),
+    }
+    let expected = fingerprint(columns);
+    match read(&mut reader) {
+        Value::UInt(actual) if actual == expected => {}
+        Value::Int(actual) if actual as u64 == expected => {}
+        _ => raise(
+            schema_mismatch_error(),
+            "the columns or types of this Builder don't match the ones this attribute set \
+             was written with",
+        ),
+    }
+    if read(&mut reader) != Value::Array(columns.len()) {
+        load_error("expected one value per column");
+    }
+
+    // The values we create are only referenced from Rust until we're done,
+    // so keep them somewhere the GC can see them.
+    let keep_alive = ffi::rb_ary_new_capa(columns.len() as isize);
+    let mut attributes = builder.attributes_with(additional_types);
+    for &key in columns.keys() {
+        let attr = read_attribute(&mut reader, &attributes[&key], keep_alive);
+        attributes.insert(key, attr);
+    }
+
+    let len = match read(&mut reader) {
+        Value::Array(len) => len,
+        _ => load_error("expected an Array of extra columns"),
+    };
+    for _ in 0..len {
+        let name = match (read(&mut reader), read(&mut reader), read(&mut reader)) {
+            (Value::Array(3), Value::Str(name), Value::Bool(is_symbol)) => {
+                let name = ffi::rb_utf8_str_new(name.as_ptr() as *const _, name.len() as _);
+                if is_symbol {
+                    ffi::rb_id2sym(ffi::rb_intern_str(name))
+                } else {
+                    name
+                }
+            }
+            _ => load_error(
+                "expected extra columns to be a name, whether it's a symbol, and a raw value",
+            ),
+        };
+        ffi::rb_ary_push(keep_alive, name);
+        let key = string_or_symbol_to_id(name);
+        if columns.contains_key(&key) {
+            load_error(format!(
+                "extra column `{}` is a column of the builder",
+                to_rust_string(name)
+            ));
+        }
+        if !attributes.contains_key(&key) {
+            let ty = builder.type_for_unknown_column(name);
+            attributes.insert(key, Attribute::uninitialized(name, ty));
+        }
+        let attr = read_attribute(&mut reader, &attributes[&key], keep_alive);
+        attributes.insert(key, attr);
+    }
+    if !reader.is_done() {
+        load_error("unexpected data after the end of the attribute set");
+    }
+    let result = AttributeSet::new(attributes).into_ruby();
+    gc_guard(&keep_alive);
+    result
+}
+
+fn write_attribute(writer: &mut Writer, attr: &Attribute) {
+    match *attr {
+        Attribute::Uninitialized { .. } => writer.write_ext(UNINITIALIZED, 0),
+        Attribute::Populated {
+            raw_value: ref raw_value @ MaybeProc::NotProc(_),
+            source: Source::FromDatabase,
+            ..
+        }
+        | Attribute::Populated {
+            raw_value: ref raw_value @ MaybeProc::Bytes { .. },
+            source: Source::FromDatabase,
+            ..
+        } => unsafe { write_value(writer, raw_value.value()) },
+        Attribute::Populated { name, .. } => unsafe {
+            raise(
+                ffi::rb_eArgError,
+                format!(
+                    "can't write `{}` to MessagePack, only attributes loaded from the \
+                     database are supported",
+                    to_rust_string(name)
+                ),
+            )
+        },
+    }
+}
+
+/// Reads the raw value of `column`, which has its name and type.
+unsafe fn read_attribute(
+    reader: &mut Reader,
+    column: &Attribute,
+    keep_alive: ffi::VALUE,
+) -> Attribute {
+    match read(reader) {
+        Value::Ext(UNINITIALIZED, _) => Attribute::uninitialized(column.name(), column.ty()),
+        value => {
+            let value = to_ruby_value(value);
+            ffi::rb_ary_push(keep_alive, value);
+            Attribute::from_database(column.name(), value, column.ty())
+        }
+    }
+}
+
+fn fingerprint(attributes: &IndexMap<ffi::ID, Attribute>) -> u64 {
+    let mut fingerprint = Fingerprint::new();
+    for (&key, attr) in attributes {
+        unsafe {
+            let name = ffi::rb_id2str(key);
+            fingerprint.write(string_bytes(name));
+            let class_name = ffi::rb_obj_classname(attr.ty());
+            fingerprint.write(::std::ffi::CStr::from_ptr(class_name).to_bytes());
+        }
+    }
+    fingerprint.finish()
+}
+
+unsafe fn write_value(writer: &mut Writer, value: ffi::VALUE) {
+    if ffi::RB_NIL_P(value) {
+        writer.write_nil();
+    } else if value == ffi::Qtrue {
+        writer.write_bool(true);
+    } else if value == ffi::Qfalse {
+        writer.write_bool(false);
+    } else if ffi::RB_TYPE_P(value, ffi::T_FIXNUM) {
+        writer.write_int(ffi::NUM2I64(value));
+    } else if ffi::RB_TYPE_P(value, ffi::T_BIGNUM) {
+        write_bignum(writer, value);
+    } else if ffi::RB_TYPE_P(value, ffi::T_FLOAT) {
+        writer.write_float(ffi::NUM2F64(value));
+    } else if ffi::RB_TYPE_P(value, ffi::T_STRING) {
+        if is_utf8_compatible(value) {
+            writer.write_str(string_bytes(value));
+        } else {
+            writer.write_bin(string_bytes(value));
+        }
+    } else {
+        let class_name = to_rust_string(protect(|| {
+            ffi::rb_funcall(ffi::rb_obj_class(value), id!("to_s"), 0)
+        }));
+        raise(
+            ffi::rb_eTypeError,
+            format!("can't write a raw value of class {} to MessagePack", class_name),
+        );
+    }
+}
+
+/// Bignums up to 64 bits fit in MessagePack's integers, with ones of exactly
+/// 64 bits written unsigned.
+unsafe fn write_bignum(writer: &mut Writer, value: ffi::VALUE) {
+    let bit_length = ffi::NUM2I64(protect(|| ffi::rb_funcall(value, id!("bit_length"), 0)));
+    let is_negative = ffi::RTEST(protect(|| ffi::rb_funcall(value, id!("negative?"), 0)));
+    if bit_length < 64 {
+        writer.write_int(protect(|| ffi::NUM2I64(value)));
+    } else if bit_length == 64 && !is_negative {
+        writer.write_uint(protect(|| ffi::NUM2U64(value)));
+    } else {
+        let value = protect(|| ffi::rb_funcall(value, id!("to_s"), 0));
+        raise(
+            rb_eRangeError,
+            format!(
+                "can't write the raw value {} to MessagePack, which only has 64 bit integers",
+                to_rust_string(value)
+            ),
+        );
+    }
+}
+
+unsafe fn to_ruby_value(value: Value) -> ffi::VALUE {
+    match value {
+        Value::Nil => ffi::Qnil,
+        Value::Bool(value) => to_ruby_bool(value),
+        Value::Int(value) => ffi::I642NUM(value),
+        Value::UInt(value) => ffi::U642NUM(value),
+        Value::Float(value) => ffi::F642NUM(value),
+        Value::Str(bytes) => ffi::rb_utf8_str_new(bytes.as_ptr() as *const _, bytes.len() as _),
+        Value::Bin(bytes) => ffi::rb_str_new(bytes.as_ptr() as *const _, bytes.len() as _),
+        Value::Array(_) | Value::Ext(..) => load_error("unexpected raw value in MessagePack data"),
+    }
+}
+
+fn read<'a>(reader: &mut Reader<'a>) -> Value<'a> {
+    reader.read().unwrap_or_else(|message| load_error(message))
+}
+
+fn schema_mismatch_error() -> ffi::VALUE {
+    unsafe { SCHEMA_MISMATCH_ERROR }.unwrap()
+}
+
+static mut SCHEMA_MISMATCH_ERROR: Option<ffi::VALUE> = None;
+
+pub unsafe fn init() {
+    let error =
+        ffi::rb_define_class_under(::module(), cstr!("SchemaMismatchError"), load_error_class());
+    SCHEMA_MISMATCH_ERROR = Some(error);
+}
@@ -1,9 +1,15 @@
 use indexmap::IndexMap;
 
-use attribute::Attribute;
+use attribute::{into_stock, Attribute};
+use builder::Builder;
 use {ffi, libc};
-use into_ruby::{Allocate, IntoRuby};
-use super::AttributeSet;
+use into_ruby::IntoRuby;
+use load::*;
+use protect::{hash_foreach, protect, ruby_boundary};
+use stock;
+use csv::Writer;
+use json::Encoder;
+use super::{arrow, csv, json, marshal, msgpack, yaml, AttributeSet};
 use util::*;
 
 impl IntoRuby for AttributeSet {
@@ -21,13 +27,15 @@ impl IntoRuby for AttributeSet {
 }
 
 static mut ATTRIBUTE_SET: Option<ffi::VALUE> = None;
+static mut STOCK_ATTRIBUTE_SET: Option<ffi::VALUE> = None;
 
 pub unsafe fn init() {
     let attribute_set =
         ffi::rb_define_class_under(::module(), cstr!("AttributeSet"), ffi::rb_cObject);
     ATTRIBUTE_SET = Some(attribute_set);
 
-    ffi::rb_define_alloc_func(attribute_set, AttributeSet::allocate);
+    // See `stock.rs`
+    stock::define_compat::<AttributeSet>(attribute_set, dump_marshal, load_marshal);
 
     ffi::rb_define_method(
         attribute_set,
@@ -92,102 +100,163 @@ pub unsafe fn init() {
     ffi::rb_define_method(attribute_set, cstr!("_dump_data"), dump_data as *const _, 0);
     ffi::rb_define_method(attribute_set, cstr!("_load_data"), load_data as *const _, 1);
     ffi::rb_define_method(attribute_set, cstr!("init_with"), init_with as *const _, 1);
+    ffi::rb_define_method(attribute_set, cstr!("to_json"), to_json as *const _, -1);
+    ffi::rb_define_module_function(::module(), cstr!("dump_json"), dump_json as *const _, -1);
+    ffi::rb_define_module_function(::module(), cstr!("write_csv"), write_csv as *const _, -1);
+    ffi::rb_define_module_function(
+        ::module(),
+        cstr!("write_arrow"),
+        write_arrow as *const _,
+        -1,
+    );
+    ffi::rb_define_method(
+        attribute_set,
+        cstr!("to_msgpack"),
+        to_msgpack as *const _,
+        1,
+    );
+    ffi::rb_define_singleton_method(
+        attribute_set,
+        cstr!("from_msgpack"),
+        from_msgpack as *const _,
+        -1,
+    );
+    ffi::rb_define_method(
+        attribute_set,
+        cstr!("encode_with"),
+        encode_with as *const _,
+        1,
+    );
     ffi::rb_define_method(attribute_set, cstr!("except"), except as *const _, -1);
+    ffi::rb_define_method(
+        attribute_set,
+        cstr!("concise_attributes"),
+        concise_attributes as *const _,
+        1,
+    );
+    ffi::rb_define_singleton_method(
+        attribute_set,
+        cstr!("from_concise_attributes"),
+        from_concise_attributes as *const _,
+        2,
+    );
+
+    // Named `ActiveModel::AttributeSet` by the Ruby side of the gem, see
+    // `stock.rs`
+    STOCK_ATTRIBUTE_SET = Some(stock::define_subclass(attribute_set));
+    ffi::rb_define_singleton_method(
+        attribute_set,
+        cstr!("stock_class"),
+        stock_class as *const _,
+        0,
+    );
+    ffi::rb_define_method(attribute_set, cstr!("to_stock"), to_stock as *const _, 0);
 }
 
 extern "C" fn initialize(this: ffi::VALUE, attrs: ffi::VALUE) -> ffi::VALUE {
-    unsafe {
-        let this = get_struct_mut::<AttributeSet>(this);
-        this.attributes.reserve(ffi::RHASH_SIZE(attrs) as usize);
-        ffi::rb_hash_foreach(
-            attrs,
-            push_attribute,
-            &mut this.attributes as *mut _ as *mut _,
-        );
-
-        extern "C" fn push_attribute(
-            key: ffi::VALUE,
-            value: ffi::VALUE,
-            hash_ptr: *mut libc::c_void,
-        ) -> ffi::st_retval {
-            let hash_ptr = hash_ptr as *mut IndexMap<ffi::ID, Attribute>;
-            let hash = unsafe { hash_ptr.as_mut().unwrap() };
-
-            let id = string_or_symbol_to_id(key);
-            let value = unsafe { get_struct::<Attribute>(value) }.clone();
-
-            hash.insert(id, value);
-
-            ffi::st_retval::ST_CONTINUE
-        }
-
+    ruby_boundary(|| unsafe {
+        initialize_from_hash(this, attrs);
         ffi::Qnil
-    }
+    })
+}
+
+unsafe fn initialize_from_hash(this: ffi::VALUE, attrs: ffi::VALUE) {
+    check_hash(attrs);
+
+    let mut attributes = IndexMap::with_capacity(ffi::RHASH_SIZE(attrs) as usize);
+    hash_foreach(attrs, |key, value| {
+        let id = string_or_symbol_to_id(key);
+        let value = get_struct::<Attribute>(value).clone();
+
+        attributes.insert(id, value);
+    });
+    get_struct_mut::<AttributeSet>(this).replace(attributes);
 }
 
 extern "C" fn fetch(this: ffi::VALUE, name: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<AttributeSet>(this) };
-    let key = string_or_symbol_to_id(name);
-    this.get(key)
-        .map(IntoRuby::as_ruby)
-        .unwrap_or_else(|| unsafe { ffi::rb_yield(ffi::Qnil) })
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<AttributeSet>(this) };
+        let key = string_or_symbol_to_id(name);
+        this.get(key)
+            .map(IntoRuby::as_ruby)
+            .unwrap_or_else(|| unsafe { protect(|| ffi::rb_yield(ffi::Qnil)) })
+    })
 }
 
 extern "C" fn each_value(this: ffi::VALUE) -> ffi::VALUE {
-    unsafe {
+    ruby_boundary(|| unsafe {
         if !ffi::rb_block_given_p() {
-            return ffi::rb_funcall(this, id!("to_enum"), 1, ffi::rb_id2sym(id!("each_value")));
+            let method = ffi::rb_id2sym(id!("each_value"));
+            return protect(|| ffi::rb_funcall(this, id!("to_enum"), 1, method));
         }
 
         let this = get_struct::<AttributeSet>(this);
         this.each_value(|value| {
-            ffi::rb_yield(value.as_ruby());
+            let value = value.as_ruby();
+            protect(|| ffi::rb_yield(value));
         });
         ffi::Qnil
-    }
+    })
 }
 
 extern "C" fn get(this: ffi::VALUE, name: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<AttributeSet>(this) };
-    let key = string_or_symbol_to_id(name);
-    this.get(key)
-        .map(IntoRuby::as_ruby)
-        .unwrap_or_else(|| unsafe { ffi::rb_funcall(Attribute::class(), id!("null"), 1, name) })
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<AttributeSet>(this) };
+        let key = string_or_symbol_to_id(name);
+        this.get(key)
+            .map(IntoRuby::as_ruby)
+            .unwrap_or_else(|| unsafe {
+                let class = Attribute::class();
+                protect(|| ffi::rb_funcall(class, id!("null"), 1, name))
+            })
+    })
 }
 
 extern "C" fn set(this: ffi::VALUE, key: ffi::VALUE, value: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct_mut::<AttributeSet>(this) };
-    let attr = unsafe { get_struct::<Attribute>(value) };
-    let key = string_or_symbol_to_id(key);
-    this.set(key, attr.clone());
-    unsafe { ffi::Qnil }
+    ruby_boundary(|| {
+        let this = unsafe { get_struct_mut::<AttributeSet>(this) };
+        let attr = unsafe { get_struct::<Attribute>(value) };
+        let key = string_or_symbol_to_id(key);
+        this.set(key, attr.clone());
+        unsafe { ffi::Qnil }
+    })
 }
 
 extern "C" fn values_before_type_cast(this: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<AttributeSet>(this) };
-    this.values_before_type_cast()
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<AttributeSet>(this) };
+        this.values_before_type_cast()
+    })
 }
 
 extern "C" fn to_hash(this: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<AttributeSet>(this) };
-    this.to_hash()
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<AttributeSet>(this) };
+        this.to_hash()
+    })
 }
 
 extern "C" fn key_eh(this: ffi::VALUE, key: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<AttributeSet>(this) };
-    let key = string_or_symbol_to_id(key);
-    to_ruby_bool(this.has_key(key))
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<AttributeSet>(this) };
+        let key = string_or_symbol_to_id(key);
+        to_ruby_bool(this.has_key(key))
+    })
 }
 
 extern "C" fn keys(this: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<AttributeSet>(this) };
-    this.keys()
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<AttributeSet>(this) };
+        this.keys()
+    })
 }
 
 extern "C" fn fetch_value(this: ffi::VALUE, key: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<AttributeSet>(this) };
-    let key = string_or_symbol_to_id(key);
-    this.fetch_value(key).unwrap_or(unsafe { ffi::Qnil })
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<AttributeSet>(this) };
+        let key = string_or_symbol_to_id(key);
+        this.fetch_value(key).unwrap_or(unsafe { ffi::Qnil })
+    })
 }
 
 extern "C" fn write_from_database(
@@ -195,124 +264,439 @@ extern "C" fn write_from_database(
     key: ffi::VALUE,
     value: ffi::VALUE,
 ) -> ffi::VALUE {
-    let this = unsafe { get_struct_mut::<AttributeSet>(this) };
-    let key = string_or_symbol_to_id(key);
-    this.write_from_database(key, value);
-    unsafe { ffi::Qnil }
+    ruby_boundary(|| {
+        let this = unsafe { get_struct_mut::<AttributeSet>(this) };
+        let key = string_or_symbol_to_id(key);
+        this.write_from_database(key, value);
+        unsafe { ffi::Qnil }
+    })
 }
 
 extern "C" fn write_from_user(this: ffi::VALUE, key: ffi::VALUE, value: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct_mut::<AttributeSet>(this) };
-    let key = string_or_symbol_to_id(key);
-    this.write_from_user(key, value);
-    unsafe { ffi::Qnil }
+    ruby_boundary(|| {
+        let this = unsafe { get_struct_mut::<AttributeSet>(this) };
+        let key = string_or_symbol_to_id(key);
+        this.write_from_user(key, value);
+        unsafe { ffi::Qnil }
+    })
 }
 
 extern "C" fn write_cast_value(this: ffi::VALUE, key: ffi::VALUE, value: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct_mut::<AttributeSet>(this) };
-    let key = string_or_symbol_to_id(key);
-    this.write_cast_value(key, value);
-    unsafe { ffi::Qnil }
+    ruby_boundary(|| {
+        let this = unsafe { get_struct_mut::<AttributeSet>(this) };
+        let key = string_or_symbol_to_id(key);
+        this.write_cast_value(key, value);
+        unsafe { ffi::Qnil }
+    })
 }
 
 extern "C" fn deep_dup(this_ptr: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<AttributeSet>(this_ptr) };
-    this.deep_dup().into_ruby()
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<AttributeSet>(this_ptr) };
+        this.deep_dup().into_ruby()
+    })
 }
 
 extern "C" fn reset(this: ffi::VALUE, key: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct_mut::<AttributeSet>(this) };
-    if unsafe { !ffi::RB_NIL_P(key) } {
-        let key = string_or_symbol_to_id(key);
-        this.reset(key);
-    }
-    unsafe { ffi::Qnil }
+    ruby_boundary(|| {
+        let this = unsafe { get_struct_mut::<AttributeSet>(this) };
+        if unsafe { !ffi::RB_NIL_P(key) } {
+            let key = string_or_symbol_to_id(key);
+            this.reset(key);
+        }
+        unsafe { ffi::Qnil }
+    })
 }
 
 extern "C" fn initialize_copy(this_ptr: ffi::VALUE, other: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct_mut::<AttributeSet>(this_ptr) };
-    let other = unsafe { get_struct::<AttributeSet>(other) };
-    this.clone_from(other);
-    this_ptr
+    ruby_boundary(|| {
+        let other = unsafe { get_struct::<AttributeSet>(other) }.attributes.clone();
+        let this = unsafe { get_struct_mut::<AttributeSet>(this_ptr) };
+        this.replace(other);
+        this_ptr
+    })
 }
 
 extern "C" fn accessed(this: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<AttributeSet>(this) };
-    this.accessed()
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<AttributeSet>(this) };
+        this.accessed()
+    })
 }
 
 extern "C" fn map(this: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<AttributeSet>(this) };
-    this.map(|attr| unsafe {
-        let new_attr = ffi::rb_yield(attr.as_ruby());
-        get_struct::<Attribute>(new_attr).clone()
-    }).into_ruby()
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<AttributeSet>(this) };
+        this.map(|attr| unsafe {
+            let attr = attr.as_ruby();
+            let new_attr = protect(|| ffi::rb_yield(attr));
+            get_struct::<Attribute>(new_attr).clone()
+        }).into_ruby()
+    })
 }
 
 extern "C" fn equals(this: ffi::VALUE, other: ffi::VALUE) -> ffi::VALUE {
-    unsafe {
+    ruby_boundary(|| unsafe {
         if !ffi::RB_TYPE_P(other, ffi::T_DATA) {
             return ffi::Qfalse;
         }
-        if ffi::rb_obj_class(other) != AttributeSet::class() {
+        if !is_kind_of(other, AttributeSet::class()) {
             return ffi::Qfalse;
         }
 
         let this = get_struct::<AttributeSet>(this);
         let other = get_struct::<AttributeSet>(other);
+        let _guard = this.borrow.read();
+        let _other_guard = other.borrow.read();
         to_ruby_bool(this == other)
-    }
+    })
 }
 
 extern "C" fn dump_data(this: ffi::VALUE) -> ffi::VALUE {
-    let this = unsafe { get_struct::<AttributeSet>(this) };
-    to_ruby_array(
-        this.attributes.len(),
-        this.attributes.values().map(Attribute::as_ruby),
-    )
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<AttributeSet>(this) };
+        let _guard = this.borrow.read();
+        marshal::dump(this.attributes.values())
+    })
 }
 
 extern "C" fn load_data(this: ffi::VALUE, data: ffi::VALUE) -> ffi::VALUE {
-    use std::slice;
-    unsafe {
-        let this = get_struct_mut::<AttributeSet>(this);
-        let attrs =
-            slice::from_raw_parts(ffi::RARRAY_CONST_PTR(data), ffi::RARRAY_LEN(data) as usize);
-        this.attributes = attrs
-            .iter()
-            .map(|value| {
-                let attr = get_struct::<Attribute>(*value);
-                let key = string_or_symbol_to_id(attr.name());
-                (key, attr.clone())
-            })
-            .collect();
+    ruby_boundary(|| unsafe {
+        let attributes = marshal::load(data);
+        get_struct_mut::<AttributeSet>(this).replace(attributes);
         ffi::Qnil
-    }
+    })
 }
 
 extern "C" fn init_with(this: ffi::VALUE, coder: ffi::VALUE) -> ffi::VALUE {
-    unsafe {
-        let attributes = ffi::rb_funcall(coder, id!("[]"), 1, rstr!("attributes"));
-        let hash = ffi::rb_const_get(ffi::rb_cObject, id!("Hash"));
-        let attributes = if ffi::RTEST(ffi::rb_funcall(coder, id!("is_a?"), 1, hash)) {
-            attributes
+    ruby_boundary(|| unsafe {
+        let types = protect(|| ffi::rb_funcall(coder, id!("[]"), 1, rstr!("types")));
+        let attributes = if ffi::RB_NIL_P(types) {
+            let attributes =
+                protect(|| ffi::rb_funcall(coder, id!("[]"), 1, rstr!("attributes")));
+            yaml::decode(attributes)
         } else {
-            ffi::rb_funcall(attributes, id!("materialize"), 0)
+            yaml::decode_lazy_attribute_set(coder)
         };
-        initialize(this, attributes);
+        get_struct_mut::<AttributeSet>(this).replace(attributes);
         ffi::Qnil
-    }
+    })
+}
+
+/// Replaces the attributes of `this` with those in `attributes`, as stored in
+/// the `@attributes` of a stock `ActiveModel::AttributeSet`.
+unsafe fn load_attributes(this: ffi::VALUE, attributes: ffi::VALUE) {
+    let attributes = yaml::decode(attributes);
+    get_struct_mut::<AttributeSet>(this).replace(attributes);
+}
+
+extern "C" fn encode_with(this: ffi::VALUE, coder: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        let this = get_struct::<AttributeSet>(this);
+        let attributes = yaml::encode(this);
+        protect(|| ffi::rb_funcall(coder, id!("[]="), 2, rstr!("attributes"), attributes));
+        // See `lie_about_our_class` in `attribute/ruby_glue.rs`
+        let tag = "!ruby/object:ActiveModel::AttributeSet";
+        protect(|| ffi::rb_funcall(coder, id!("map"), 1, rstr!(tag)));
+        ffi::Qnil
+    })
+}
+
+extern "C" fn concise_attributes(this: ffi::VALUE, default_types: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        let this = get_struct::<AttributeSet>(this);
+        yaml::encode_concise(this, default_types)
+    })
+}
+
+extern "C" fn from_concise_attributes(
+    _class: ffi::VALUE,
+    attributes: ffi::VALUE,
+    default_types: ffi::VALUE,
+) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        AttributeSet::new(yaml::decode_concise(attributes, default_types)).into_ruby()
+    })
+}
+
+extern "C" fn to_json(
+    argc: libc::c_int,
+    argv: *const ffi::VALUE,
+    this: ffi::VALUE,
+) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        let this = get_struct::<AttributeSet>(this);
+        let mut options = ffi::Qnil;
+        protect(|| ffi::rb_scan_args(argc, argv, cstr!("01"), &mut options));
+
+        let options = json::Options::from_ruby(options);
+        let mut encoder = Encoder::new();
+        json::write(&mut encoder, this, &options);
+        encoder.take_string()
+    })
+}
+
+/// `dump_json` and `write_csv` write their output to `io` in chunks of this
+/// size, so that dumping a large result doesn't hold all of it in memory at
+/// once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+extern "C" fn dump_json(
+    argc: libc::c_int,
+    argv: *const ffi::VALUE,
+    _module: ffi::VALUE,
+) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        let mut sets = ffi::Qnil;
+        let mut io = ffi::Qnil;
+        let mut options = ffi::Qnil;
+        protect(|| ffi::rb_scan_args(argc, argv, cstr!("21"), &mut sets, &mut io, &mut options));
+        check_type(sets, ffi::T_ARRAY, ffi::rb_const_get(ffi::rb_cObject, id!("Array")));
+
+        let options = json::Options::from_ruby(options);
+        let mut encoder = Encoder::new();
+        let flush = |encoder: &mut Encoder| {
+            let chunk = encoder.take_string();
+            protect(|| ffi::rb_funcall(io, id!("write"), 1, chunk));
+        };
+
+        encoder.write_raw(b"[");
+        for i in 0..ffi::RARRAY_LEN(sets) {
+            if i > 0 {
+                encoder.write_raw(b",");
+            }
+            let set = get_struct::<AttributeSet>(ffi::rb_ary_entry(sets, i));
+            json::write(&mut encoder, set, &options);
+            if encoder.len() >= CHUNK_SIZE {
+                flush(&mut encoder);
+            }
+        }
+        encoder.write_raw(b"]");
+        flush(&mut encoder);
+        io
+    })
+}
+
+extern "C" fn write_csv(
+    argc: libc::c_int,
+    argv: *const ffi::VALUE,
+    _module: ffi::VALUE,
+) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        let mut sets = ffi::Qnil;
+        let mut columns = ffi::Qnil;
+        let mut io = ffi::Qnil;
+        let mut options = ffi::Qnil;
+        protect(|| {
+            ffi::rb_scan_args(
+                argc,
+                argv,
+                cstr!("31"),
+                &mut sets,
+                &mut columns,
+                &mut io,
+                &mut options,
+            )
+        });
+        check_type(sets, ffi::T_ARRAY, ffi::rb_const_get(ffi::rb_cObject, id!("Array")));
+
+        let mut headers = true;
+        let mut before_type_cast = false;
+        if !ffi::RB_NIL_P(options) {
+            check_hash(options);
+            let option = |name| protect(|| ffi::rb_hash_aref(options, ffi::rb_id2sym(name)));
+            let headers_option = option(id!("headers"));
+            headers = ffi::RB_NIL_P(headers_option) || ffi::RTEST(headers_option);
+            before_type_cast = ffi::RTEST(option(id!("before_type_cast")));
+        }
+
+        let set_at = |i| get_struct::<AttributeSet>(ffi::rb_ary_entry(sets, i));
+        let len = ffi::RARRAY_LEN(sets);
+        let columns = csv::Columns::new(columns, if len > 0 { Some(set_at(0)) } else { None });
+        let mut writer = Writer::new();
+        let flush = |writer: &mut Writer| {
+            let chunk = writer.take_string();
+            protect(|| ffi::rb_funcall(io, id!("write"), 1, chunk));
+        };
+
+        if headers {
+            columns.write_header(&mut writer);
+        }
+        for i in 0..len {
+            columns.write_row(&mut writer, set_at(i), before_type_cast);
+            if writer.len() >= CHUNK_SIZE {
+                flush(&mut writer);
+            }
+        }
+        flush(&mut writer);
+        io
+    })
+}
+
+/// The default number of rows per record batch written by `write_arrow`
+const ARROW_BATCH_SIZE: usize = 64 * 1024;
+
+extern "C" fn write_arrow(
+    argc: libc::c_int,
+    argv: *const ffi::VALUE,
+    _module: ffi::VALUE,
+) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        let mut builder = ffi::Qnil;
+        let mut sets = ffi::Qnil;
+        let mut io = ffi::Qnil;
+        let mut options = ffi::Qnil;
+        protect(|| {
+            ffi::rb_scan_args(
+                argc,
+                argv,
+                cstr!("31"),
+                &mut builder,
+                &mut sets,
+                &mut io,
+                &mut options,
+            )
+        });
+        let builder = get_struct::<Builder>(builder);
+        check_type(sets, ffi::T_ARRAY, ffi::rb_const_get(ffi::rb_cObject, id!("Array")));
+
+        let mut file = false;
+        let mut batch_size = ARROW_BATCH_SIZE;
+        if !ffi::RB_NIL_P(options) {
+            check_hash(options);
+            let option = |name| protect(|| ffi::rb_hash_aref(options, ffi::rb_id2sym(name)));
+            let format = option(id!("format"));
+            if !ffi::RB_NIL_P(format) {
+                file = match string_or_symbol_to_id(format) {
+                    id if id == id!("file") => true,
+                    id if id == id!("stream") => false,
+                    _ => raise(ffi::rb_eArgError, "format must be :stream or :file"),
+                };
+            }
+            let batch_size_option = option(id!("batch_size"));
+            if !ffi::RB_NIL_P(batch_size_option) {
+                batch_size = protect(|| ffi::NUM2I64(batch_size_option)).max(1) as usize;
+            }
+        }
+
+        let schema = arrow::Schema::new(builder);
+        let mut writer = ::arrow::Writer::new(&schema.fields(), file);
+        let write = |bytes: Vec<u8>| {
+            let chunk = ffi::rb_str_new(bytes.as_ptr() as *const _, bytes.len() as _);
+            protect(|| ffi::rb_funcall(io, id!("write"), 1, chunk));
+        };
+
+        write(writer.start());
+        let len = ffi::RARRAY_LEN(sets) as usize;
+        for start in (0..len).step_by(batch_size) {
+            let end = (start + batch_size).min(len);
+            let batch = (start..end)
+                .map(|i| get_struct::<AttributeSet>(ffi::rb_ary_entry(sets, i as isize)))
+                .collect::<Vec<_>>();
+            let columns = schema.columns(batch);
+            write(writer.write_batch(&columns));
+        }
+        write(writer.finish());
+        io
+    })
+}
+
+extern "C" fn to_msgpack(this: ffi::VALUE, builder: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        let this = get_struct::<AttributeSet>(this);
+        let builder = get_struct::<Builder>(builder);
+        msgpack::dump(this, builder)
+    })
+}
+
+extern "C" fn from_msgpack(
+    argc: libc::c_int,
+    argv: *const ffi::VALUE,
+    _class: ffi::VALUE,
+) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        let mut builder = ffi::Qnil;
+        let mut bytes = ffi::Qnil;
+        let mut additional_types = ffi::Qnil;
+        protect(|| {
+            ffi::rb_scan_args(
+                argc,
+                argv,
+                cstr!("21"),
+                &mut builder,
+                &mut bytes,
+                &mut additional_types,
+            )
+        });
+        let builder = get_struct::<Builder>(builder);
+        let additional_types = if ffi::RB_NIL_P(additional_types) {
+            None
+        } else {
+            Some(additional_types)
+        };
+        msgpack::load(builder, bytes, additional_types)
+    })
+}
+
+extern "C" fn stock_class(_class: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| unsafe { STOCK_ATTRIBUTE_SET.unwrap() })
+}
+
+extern "C" fn to_stock(this: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        let this = get_struct::<AttributeSet>(this);
+        let _guard = this.borrow.read();
+        AttributeSet::new(this.attributes.clone()).into_ruby_as(STOCK_ATTRIBUTE_SET.unwrap())
+    })
+}
+
+extern "C" fn dump_marshal(this: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        let is_stock = ffi::rb_obj_class(this) == STOCK_ATTRIBUTE_SET.unwrap();
+        let this = get_struct::<AttributeSet>(this);
+        let _guard = this.borrow.read();
+        let result = stock::new_object();
+
+        if is_stock {
+            let attributes = ffi::rb_hash_new();
+            for attr in this.attributes.values() {
+                let (name, attr) = (attr.name(), into_stock(attr));
+                protect(|| ffi::rb_hash_aset(attributes, name, attr));
+            }
+            stock::set_ivar(result, id!("@attributes"), attributes);
+        } else {
+            let data = marshal::dump(this.attributes.values());
+            stock::set_ivar(result, id!("@marshal_data"), data);
+        }
+        result
+    })
+}
+
+extern "C" fn load_marshal(this: ffi::VALUE, data: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        if let Some(data) = stock::marshal_data(data) {
+            let attributes = marshal::load(data);
+            get_struct_mut::<AttributeSet>(this).replace(attributes);
+        } else if let Some(attributes) = stock::ivar(data, id!("@attributes")) {
+            load_attributes(this, attributes);
+        } else {
+            load_error("expected attribute set data to have @attributes");
+        }
+        ffi::Qnil
+    })
 }
 
 extern "C" fn except(argc: libc::c_int, argv: *const ffi::VALUE, this: ffi::VALUE) -> ffi::VALUE {
-    unsafe {
+    ruby_boundary(|| unsafe {
         let this = get_struct::<AttributeSet>(this);
+        let _guard = this.borrow.read();
         let result = ffi::rb_hash_new();
 
         for attr in this.attributes.values() {
-            ffi::rb_hash_aset(result, attr.name(), attr.as_ruby());
+            let (name, attr) = (attr.name(), attr.as_ruby());
+            protect(|| ffi::rb_hash_aset(result, name, attr));
         }
 
-        ffi::rb_funcallv(result, id!("except"), argc, argv)
-    }
+        protect(|| ffi::rb_funcallv(result, id!("except"), argc, argv))
+    })
 }
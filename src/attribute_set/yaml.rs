@@ -0,0 +1,241 @@
+//! The YAML representation of an `AttributeSet`.
+//!
+//! Stock Rails has no `encode_with` for `AttributeSet`, so Psych writes its
+//! `@attributes`, which is usually an `ActiveModel::LazyAttributeHash`. We
+//! write the same structure, so that stock Rails can load our YAML. Each
+//! attribute's type is written once under `types`, and the raw value of each
+//! attribute which came from the database under `values`. Only attributes
+//! which have been changed are written out in full, under `delegate_hash`.
+//!
+//! When loading, the `LazyAttributeHash` is read directly rather than through
+//! `materialize`, which would build every attribute in Ruby only for us to
+//! copy them. Rails 4.2 through 5.1 write the same structure under the
+//! `ActiveRecord` namespace, and Rails 7 writes an `ActiveModel::LazyAttributeSet`
+//! instead, which holds the same parts itself.
+//!
+//! Active Record encodes records through `YAMLEncoder` instead, which writes
+//! a list of `concise_attributes`. Any attribute whose type is the model's
+//! default type for that attribute is written without one, and given it back
+//! when decoding.
+
+use indexmap::IndexMap;
+
+use attribute::{Attribute, MaybeProc, Source};
+use ffi;
+use into_ruby::IntoRuby;
+use load::*;
+use protect::{hash_foreach, protect};
+use stock;
+use super::AttributeSet;
+use util::check_hash;
+
+pub fn encode(set: &AttributeSet) -> ffi::VALUE {
+    let _guard = set.borrow.read();
+    unsafe {
+        let types = ffi::rb_hash_new();
+        let values = ffi::rb_hash_new();
+        let delegate_hash = ffi::rb_hash_new();
+
+        for attr in set.attributes.values() {
+            let (name, ty) = (attr.name(), attr.ty());
+            protect(|| ffi::rb_hash_aset(types, name, ty));
+            match *attr {
+                Attribute::Populated {
+                    raw_value: ref raw_value @ MaybeProc::NotProc(_),
+                    source: Source::FromDatabase,
+                    ..
+                }
+                | Attribute::Populated {
+                    raw_value: ref raw_value @ MaybeProc::Bytes { .. },
+                    source: Source::FromDatabase,
+                    ..
+                } => {
+                    let value = raw_value.value();
+                    protect(|| ffi::rb_hash_aset(values, name, value));
+                }
+                Attribute::Populated { .. } => {
+                    let attr = attr.clone().into_ruby();
+                    protect(|| ffi::rb_hash_aset(delegate_hash, name, attr));
+                }
+                Attribute::Uninitialized { .. } => {}
+            }
+        }
+
+        let additional_types = ffi::rb_hash_new();
+        let default_attributes = ffi::rb_hash_new();
+        protect(|| {
+            let active_model = ffi::rb_const_get(ffi::rb_cObject, id!("ActiveModel"));
+            let lazy_attribute_hash = ffi::rb_const_get(active_model, id!("LazyAttributeHash"));
+            ffi::rb_funcall(
+                lazy_attribute_hash,
+                id!("new"),
+                5,
+                types,
+                values,
+                additional_types,
+                default_attributes,
+                delegate_hash,
+            )
+        })
+    }
+}
+
+/// The `concise_attributes` written by `YAMLEncoder#encode`.
+pub unsafe fn encode_concise(set: &AttributeSet, default_types: ffi::VALUE) -> ffi::VALUE {
+    check_hash(default_types);
+    let _guard = set.borrow.read();
+    let result = ffi::rb_ary_new_capa(set.attributes.len() as _);
+    for attr in set.attributes.values() {
+        let default_type = protect(|| ffi::rb_hash_aref(default_types, attr.name()));
+        let attr = if attr.ty() == default_type {
+            attr.with_type(ffi::Qnil)
+        } else {
+            attr.clone()
+        };
+        let attr = attr.into_ruby();
+        protect(|| ffi::rb_ary_push(result, attr));
+    }
+    result
+}
+
+/// Reads the `concise_attributes` written by `YAMLEncoder#encode`.
+pub unsafe fn decode_concise(
+    attributes: ffi::VALUE,
+    default_types: ffi::VALUE,
+) -> IndexMap<ffi::ID, Attribute> {
+    check_hash(default_types);
+    let len = expect_array(attributes, 0, usize::max_value(), "concise attributes");
+    let mut result = IndexMap::with_capacity(len);
+    for i in 0..len {
+        let attr = expect_attribute(ffi::rb_ary_entry(attributes, i as _), "attribute");
+        let name = attr.name();
+        let attr = if ffi::RB_NIL_P(attr.ty()) {
+            attr.with_type(lookup(default_types, name))
+        } else {
+            attr.clone()
+        };
+        result.insert(expect_key(name, "attribute name"), attr);
+    }
+    result
+}
+
+/// Reads the `attributes` of a YAML encoded `AttributeSet`, which is either
+/// a `Hash` or an `ActiveModel::LazyAttributeHash`.
+pub unsafe fn decode(attributes: ffi::VALUE) -> IndexMap<ffi::ID, Attribute> {
+    if ffi::RB_TYPE_P(attributes, ffi::T_HASH) {
+        return decode_hash(attributes);
+    }
+
+    let ivar = |name| {
+        stock::ivar(attributes, name).unwrap_or_else(|| {
+            load_error("expected attributes to be a Hash or an ActiveModel::LazyAttributeHash")
+        })
+    };
+    Lazy {
+        types: ivar(id!("@types")),
+        values: ivar(id!("@values")),
+        additional_types: stock::ivar(attributes, id!("@additional_types")),
+        default_attributes: stock::ivar(attributes, id!("@default_attributes")),
+        delegate_hash: ivar(id!("@delegate_hash")),
+    }.decode()
+}
+
+/// Reads a YAML encoded `ActiveModel::LazyAttributeSet`, which is what
+/// Rails 7 writes. It has the same parts as a `LazyAttributeHash`, but they
+/// are stored on the set itself.
+pub unsafe fn decode_lazy_attribute_set(coder: ffi::VALUE) -> IndexMap<ffi::ID, Attribute> {
+    let get = |key: &str| {
+        let value = protect(|| ffi::rb_funcall(coder, id!("[]"), 1, rstr!(key)));
+        if ffi::RB_NIL_P(value) {
+            None
+        } else {
+            Some(value)
+        }
+    };
+    let nil = ffi::Qnil;
+    Lazy {
+        types: get("types").unwrap_or(nil),
+        values: get("values").unwrap_or(nil),
+        additional_types: get("additional_types"),
+        default_attributes: get("default_attributes"),
+        delegate_hash: get("attributes").unwrap_or(nil),
+    }.decode()
+}
+
+/// The parts of a lazily built attribute set in stock Rails.
+struct Lazy {
+    types: ffi::VALUE,
+    values: ffi::VALUE,
+    additional_types: Option<ffi::VALUE>,
+    default_attributes: Option<ffi::VALUE>,
+    delegate_hash: ffi::VALUE,
+}
+
+impl Lazy {
+    unsafe fn decode(self) -> IndexMap<ffi::ID, Attribute> {
+        let Lazy {
+            types,
+            values,
+            additional_types,
+            default_attributes,
+            delegate_hash,
+        } = self;
+        expect_hash(types, "types");
+        expect_hash(values, "values");
+
+        // This follows `LazyAttributeHash#materialize`. Anything that has
+        // already been materialized takes precedence, followed by the values
+        // from the database, and then the remaining types.
+        let mut result = decode_hash(delegate_hash);
+        hash_foreach(values, |name, value| {
+            let key = expect_key(name, "attribute name");
+            if !result.contains_key(&key) {
+                let ty = optional_lookup(additional_types, name)
+                    .unwrap_or_else(|| lookup(types, name));
+                result.insert(key, Attribute::from_database(name, value, ty));
+            }
+        });
+        hash_foreach(types, |name, ty| {
+            let key = expect_key(name, "attribute name");
+            if !result.contains_key(&key) {
+                let attr = match optional_lookup(default_attributes, name) {
+                    Some(attr) => expect_attribute(attr, "default attribute").deep_dup(),
+                    None => Attribute::uninitialized(name, ty),
+                };
+                result.insert(key, attr);
+            }
+        });
+        result
+    }
+}
+
+unsafe fn decode_hash(hash: ffi::VALUE) -> IndexMap<ffi::ID, Attribute> {
+    expect_hash(hash, "attributes");
+
+    let mut result = IndexMap::with_capacity(ffi::RHASH_SIZE(hash) as usize);
+    hash_foreach(hash, |key, value| {
+        let key = expect_key(key, "attribute name");
+        let value = expect_attribute(value, "attribute").clone();
+        result.insert(key, value);
+    });
+    result
+}
+
+unsafe fn lookup(hash: ffi::VALUE, key: ffi::VALUE) -> ffi::VALUE {
+    protect(|| ffi::rb_hash_aref(hash, key))
+}
+
+unsafe fn optional_lookup(hash: Option<ffi::VALUE>, key: ffi::VALUE) -> Option<ffi::VALUE> {
+    match hash {
+        Some(hash) if !ffi::RB_NIL_P(hash) => {
+            expect_hash(hash, "attributes");
+            let value = lookup(hash, key);
+            if ffi::RB_NIL_P(value) {
+                None
+            } else {
+                Some(value)
+            }
+        }
+        _ => None,
+    }
+}
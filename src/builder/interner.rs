@@ -0,0 +1,230 @@
+//! Interning of the raw values of low cardinality columns, such as statuses,
+//! types and country codes. Each distinct value is stored once as a frozen
+//! string and shared by every attribute loaded with it. When its cast value
+//! is frozen too, that is shared as well, so it is only cast once.
+
+use std::cell::RefCell;
+
+use attribute::Attribute;
+use ffi;
+use indexmap::IndexMap;
+use protect::protect;
+use util::*;
+
+extern "C" {
+    fn rb_hash_lookup2(hash: ffi::VALUE, key: ffi::VALUE, default: ffi::VALUE) -> ffi::VALUE;
+    fn rb_str_new_frozen(value: ffi::VALUE) -> ffi::VALUE;
+}
+
+/// How many distinct values a column may have before we stop interning it,
+/// so a column which turns out not to be low cardinality can't grow its
+/// table forever.
+const INTERN_LIMIT: usize = 256;
+
+#[derive(Default)]
+pub struct Interner {
+    columns: Columns,
+    tables: RefCell<IndexMap<ffi::ID, Table>>,
+}
+
+/// Which columns are interned
+#[derive(Clone, PartialEq)]
+enum Columns {
+    Off,
+    /// Every column with string values, until it has too many of them
+    All,
+    Only(Vec<ffi::ID>),
+}
+
+impl Default for Columns {
+    fn default() -> Self {
+        Columns::Off
+    }
+}
+
+enum Table {
+    Interning {
+        /// Maps raw values to their position in `values`
+        index: ffi::VALUE,
+        /// The frozen raw values, and what they cast to if it is frozen
+        values: Vec<(ffi::VALUE, Option<ffi::VALUE>)>,
+        /// The type the values were cast with
+        ty: ffi::VALUE,
+    },
+    /// The column had more than `INTERN_LIMIT` distinct values
+    Disabled,
+}
+
+/// What a table has for a value
+enum Lookup {
+    /// The interned raw value, and its cast value if it can be shared
+    Found(ffi::VALUE, Option<ffi::VALUE>),
+    Missing,
+    Disabled,
+}
+
+impl Interner {
+    /// `option` is the `intern:` option given to `Builder.new`, either `true`
+    /// or the names of the columns to intern.
+    pub unsafe fn from_option(option: ffi::VALUE) -> Self {
+        let columns = if option == ffi::Qtrue {
+            Columns::All
+        } else if ffi::RTEST(option) {
+            let array_class = ffi::rb_const_get(ffi::rb_cObject, id!("Array"));
+            check_type(option, ffi::T_ARRAY, array_class);
+            let names = (0..ffi::RARRAY_LEN(option))
+                .map(|i| string_or_symbol_to_id(ffi::rb_ary_entry(option, i)))
+                .collect();
+            Columns::Only(names)
+        } else {
+            Columns::Off
+        };
+        Interner {
+            columns,
+            tables: RefCell::default(),
+        }
+    }
+
+    /// The inverse of `from_option`
+    pub fn to_option(&self) -> ffi::VALUE {
+        match self.columns {
+            Columns::Off => unsafe { ffi::Qnil },
+            Columns::All => unsafe { ffi::Qtrue },
+            Columns::Only(ref names) => {
+                let names = names.iter().map(|&name| unsafe { ffi::rb_id2sym(name) });
+                to_ruby_array(names.len(), names)
+            }
+        }
+    }
+
+    fn is_interned(&self, key: ffi::ID) -> bool {
+        match self.columns {
+            Columns::Off => false,
+            Columns::All => true,
+            Columns::Only(ref names) => names.contains(&key),
+        }
+    }
+
+    /// Same as `attr.with_value_from_database(value)`, but sharing `value`
+    /// (and possibly its cast value) with previous rows when the column is
+    /// interned.
+    pub fn with_value_from_database(
+        &self,
+        key: ffi::ID,
+        attr: &Attribute,
+        value: ffi::VALUE,
+    ) -> Attribute {
+        if !self.is_interned(key) || unsafe { !ffi::RB_TYPE_P(value, ffi::T_STRING) } {
+            return attr.with_value_from_database(value);
+        }
+
+        let ty = attr.ty();
+        // Nothing that can allocate Ruby objects may run while `tables` is
+        // borrowed, since that can start a GC, which calls `mark`.
+        if !self.tables.borrow().contains_key(&key) {
+            let table = Table::new(ty);
+            self.tables.borrow_mut().insert(key, table);
+        }
+
+        let lookup = match self.tables.borrow()[&key] {
+            Table::Interning {
+                index,
+                ref values,
+                ty: table_ty,
+            } => {
+                let position = unsafe { rb_hash_lookup2(index, value, ffi::Qnil) };
+                if unsafe { ffi::RB_NIL_P(position) } {
+                    Lookup::Missing
+                } else {
+                    let (raw, cast) = values[unsafe { ffi::NUM2U64(position) } as usize];
+                    Lookup::Found(raw, if table_ty == ty { cast } else { None })
+                }
+            }
+            Table::Disabled => Lookup::Disabled,
+        };
+        match lookup {
+            Lookup::Found(raw, cast) => return attr.with_value_from_database_cast_as(raw, cast),
+            Lookup::Disabled => return attr.with_value_from_database(value),
+            Lookup::Missing => {}
+        }
+
+        let raw = unsafe { rb_str_new_frozen(value) };
+        let cast = attr.with_value_from_database(raw).value();
+        let cast = if unsafe { ffi::OBJ_FROZEN(cast) } {
+            Some(cast)
+        } else {
+            None
+        };
+
+        let added = {
+            let mut tables = self.tables.borrow_mut();
+            let table = &mut tables[&key];
+            match *table {
+                Table::Interning {
+                    index,
+                    ref mut values,
+                    ..
+                } if values.len() < INTERN_LIMIT =>
+                {
+                    values.push((raw, cast));
+                    Some((index, values.len() - 1))
+                }
+                _ => {
+                    *table = Table::Disabled;
+                    None
+                }
+            }
+        };
+        match added {
+            Some((index, position)) => {
+                let position = unsafe { ffi::U642NUM(position as u64) };
+                protect(|| unsafe { ffi::rb_hash_aset(index, raw, position) });
+                attr.with_value_from_database_cast_as(raw, cast)
+            }
+            // Nothing but the table would keep `raw` and `cast` alive, so
+            // fall back to the value we were given.
+            None => attr.with_value_from_database(value),
+        }
+    }
+
+    pub unsafe fn mark(&self) {
+        for table in self.tables.borrow().values() {
+            if let Table::Interning {
+                index,
+                ref values,
+                ty,
+            } = *table
+            {
+                ffi::rb_gc_mark(index);
+                ffi::rb_gc_mark(ty);
+                for &(raw, cast) in values {
+                    ffi::rb_gc_mark(raw);
+                    if let Some(cast) = cast {
+                        ffi::rb_gc_mark(cast);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Copies share the configuration, but start out with empty tables, since
+/// the Ruby hashes behind them can't be shared.
+impl Clone for Interner {
+    fn clone(&self) -> Self {
+        Interner {
+            columns: self.columns.clone(),
+            tables: RefCell::default(),
+        }
+    }
+}
+
+impl Table {
+    fn new(ty: ffi::VALUE) -> Self {
+        Table::Interning {
+            index: unsafe { ffi::rb_hash_new() },
+            values: Vec::new(),
+            ty,
+        }
+    }
+}
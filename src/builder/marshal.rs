@@ -0,0 +1,108 @@
+//! The format written by `Builder#_dump_data`. It is an `Array` of the form:
+//!
+//! ```text
+//! [VERSION, keys, attributes, fallback_type, strict, intern]
+//! ```
+//!
+//! where `keys` are the symbols the attributes are stored under, and
+//! `attributes` is written the same way as an `AttributeSet`. Defaults which
+//! are procs, and `strict:` callbacks can't be dumped, since Marshal can't
+//! write procs. `intern` is the `intern:` option.
+
+use attribute_set::marshal;
+use ffi;
+use indexmap::IndexMap;
+use load::*;
+use protect::protect;
+use super::{Builder, UnknownColumns};
+use super::interner::Interner;
+use util::*;
+
+const VERSION: i32 = 1;
+
+pub fn dump(builder: &Builder) -> ffi::VALUE {
+    let attributes = &builder.uninitialized_attributes;
+    if let Some(attr) = attributes.values().find(|attr| attr.has_proc()) {
+        unsafe {
+            let name = to_rust_string(protect(|| ffi::rb_funcall(attr.name(), id!("to_s"), 0)));
+            raise(
+                ffi::rb_eTypeError,
+                format!("can't dump a Builder with a proc default for `{}`", name),
+            );
+        }
+    }
+    let strict = match builder.unknown_columns {
+        UnknownColumns::Allow => unsafe { ffi::Qfalse },
+        UnknownColumns::Raise => unsafe { ffi::Qtrue },
+        UnknownColumns::Report(_) => raise(
+            unsafe { ffi::rb_eTypeError },
+            "can't dump a Builder with a strict callback",
+        ),
+    };
+
+    let keys = attributes.keys().map(|&key| unsafe { ffi::rb_id2sym(key) });
+    unsafe {
+        let result = ffi::rb_ary_new_capa(6);
+        ffi::rb_ary_push(result, ffi::I322NUM(VERSION));
+        ffi::rb_ary_push(result, to_ruby_array(attributes.len(), keys));
+        ffi::rb_ary_push(result, marshal::dump(attributes.values()));
+        ffi::rb_ary_push(result, builder.fallback_type.get().unwrap_or(ffi::Qnil));
+        ffi::rb_ary_push(result, strict);
+        ffi::rb_ary_push(result, builder.interner.to_option());
+        result
+    }
+}
+
+pub unsafe fn load(builder: &mut Builder, data: ffi::VALUE) {
+    expect_array(data, 6, 6, "builder data");
+    let version = expect_integer(ffi::rb_ary_entry(data, 0), 1, i32::max_value(), "format version");
+    if version > VERSION {
+        load_error(format!(
+            "unsupported builder format version {} (expected at most {})",
+            version, VERSION
+        ));
+    }
+
+    let keys = ffi::rb_ary_entry(data, 1);
+    let len = expect_array(keys, 0, usize::max_value(), "builder keys");
+    let loaded = marshal::load(ffi::rb_ary_entry(data, 2));
+    if loaded.len() != len {
+        load_error(format!(
+            "expected {} builder attributes, got {}",
+            len,
+            loaded.len()
+        ));
+    }
+    let mut attributes = IndexMap::with_capacity(len);
+    for (i, (_, attr)) in loaded.into_iter().enumerate() {
+        let key = expect_key(ffi::rb_ary_entry(keys, i as _), "builder key");
+        attributes.insert(key, attr);
+    }
+
+    let fallback_type = ffi::rb_ary_entry(data, 3);
+    let unknown_columns = match ffi::rb_ary_entry(data, 4) {
+        strict if strict == ffi::Qtrue => UnknownColumns::Raise,
+        strict if strict == ffi::Qfalse => UnknownColumns::Allow,
+        strict => load_error(format!(
+            "expected strict to be true or false, got {}",
+            class_name(strict)
+        )),
+    };
+
+    let intern = ffi::rb_ary_entry(data, 5);
+    if intern != ffi::Qtrue && !ffi::RB_NIL_P(intern) && !ffi::RB_TYPE_P(intern, ffi::T_ARRAY) {
+        load_error(format!(
+            "expected intern to be true, nil or an Array, got {}",
+            class_name(intern)
+        ));
+    }
+    let interner = Interner::from_option(intern);
+
+    builder.uninitialized_attributes = attributes;
+    builder.merged_schemas.borrow_mut().clear();
+    builder
+        .fallback_type
+        .set(if ffi::RB_NIL_P(fallback_type) { None } else { Some(fallback_type) });
+    builder.unknown_columns = unknown_columns;
+    builder.interner = interner;
+}
@@ -1,37 +1,212 @@
+use std::cell::{Cell, RefCell};
+
 use indexmap::IndexMap;
 
-use attribute::Attribute;
+use attribute::{deserialize, Attribute};
 use attribute_set::AttributeSet;
-use {ffi, libc};
+use ffi;
+use identity_map::IdentityMap;
+use into_ruby::IntoRuby;
+use self::interner::Interner;
+use protect::{hash_foreach, protect, rescue};
+use raw_bytes::{buffer_from_ruby, RawBytes, RawValue};
+use sqlite3;
 use util::*;
 
+mod interner;
+mod marshal;
 mod ruby_glue;
 
 #[derive(Default, Clone)]
 pub struct Builder {
     uninitialized_attributes: IndexMap<ffi::ID, Attribute>,
+    merged_schemas: RefCell<Vec<MergedSchema>>,
+    /// The type of columns we have no type for. When not given, this is
+    /// looked up the first time it's needed.
+    fallback_type: Cell<Option<ffi::VALUE>>,
+    unknown_columns: UnknownColumns,
+    /// The unknown columns we've already reported, since a callback only
+    /// needs to hear about each one once rather than once per row.
+    reported_columns: RefCell<Vec<ffi::ID>>,
+    interner: Interner,
+}
+
+/// What to do when the database returns a column we have no type for.
+#[derive(Clone, Copy)]
+enum UnknownColumns {
+    /// Use the fallback type
+    Allow,
+    /// Raise `UnknownColumnError`
+    Raise,
+    /// Call the given callable with the column name, and then use the
+    /// fallback type
+    Report(ffi::VALUE),
 }
 
+impl Default for UnknownColumns {
+    fn default() -> Self {
+        UnknownColumns::Allow
+    }
+}
+
+/// `uninitialized_attributes` with the `additional_types` of a query merged
+/// in. Queries with the same `select` or joins pass the same types, so we
+/// only merge them once.
+#[derive(Clone)]
+struct MergedSchema {
+    additional_types: ffi::VALUE,
+    entries: Vec<(ffi::VALUE, ffi::VALUE)>,
+    attributes: IndexMap<ffi::ID, Attribute>,
+}
+
+/// How many different `additional_types` a builder remembers
+const MERGED_SCHEMA_CACHE_SIZE: usize = 8;
+
 impl Builder {
-    unsafe fn initialize(&mut self, types: ffi::VALUE, defaults: Option<ffi::VALUE>) {
-        if !ffi::RB_TYPE_P(types, ffi::T_HASH) {
-            ffi::rb_raise(ffi::rb_eTypeError, cstr!("Expected a Hash"));
-        }
-
-        self.uninitialized_attributes
-            .reserve(ffi::RHASH_SIZE(types) as usize);
-        ffi::rb_hash_foreach(
-            types,
-            push_uninitialized_value,
-            &mut self.uninitialized_attributes as *mut _ as *mut _,
-        );
+    pub fn attributes(&self) -> &IndexMap<ffi::ID, Attribute> {
+        &self.uninitialized_attributes
+    }
+
+    /// `options` is a third positional argument, since default attributes may
+    /// be keyed by symbols. It may contain `fallback_type:`, `strict:`,
+    /// which is either `true` to raise on unknown columns or a callable to
+    /// report them to, and `intern:`, which is either `true` to intern every
+    /// column with string values or the names of the columns to intern.
+    /// Interned columns share their raw values between rows.
+    unsafe fn initialize(
+        &mut self,
+        types: ffi::VALUE,
+        defaults: Option<ffi::VALUE>,
+        options: Option<ffi::VALUE>,
+    ) {
+        check_hash(types);
+
+        let mut fallback_type = None;
+        let mut unknown_columns = UnknownColumns::Allow;
+        let mut interner = Interner::default();
+        if let Some(options) = options {
+            check_hash(options);
+            let option = |name| protect(|| ffi::rb_hash_aref(options, ffi::rb_id2sym(name)));
+            let ty = option(id!("fallback_type"));
+            if !ffi::RB_NIL_P(ty) {
+                fallback_type = Some(ty);
+            }
+            let strict = option(id!("strict"));
+            if strict == ffi::Qtrue {
+                unknown_columns = UnknownColumns::Raise;
+            } else if ffi::RTEST(strict) {
+                unknown_columns = UnknownColumns::Report(strict);
+            }
+            interner = Interner::from_option(option(id!("intern")));
+        }
+
+        // Build into a local map, so an exception half way through doesn't
+        // leave us with a partially initialized builder.
+        let mut attributes = IndexMap::with_capacity(ffi::RHASH_SIZE(types) as usize);
+        hash_foreach(types, |key, value| {
+            push_uninitialized_value(&mut attributes, key, value)
+        });
         if let Some(defaults) = defaults {
-            ffi::rb_hash_foreach(
-                defaults,
-                push_attribute,
-                &mut self.uninitialized_attributes as *mut _ as *mut _,
-            );
+            check_hash(defaults);
+            hash_foreach(defaults, |key, value| {
+                push_attribute(&mut attributes, key, value)
+            });
         }
+        self.uninitialized_attributes = attributes;
+        self.merged_schemas.borrow_mut().clear();
+        self.fallback_type.set(fallback_type);
+        self.unknown_columns = unknown_columns;
+        self.reported_columns.borrow_mut().clear();
+        self.interner = interner;
+    }
+
+    fn attribute_names(&self) -> ffi::VALUE {
+        let names = self.uninitialized_attributes.values().map(Attribute::name);
+        to_ruby_array(self.uninitialized_attributes.len(), names)
+    }
+
+    fn types(&self) -> ffi::VALUE {
+        let result = unsafe { ffi::rb_hash_new() };
+        for attr in self.uninitialized_attributes.values() {
+            let (name, ty) = (attr.name(), attr.ty());
+            protect(|| unsafe { ffi::rb_hash_aset(result, name, ty) });
+        }
+        result
+    }
+
+    /// The attributes which were given as defaults, rather than just a type.
+    fn default_attributes(&self) -> ffi::VALUE {
+        let result = unsafe { ffi::rb_hash_new() };
+        let defaults = self.uninitialized_attributes
+            .values()
+            .filter(|attr| attr.is_initialized());
+        for attr in defaults {
+            let name = attr.name();
+            let attr = attr.clone().into_ruby();
+            protect(|| unsafe { ffi::rb_hash_aset(result, name, attr) });
+        }
+        result
+    }
+
+    /// A copy of this builder with `types` added, or replacing the types of
+    /// existing attributes.
+    unsafe fn with_additional_types(&self, types: ffi::VALUE) -> Self {
+        check_hash(types);
+        let mut attributes = self.uninitialized_attributes.clone();
+        hash_foreach(types, |key, value| {
+            push_uninitialized_value(&mut attributes, key, value)
+        });
+        Builder {
+            uninitialized_attributes: attributes,
+            merged_schemas: RefCell::default(),
+            fallback_type: self.fallback_type.clone(),
+            unknown_columns: self.unknown_columns,
+            reported_columns: self.reported_columns.clone(),
+            interner: self.interner.clone(),
+        }
+    }
+
+    /// Builders are equal when they have the same attributes in the same
+    /// order, with equal types and defaults.
+    fn equals(&self, other: &Builder) -> bool {
+        self.uninitialized_attributes.len() == other.uninitialized_attributes.len()
+            && self.uninitialized_attributes
+                .iter()
+                .zip(&other.uninitialized_attributes)
+                .all(|((key, attr), (other_key, other_attr))| {
+                    key == other_key && attr == other_attr
+                })
+    }
+
+    /// Consistent with `equals`, by hashing the names and types.
+    fn hash(&self) -> ffi::VALUE {
+        let names = self.attribute_names();
+        let types = self.uninitialized_attributes.values().map(Attribute::ty);
+        let types = to_ruby_array(self.uninitialized_attributes.len(), types);
+        let pair = to_ruby_array(2, vec![names, types]);
+        unsafe { protect(|| ffi::rb_funcall(pair, id!("hash"), 0)) }
+    }
+
+    /// A new attribute set for `Model.new`, with the same attributes as
+    /// `default_attributes.deep_dup` but without duplicating anything that
+    /// doesn't need it.
+    fn build_defaults(&self) -> ffi::VALUE {
+        // Duplicated values are only referenced from Rust until the set is
+        // wrapped, so keep them somewhere the GC can see them.
+        let keep_alive = unsafe { ffi::rb_ary_new() };
+        let attributes = self.uninitialized_attributes
+            .iter()
+            .map(|(&key, attr)| {
+                let attr = attr.dup_default();
+                if attr.has_been_read() {
+                    unsafe { ffi::rb_ary_push(keep_alive, attr.value()) };
+                }
+                (key, attr)
+            })
+            .collect();
+        let result = AttributeSet::new(attributes).into_ruby();
+        gc_guard(&keep_alive);
+        result
     }
 
     fn build_from_database(
@@ -39,80 +214,518 @@ impl Builder {
         values: ffi::VALUE,
         additional_types: Option<ffi::VALUE>,
     ) -> AttributeSet {
+        unsafe { check_hash(values) };
+        let mut attributes = self.attributes_with(additional_types);
+        hash_foreach(values, |key, value| self.push_value(&mut attributes, key, value));
+
+        AttributeSet::new(attributes)
+    }
+
+    /// Like `build_from_database`, but returns a copy of the set in
+    /// `identity_map` if we've already loaded the row with the same columns.
+    /// `this` is the Ruby object for `self`, which the map is keyed by.
+    unsafe fn build_identity_mapped(
+        &self,
+        this: ffi::VALUE,
+        identity_map: &mut IdentityMap,
+        values: ffi::VALUE,
+        additional_types: Option<ffi::VALUE>,
+    ) -> ffi::VALUE {
+        check_hash(values);
+        let primary_key = identity_map.primary_key();
+        let mut columns = Vec::with_capacity(ffi::RHASH_SIZE(values) as usize);
+        let mut id = ffi::Qnil;
+        hash_foreach(values, |key, value| {
+            let key = string_or_symbol_to_id(key);
+            if key == primary_key {
+                id = value;
+            }
+            columns.push(key);
+        });
+
+        identity_map.fetch(this, id, &columns, || {
+            self.build_from_database(values, additional_types)
+        })
+    }
+
+    /// `uninitialized_attributes` with `additional_types` merged in, from the
+    /// cache if we've seen the same hash with the same contents before.
+    pub fn attributes_with(
+        &self,
+        additional_types: Option<ffi::VALUE>,
+    ) -> IndexMap<ffi::ID, Attribute> {
+        let types = match additional_types {
+            Some(types) => types,
+            None => return self.uninitialized_attributes.clone(),
+        };
+
+        unsafe { check_hash(types) };
+        let mut entries = Vec::with_capacity(unsafe { ffi::RHASH_SIZE(types) } as usize);
+        hash_foreach(types, |key, value| entries.push((key, value)));
+
+        let cached = self.merged_schemas
+            .borrow()
+            .iter()
+            .find(|schema| schema.additional_types == types && schema.entries == entries)
+            .map(|schema| schema.attributes.clone());
+        if let Some(attributes) = cached {
+            return attributes;
+        }
+
         let mut attributes = self.uninitialized_attributes.clone();
+        for &(key, value) in &entries {
+            push_uninitialized_value(&mut attributes, key, value);
+        }
 
+        let mut merged_schemas = self.merged_schemas.borrow_mut();
+        if merged_schemas.len() >= MERGED_SCHEMA_CACHE_SIZE {
+            merged_schemas.remove(0);
+        }
+        merged_schemas.push(MergedSchema {
+            additional_types: types,
+            entries,
+            attributes: attributes.clone(),
+        });
+        attributes
+    }
+
+    /// Like `build_from_database`, for a whole result at once. `rows` are
+    /// arrays of values in the order of `columns`, such as the `columns` and
+    /// `rows` of an `ActiveRecord::Result`. Column names and types are looked
+    /// up once, rather than once per row. When given an identity map (and
+    /// the Ruby object for `self`), rows which are already in it are copied
+    /// rather than built again.
+    unsafe fn build_rows_from_database(
+        &self,
+        columns: ffi::VALUE,
+        rows: ffi::VALUE,
+        additional_types: Option<ffi::VALUE>,
+        mut identity_map: Option<(ffi::VALUE, &mut IdentityMap)>,
+    ) -> ffi::VALUE {
+        let array_class = ffi::rb_const_get(ffi::rb_cObject, id!("Array"));
+        check_type(columns, ffi::T_ARRAY, array_class);
+        check_type(rows, ffi::T_ARRAY, array_class);
+
+        let (template, ids, positions) = self.row_template(columns, additional_types);
+        let primary_key = identity_map
+            .as_ref()
+            .and_then(|&(_, ref map)| ids.iter().position(|&id| id == map.primary_key()));
+
+        let len = ffi::RARRAY_LEN(rows);
+        let result = ffi::rb_ary_new_capa(len);
+        for index in 0..len {
+            let row = ffi::rb_ary_entry(rows, index);
+            check_type(row, ffi::T_ARRAY, array_class);
+            if ffi::RARRAY_LEN(row) != positions.len() as isize {
+                raise(
+                    ffi::rb_eArgError,
+                    format!(
+                        "expected {} values in row {}, got {}",
+                        positions.len(),
+                        index,
+                        ffi::RARRAY_LEN(row)
+                    ),
+                );
+            }
+
+            let build = || {
+                let mut attributes = template.clone();
+                for (i, &position) in positions.iter().enumerate() {
+                    let value = ffi::rb_ary_entry(row, i as isize);
+                    let (_, attr) = attributes.get_index_mut(position).unwrap();
+                    *attr = self.interner.with_value_from_database(ids[i], attr, value);
+                }
+                AttributeSet::new(attributes)
+            };
+            let set = match identity_map {
+                Some((this, ref mut map)) => {
+                    let id = primary_key.map_or(ffi::Qnil, |i| ffi::rb_ary_entry(row, i as isize));
+                    map.fetch(this, id, &ids, build)
+                }
+                None => build().into_ruby(),
+            };
+            ffi::rb_ary_push(result, set);
+        }
+        result
+    }
+
+    /// The attributes of a row of a result with `columns`, the id of each
+    /// column, and the position of its attribute.
+    unsafe fn row_template(
+        &self,
+        columns: ffi::VALUE,
+        additional_types: Option<ffi::VALUE>,
+    ) -> (IndexMap<ffi::ID, Attribute>, Vec<ffi::ID>, Vec<usize>) {
+        let mut template = self.attributes_with(additional_types);
+
+        let ids = (0..ffi::RARRAY_LEN(columns))
+            .map(|i| string_or_symbol_to_id(ffi::rb_ary_entry(columns, i)))
+            .collect::<Vec<_>>();
+        let positions = ids.iter()
+            .enumerate()
+            .map(|(i, &id)| {
+                if !template.contains_key(&id) {
+                    let name = ffi::rb_ary_entry(columns, i as isize);
+                    let ty = self.type_for_unknown_column(name);
+                    template.insert(id, Attribute::uninitialized(name, ty));
+                }
+                template.get_full(&id).unwrap().0
+            })
+            .collect::<Vec<_>>();
+        (template, ids, positions)
+    }
+
+    /// Like `build_rows_from_database`, for raw values which may still be in
+    /// the buffer the adapter read them into. `cells` are the values of every
+    /// row in order, one per column.
+    pub unsafe fn build_rows_from_raw(
+        &self,
+        columns: ffi::VALUE,
+        cells: &[RawValue],
+        additional_types: Option<ffi::VALUE>,
+    ) -> ffi::VALUE {
+        let array_class = ffi::rb_const_get(ffi::rb_cObject, id!("Array"));
+        check_type(columns, ffi::T_ARRAY, array_class);
+
+        let (template, ids, positions) = self.row_template(columns, additional_types);
+        let width = positions.len();
+        if (width == 0 && !cells.is_empty()) || (width != 0 && cells.len() % width != 0) {
+            raise(
+                ffi::rb_eArgError,
+                format!(
+                    "expected a multiple of {} values, got {}",
+                    width,
+                    cells.len()
+                ),
+            );
+        }
+        if width == 0 {
+            return ffi::rb_ary_new();
+        }
+
+        let result = ffi::rb_ary_new_capa((cells.len() / width) as isize);
+        for row in cells.chunks(width) {
+            let mut attributes = template.clone();
+            for (i, (&position, cell)) in positions.iter().zip(row).enumerate() {
+                let (_, attr) = attributes.get_index_mut(position).unwrap();
+                *attr = match *cell {
+                    RawValue::Value(value) => {
+                        self.interner.with_value_from_database(ids[i], attr, value)
+                    }
+                    RawValue::Bytes(ref bytes) => attr.with_bytes_from_database(bytes.clone()),
+                };
+            }
+            ffi::rb_ary_push(result, AttributeSet::new(attributes).into_ruby());
+        }
+        result
+    }
+
+    /// `build_rows_from_raw` for a result which an adapter wrote into one
+    /// `String`. `lengths` has the length of each value in `buffer` in order,
+    /// or `nil` for `NULL`.
+    unsafe fn build_from_buffer(
+        &self,
+        columns: ffi::VALUE,
+        buffer: ffi::VALUE,
+        lengths: ffi::VALUE,
+        additional_types: Option<ffi::VALUE>,
+    ) -> ffi::VALUE {
+        check_type(buffer, ffi::T_STRING, ffi::rb_cString);
+        check_type(lengths, ffi::T_ARRAY, ffi::rb_const_get(ffi::rb_cObject, id!("Array")));
+
+        let (buffer, binary) = buffer_from_ruby(buffer);
+        let mut offset = 0;
+        let cells = (0..ffi::RARRAY_LEN(lengths))
+            .map(|i| {
+                let len = ffi::rb_ary_entry(lengths, i);
+                if ffi::RB_NIL_P(len) {
+                    return RawValue::Value(ffi::Qnil);
+                }
+                let len = protect(|| ffi::NUM2I64(len));
+                if len < 0 || offset + len as usize > buffer.len() {
+                    raise(
+                        ffi::rb_eArgError,
+                        format!("value {} is out of bounds of the buffer", i),
+                    );
+                }
+                let bytes = RawBytes::new(&buffer, offset, len as usize, binary);
+                offset += len as usize;
+                RawValue::Bytes(bytes)
+            })
+            .collect::<Vec<_>>();
+        if offset != buffer.len() {
+            raise(
+                ffi::rb_eArgError,
+                format!(
+                    "the buffer has {} bytes, but the lengths add up to {}",
+                    buffer.len(),
+                    offset
+                ),
+            );
+        }
+
+        self.build_rows_from_raw(columns, &cells, additional_types)
+    }
+
+    /// The rows of a result with each value cast as `build_rows_from_database`
+    /// would cast it, but without building any attributes, for `pluck`.
+    /// Every row is an array, even when there is only one column.
+    unsafe fn cast_rows(
+        &self,
+        columns: ffi::VALUE,
+        rows: ffi::VALUE,
+        additional_types: Option<ffi::VALUE>,
+    ) -> ffi::VALUE {
+        let array_class = ffi::rb_const_get(ffi::rb_cObject, id!("Array"));
+        check_type(columns, ffi::T_ARRAY, array_class);
+        check_type(rows, ffi::T_ARRAY, array_class);
+
+        let (template, _, positions) = self.row_template(columns, additional_types);
+        let types = positions
+            .iter()
+            .map(|&position| template.get_index(position).unwrap().1.ty())
+            .collect::<Vec<_>>();
+
+        let len = ffi::RARRAY_LEN(rows);
+        let result = ffi::rb_ary_new_capa(len);
+        for index in 0..len {
+            let row = ffi::rb_ary_entry(rows, index);
+            check_type(row, ffi::T_ARRAY, array_class);
+            if ffi::RARRAY_LEN(row) != types.len() as isize {
+                raise(
+                    ffi::rb_eArgError,
+                    format!(
+                        "expected {} values in row {}, got {}",
+                        types.len(),
+                        index,
+                        ffi::RARRAY_LEN(row)
+                    ),
+                );
+            }
+
+            let values = ffi::rb_ary_new_capa(types.len() as isize);
+            ffi::rb_ary_push(result, values);
+            for (i, &ty) in types.iter().enumerate() {
+                let value = ffi::rb_ary_entry(row, i as isize);
+                ffi::rb_ary_push(values, deserialize(ty, value));
+            }
+        }
+        result
+    }
+
+    /// Builds a set for each remaining row of a `SQLite3::Statement`, with
+    /// the values SQLite returns as the raw values.
+    unsafe fn build_from_sqlite3(
+        &self,
+        statement: ffi::VALUE,
+        additional_types: Option<ffi::VALUE>,
+    ) -> ffi::VALUE {
+        let rows = sqlite3::read_statement(statement);
+        let result = self.build_rows_from_raw(rows.columns, &rows.cells, additional_types);
+        gc_guard(&rows.keep_alive);
+        result
+    }
+
+    fn push_value(
+        &self,
+        hash: &mut IndexMap<ffi::ID, Attribute>,
+        key: ffi::VALUE,
+        value: ffi::VALUE,
+    ) {
+        let id = string_or_symbol_to_id(key);
+
+        let new_attr = if let Some(attr) = hash.get(&id) {
+            self.interner.with_value_from_database(id, attr, value)
+        } else {
+            Attribute::from_database(key, value, self.type_for_unknown_column(key))
+        };
+
+        hash.insert(id, new_attr);
+    }
+
+    /// Raises or reports an unknown column if we're strict, and returns the
+    /// fallback type. Each column is only reported the first time we see it.
+    pub fn type_for_unknown_column(&self, name: ffi::VALUE) -> ffi::VALUE {
         unsafe {
-            if let Some(types) = additional_types {
-                ffi::rb_hash_foreach(
-                    types,
-                    push_uninitialized_value,
-                    &mut attributes as *mut _ as *mut _,
+            match self.unknown_columns {
+                UnknownColumns::Allow => {}
+                UnknownColumns::Raise => {
+                    let name = protect(|| ffi::rb_funcall(name, id!("to_s"), 0));
+                    raise(
+                        unknown_column_error(),
+                        format!(
+                            "the database returned the column `{}`, which has no type",
+                            to_rust_string(name)
+                        ),
+                    );
+                }
+                UnknownColumns::Report(callback) => {
+                    let id = string_or_symbol_to_id(name);
+                    if !self.reported_columns.borrow().contains(&id) {
+                        self.reported_columns.borrow_mut().push(id);
+                        protect(|| ffi::rb_funcall(callback, id!("call"), 1, name));
+                    }
+                }
+            }
+        }
+
+        if let Some(ty) = self.fallback_type.get() {
+            return ty;
+        }
+        let ty = default_fallback_type();
+        self.fallback_type.set(Some(ty));
+        ty
+    }
+
+    /// Builds one attribute set per row of `rows`, which are arrays of values
+    /// in the order of `columns`, assigned as if by the user. Every value is
+    /// validated and cast up front, and rather than raising, errors are
+    /// collected as `RowError`s. Rows which had an error are `nil` in the
+    /// returned sets.
+    unsafe fn build_from_user_rows(&self, rows: ffi::VALUE, columns: ffi::VALUE) -> ffi::VALUE {
+        let array_class = ffi::rb_const_get(ffi::rb_cObject, id!("Array"));
+        check_type(rows, ffi::T_ARRAY, array_class);
+        check_type(columns, ffi::T_ARRAY, array_class);
+
+        let columns = (0..ffi::RARRAY_LEN(columns))
+            .map(|i| {
+                let name = ffi::rb_ary_entry(columns, i);
+                let id = string_or_symbol_to_id(name);
+                if !self.uninitialized_attributes.contains_key(&id) {
+                    missing_attribute(name);
+                }
+                id
+            })
+            .collect::<Vec<_>>();
+
+        let row_error = protect(|| ffi::rb_const_get(::module(), id!("RowError")));
+        let len = ffi::RARRAY_LEN(rows);
+        let sets = ffi::rb_ary_new_capa(len);
+        let errors = ffi::rb_ary_new();
+        let push_error = |index: isize, column: ffi::VALUE, error: ffi::VALUE| {
+            let index = ffi::I642NUM(index as i64);
+            let error = protect(|| ffi::rb_funcall(row_error, id!("new"), 3, index, column, error));
+            ffi::rb_ary_push(errors, error);
+        };
+
+        for index in 0..len {
+            let row = ffi::rb_ary_entry(rows, index);
+            check_type(row, ffi::T_ARRAY, array_class);
+            if ffi::RARRAY_LEN(row) != columns.len() as isize {
+                let message = format!(
+                    "expected {} values, got {}",
+                    columns.len(),
+                    ffi::RARRAY_LEN(row)
                 );
+                let error = protect(|| {
+                    ffi::rb_funcall(ffi::rb_eArgError, id!("new"), 1, rstr!(message))
+                });
+                push_error(index, ffi::Qnil, error);
+                ffi::rb_ary_push(sets, ffi::Qnil);
+                continue;
+            }
+
+            // Cast values are only referenced from Rust until the set is
+            // wrapped, so keep them somewhere the GC can see them.
+            let keep_alive = ffi::rb_ary_new_capa(columns.len() as isize);
+            let mut attributes = self.uninitialized_attributes.clone();
+            let mut valid = true;
+            for (i, id) in columns.iter().enumerate() {
+                let value = ffi::rb_ary_entry(row, i as isize);
+                let attr = attributes[id].clone();
+                let name = attr.name();
+                let result = rescue(|| {
+                    let attr = attr.with_value_from_user(value);
+                    ffi::rb_ary_push(keep_alive, attr.value());
+                    attr
+                });
+                match result {
+                    Ok(attr) => {
+                        attributes.insert(*id, attr);
+                    }
+                    Err(error) => {
+                        push_error(index, name, error);
+                        valid = false;
+                    }
+                }
             }
 
-            ffi::rb_hash_foreach(values, push_value, &mut attributes as *mut _ as *mut _);
+            if valid {
+                ffi::rb_ary_push(sets, AttributeSet::new(attributes).into_ruby());
+            } else {
+                ffi::rb_ary_push(sets, ffi::Qnil);
+            }
+            gc_guard(&keep_alive);
         }
 
-        AttributeSet::new(attributes)
+        let result = ffi::rb_ary_new_capa(2);
+        ffi::rb_ary_push(result, sets);
+        ffi::rb_ary_push(result, errors);
+        result
+    }
+}
+
+fn missing_attribute(name: ffi::VALUE) -> ! {
+    unsafe {
+        let name = protect(|| ffi::rb_funcall(name, id!("to_s"), 0));
+        raise(
+            ffi::rb_eArgError,
+            format!("unknown attribute `{}`", to_rust_string(name)),
+        )
     }
 }
 
 pub unsafe fn init() {
+    let error = ffi::rb_define_class_under(
+        ::module(),
+        cstr!("UnknownColumnError"),
+        ffi::rb_eStandardError,
+    );
+    UNKNOWN_COLUMN_ERROR = Some(error);
     self::ruby_glue::init();
 }
 
-extern "C" fn push_uninitialized_value(
+fn push_uninitialized_value(
+    hash: &mut IndexMap<ffi::ID, Attribute>,
     key: ffi::VALUE,
     value: ffi::VALUE,
-    hash_ptr: *mut libc::c_void,
-) -> ffi::st_retval {
-    let hash_ptr = hash_ptr as *mut IndexMap<ffi::ID, Attribute>;
-    let hash = unsafe { hash_ptr.as_mut().unwrap() };
-
+) {
     let id = string_or_symbol_to_id(key);
     let attribute = Attribute::uninitialized(key, value);
 
     hash.insert(id, attribute);
-
-    ffi::st_retval::ST_CONTINUE
 }
 
-extern "C" fn push_value(
-    key: ffi::VALUE,
-    value: ffi::VALUE,
-    data_ptr: *mut libc::c_void,
-) -> ffi::st_retval {
-    let data_ptr = data_ptr as *mut IndexMap<ffi::ID, Attribute>;
-    let hash = unsafe { data_ptr.as_mut().unwrap() };
-
-    let id = string_or_symbol_to_id(key);
-
-    let new_attr = if let Some(attr) = hash.get(&id) {
-        attr.with_value_from_database(value)
-    } else {
-        let active_record = unsafe { ffi::rb_const_get(ffi::rb_cObject, id!("ActiveRecord")) };
-        let type_module = unsafe { ffi::rb_const_get(active_record, id!("Type")) };
-        let ty = unsafe { ffi::rb_funcall(type_module, id!("default_value"), 0) };
-        Attribute::from_database(key, value, ty)
-    };
-
-    hash.insert(id, new_attr);
+/// `ActiveRecord::Type.default_value` when ActiveRecord is loaded, so that
+/// adapters can override it, and otherwise `ActiveModel::Type.default_value`.
+fn default_fallback_type() -> ffi::VALUE {
+    unsafe {
+        protect(|| {
+            let namespace = if ffi::RTEST(ffi::rb_funcall(
+                ffi::rb_cObject,
+                id!("const_defined?"),
+                1,
+                rstr!("ActiveRecord::Type"),
+            )) {
+                ffi::rb_const_get(ffi::rb_cObject, id!("ActiveRecord"))
+            } else {
+                ffi::rb_const_get(ffi::rb_cObject, id!("ActiveModel"))
+            };
+            let type_module = ffi::rb_const_get(namespace, id!("Type"));
+            ffi::rb_funcall(type_module, id!("default_value"), 0)
+        })
+    }
+}
 
-    ffi::st_retval::ST_CONTINUE
+fn unknown_column_error() -> ffi::VALUE {
+    unsafe { UNKNOWN_COLUMN_ERROR }.unwrap()
 }
 
-extern "C" fn push_attribute(
-    key: ffi::VALUE,
-    value: ffi::VALUE,
-    data_ptr: *mut libc::c_void,
-) -> ffi::st_retval {
-    let data_ptr = data_ptr as *mut IndexMap<ffi::ID, Attribute>;
-    let hash = unsafe { data_ptr.as_mut().unwrap() };
+static mut UNKNOWN_COLUMN_ERROR: Option<ffi::VALUE> = None;
 
+fn push_attribute(hash: &mut IndexMap<ffi::ID, Attribute>, key: ffi::VALUE, value: ffi::VALUE) {
     let id = string_or_symbol_to_id(key);
     let attr = unsafe { get_struct::<Attribute>(value) };
 
     hash.insert(id, attr.without_cast_value());
-
-    ffi::st_retval::ST_CONTINUE
 }
@@ -1,6 +1,8 @@
 use {ffi, libc};
+use identity_map::IdentityMap;
 use into_ruby::{Allocate, IntoRuby};
-use super::Builder;
+use protect::{protect, ruby_boundary};
+use super::{marshal, Builder, UnknownColumns};
 use util::*;
 
 impl IntoRuby for Builder {
@@ -14,6 +16,23 @@ impl IntoRuby for Builder {
             ffi::rb_gc_mark(sym);
             value.mark();
         }
+        for schema in self.merged_schemas.borrow().iter() {
+            ffi::rb_gc_mark(schema.additional_types);
+            for &(key, value) in &schema.entries {
+                ffi::rb_gc_mark(key);
+                ffi::rb_gc_mark(value);
+            }
+            for attr in schema.attributes.values() {
+                attr.mark();
+            }
+        }
+        if let Some(ty) = self.fallback_type.get() {
+            ffi::rb_gc_mark(ty);
+        }
+        if let UnknownColumns::Report(callback) = self.unknown_columns {
+            ffi::rb_gc_mark(callback);
+        }
+        self.interner.mark();
     }
 }
 
@@ -32,6 +51,55 @@ pub unsafe fn init() {
         build_from_database as *const _,
         -1,
     );
+    ffi::rb_define_method(
+        builder,
+        cstr!("build_from_buffer"),
+        build_from_buffer as *const _,
+        -1,
+    );
+    ffi::rb_define_method(
+        builder,
+        cstr!("build_from_sqlite3"),
+        build_from_sqlite3 as *const _,
+        -1,
+    );
+    ffi::rb_define_method(builder, cstr!("cast_rows"), cast_rows as *const _, -1);
+    ffi::rb_define_method(
+        builder,
+        cstr!("build_from_user_rows"),
+        build_from_user_rows as *const _,
+        2,
+    );
+    ffi::rb_define_method(
+        builder,
+        cstr!("attribute_names"),
+        attribute_names as *const _,
+        0,
+    );
+    ffi::rb_define_method(builder, cstr!("types"), types as *const _, 0);
+    ffi::rb_define_method(
+        builder,
+        cstr!("default_attributes"),
+        default_attributes as *const _,
+        0,
+    );
+    ffi::rb_define_method(
+        builder,
+        cstr!("with_additional_types"),
+        with_additional_types as *const _,
+        1,
+    );
+    ffi::rb_define_method(
+        builder,
+        cstr!("build_defaults"),
+        build_defaults as *const _,
+        0,
+    );
+    ffi::rb_define_method(builder, cstr!("=="), equals as *const _, 1);
+    ffi::rb_define_method(builder, cstr!("eql?"), equals as *const _, 1);
+    ffi::rb_define_method(builder, cstr!("hash"), hash as *const _, 0);
+    ffi::rb_define_method(builder, cstr!("_dump_data"), dump_data as *const _, 0);
+    ffi::rb_define_method(builder, cstr!("_load_data"), load_data as *const _, 1);
 }
 
 extern "C" fn initialize(
@@ -39,45 +107,257 @@ extern "C" fn initialize(
     argv: *const ffi::VALUE,
     this: ffi::VALUE,
 ) -> ffi::VALUE {
-    unsafe {
-        let mut types = ffi::Qnil;
-        let mut default_attributes = ffi::Qnil;
-        ffi::rb_scan_args(argc, argv, cstr!("11"), &mut types, &mut default_attributes);
+    ruby_boundary(|| {
+        unsafe {
+            let mut types = ffi::Qnil;
+            let mut default_attributes = ffi::Qnil;
+            let mut options = ffi::Qnil;
+            protect(|| {
+                ffi::rb_scan_args(
+                    argc,
+                    argv,
+                    cstr!("12"),
+                    &mut types,
+                    &mut default_attributes,
+                    &mut options,
+                )
+            });
 
-        let default_attributes = if ffi::RB_NIL_P(default_attributes) {
-            None
-        } else {
-            Some(default_attributes)
-        };
-
-        let this = get_struct_mut::<Builder>(this);
-        this.initialize(types, default_attributes);
-    }
-    this
+            let optional = |value| if ffi::RB_NIL_P(value) { None } else { Some(value) };
+            let this = get_struct_mut::<Builder>(this);
+            this.initialize(types, optional(default_attributes), optional(options));
+        }
+        this
+    })
 }
 
 extern "C" fn build_from_database(
     argc: libc::c_int,
     argv: *const ffi::VALUE,
-    this: ffi::VALUE,
+    this_ptr: ffi::VALUE,
 ) -> ffi::VALUE {
-    unsafe {
-        let this = get_struct::<Builder>(this);
+    ruby_boundary(|| unsafe {
+        let this = get_struct::<Builder>(this_ptr);
         let mut values = ffi::Qnil;
+        let mut rows = ffi::Qnil;
         let mut additional_types = ffi::Qnil;
-        ffi::rb_scan_args(argc, argv, cstr!("02"), &mut values, &mut additional_types);
+        let mut identity_map = ffi::Qnil;
+        protect(|| {
+            ffi::rb_scan_args(
+                argc,
+                argv,
+                cstr!("04"),
+                &mut values,
+                &mut rows,
+                &mut additional_types,
+                &mut identity_map,
+            )
+        });
+
+        let optional = |value| if ffi::RB_NIL_P(value) { None } else { Some(value) };
+
+        // `build_from_database(columns, rows, additional_types = nil, identity_map = nil)`.
+        // A lone Array falls through to the Hash form, which rejects it.
+        if argc >= 2 && ffi::RB_TYPE_P(values, ffi::T_ARRAY) {
+            let identity_map = optional(identity_map)
+                .map(|map| (this_ptr, get_struct_mut::<IdentityMap>(map)));
+            return this.build_rows_from_database(
+                values,
+                rows,
+                optional(additional_types),
+                identity_map,
+            );
+        }
 
+        // `build_from_database(values = {}, additional_types = nil, identity_map = nil)`
+        if argc > 3 {
+            raise(
+                ffi::rb_eArgError,
+                format!("wrong number of arguments (given {}, expected 0..3)", argc),
+            );
+        }
+        let (additional_types, identity_map) = (rows, additional_types);
         if ffi::RB_NIL_P(values) {
             values = ffi::rb_hash_new();
         }
 
+        match optional(identity_map) {
+            Some(map) => this.build_identity_mapped(
+                this_ptr,
+                get_struct_mut::<IdentityMap>(map),
+                values,
+                optional(additional_types),
+            ),
+            None => this.build_from_database(values, optional(additional_types))
+                .into_ruby(),
+        }
+    })
+}
+
+extern "C" fn build_from_buffer(
+    argc: libc::c_int,
+    argv: *const ffi::VALUE,
+    this: ffi::VALUE,
+) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        let this = get_struct::<Builder>(this);
+        let mut columns = ffi::Qnil;
+        let mut buffer = ffi::Qnil;
+        let mut lengths = ffi::Qnil;
+        let mut additional_types = ffi::Qnil;
+        protect(|| {
+            ffi::rb_scan_args(
+                argc,
+                argv,
+                cstr!("31"),
+                &mut columns,
+                &mut buffer,
+                &mut lengths,
+                &mut additional_types,
+            )
+        });
+
         let additional_types = if ffi::RB_NIL_P(additional_types) {
             None
         } else {
             Some(additional_types)
         };
+        this.build_from_buffer(columns, buffer, lengths, additional_types)
+    })
+}
 
-        this.build_from_database(values, additional_types)
-            .into_ruby()
-    }
+extern "C" fn build_from_sqlite3(
+    argc: libc::c_int,
+    argv: *const ffi::VALUE,
+    this: ffi::VALUE,
+) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        let this = get_struct::<Builder>(this);
+        let mut statement = ffi::Qnil;
+        let mut additional_types = ffi::Qnil;
+        protect(|| {
+            ffi::rb_scan_args(
+                argc,
+                argv,
+                cstr!("11"),
+                &mut statement,
+                &mut additional_types,
+            )
+        });
+
+        let additional_types = if ffi::RB_NIL_P(additional_types) {
+            None
+        } else {
+            Some(additional_types)
+        };
+        this.build_from_sqlite3(statement, additional_types)
+    })
+}
+
+extern "C" fn cast_rows(
+    argc: libc::c_int,
+    argv: *const ffi::VALUE,
+    this: ffi::VALUE,
+) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        let this = get_struct::<Builder>(this);
+        let mut columns = ffi::Qnil;
+        let mut rows = ffi::Qnil;
+        let mut additional_types = ffi::Qnil;
+        protect(|| {
+            ffi::rb_scan_args(
+                argc,
+                argv,
+                cstr!("21"),
+                &mut columns,
+                &mut rows,
+                &mut additional_types,
+            )
+        });
+
+        let additional_types = if ffi::RB_NIL_P(additional_types) {
+            None
+        } else {
+            Some(additional_types)
+        };
+        this.cast_rows(columns, rows, additional_types)
+    })
+}
+
+extern "C" fn build_from_user_rows(
+    this: ffi::VALUE,
+    rows: ffi::VALUE,
+    columns: ffi::VALUE,
+) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        let this = get_struct::<Builder>(this);
+        this.build_from_user_rows(rows, columns)
+    })
+}
+
+extern "C" fn attribute_names(this: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Builder>(this) };
+        this.attribute_names()
+    })
+}
+
+extern "C" fn types(this: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Builder>(this) };
+        this.types()
+    })
+}
+
+extern "C" fn default_attributes(this: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Builder>(this) };
+        this.default_attributes()
+    })
+}
+
+extern "C" fn with_additional_types(this: ffi::VALUE, types: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        let this = get_struct::<Builder>(this);
+        this.with_additional_types(types).into_ruby()
+    })
+}
+
+extern "C" fn build_defaults(this: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Builder>(this) };
+        this.build_defaults()
+    })
+}
+
+extern "C" fn equals(this: ffi::VALUE, other: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        if !is_instance_of::<Builder>(other) {
+            return ffi::Qfalse;
+        }
+        let this = get_struct::<Builder>(this);
+        let other = get_struct::<Builder>(other);
+        to_ruby_bool(this.equals(other))
+    })
+}
+
+extern "C" fn hash(this: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Builder>(this) };
+        this.hash()
+    })
+}
+
+extern "C" fn dump_data(this: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| {
+        let this = unsafe { get_struct::<Builder>(this) };
+        marshal::dump(this)
+    })
+}
+
+extern "C" fn load_data(this: ffi::VALUE, data: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        marshal::load(get_struct_mut::<Builder>(this), data);
+        ffi::Qnil
+    })
 }
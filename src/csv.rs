@@ -0,0 +1,100 @@
+//! RFC 4180 CSV output. Fields are converted with `to_s` like Ruby's CSV
+//! library does, with fast paths for the common cases.
+
+use ffi;
+use protect::protect;
+use util::*;
+
+pub struct Writer {
+    buf: Vec<u8>,
+    at_row_start: bool,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Writer {
+            buf: Vec::new(),
+            at_row_start: true,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Returns everything written so far as a Ruby String, leaving the
+    /// writer empty.
+    pub fn take_string(&mut self) -> ffi::VALUE {
+        let result =
+            unsafe { ffi::rb_utf8_str_new(self.buf.as_ptr() as *const _, self.buf.len() as _) };
+        self.buf.clear();
+        result
+    }
+
+    /// Writes `field`, quoted if it's empty so that it can be told apart
+    /// from `nil`, as Ruby's CSV library does.
+    pub fn write_field(&mut self, field: &[u8]) {
+        self.start_field();
+
+        let needs_quotes = field.is_empty() || field
+            .iter()
+            .any(|&b| b == b',' || b == b'"' || b == b'\r' || b == b'\n');
+        if needs_quotes {
+            self.buf.push(b'"');
+            for &byte in field {
+                if byte == b'"' {
+                    self.buf.push(b'"');
+                }
+                self.buf.push(byte);
+            }
+            self.buf.push(b'"');
+        } else {
+            self.buf.extend_from_slice(field);
+        }
+    }
+
+    fn write_nil(&mut self) {
+        self.start_field();
+    }
+
+    fn start_field(&mut self) {
+        if !self.at_row_start {
+            self.buf.push(b',');
+        }
+        self.at_row_start = false;
+    }
+
+    /// Writes `value.to_s` encoded as UTF-8, or an empty field for `nil`.
+    pub fn write_value(&mut self, value: ffi::VALUE) {
+        unsafe {
+            if ffi::RB_NIL_P(value) {
+                self.write_nil();
+            } else if value == ffi::Qtrue {
+                self.write_field(b"true");
+            } else if value == ffi::Qfalse {
+                self.write_field(b"false");
+            } else if ffi::RB_TYPE_P(value, ffi::T_STRING) {
+                let string = to_utf8(value);
+                self.write_field(string_bytes(string));
+                gc_guard(&string);
+            } else if ffi::RB_TYPE_P(value, ffi::T_FIXNUM) {
+                self.write_field(ffi::NUM2I64(value).to_string().as_bytes());
+            } else if ffi::RB_TYPE_P(value, ffi::T_FLOAT) {
+                self.write_field(float_to_s(ffi::NUM2F64(value)).as_bytes());
+            } else if ffi::RB_TYPE_P(value, ffi::T_SYMBOL) {
+                self.write_field(string_bytes(ffi::rb_id2str(ffi::rb_sym2id(value))));
+            } else {
+                let string = protect(|| ffi::rb_funcall(value, id!("to_s"), 0));
+                check_type(string, ffi::T_STRING, ffi::rb_cString);
+                let string = to_utf8(string);
+                self.write_field(string_bytes(string));
+                gc_guard(&string);
+            }
+        }
+    }
+
+    pub fn end_row(&mut self) {
+        self.buf.extend_from_slice(b"\r\n");
+        self.at_row_start = true;
+    }
+}
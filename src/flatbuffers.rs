@@ -0,0 +1,180 @@
+//! Just enough of a FlatBuffers encoder to write Arrow IPC metadata.
+//!
+//! Unlike the official builder, which writes back to front, this lays out
+//! each table before its children, since forward offsets are all FlatBuffers
+//! requires. Every value is aligned relative to the start of the buffer, so
+//! the result must be written at an 8 byte aligned position.
+
+#[derive(Clone)]
+pub enum Value {
+    U8(u8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    Table(Table),
+    String(Vec<u8>),
+    Tables(Vec<Table>),
+    /// A vector of structs, with their already encoded little endian bytes
+    Structs {
+        len: usize,
+        alignment: usize,
+        bytes: Vec<u8>,
+    },
+}
+
+impl Value {
+    fn inline_size(&self) -> usize {
+        match *self {
+            Value::U8(_) => 1,
+            Value::I16(_) => 2,
+            Value::I32(_) => 4,
+            Value::I64(_) => 8,
+            _ => 4,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Table {
+    fields: Vec<Option<Value>>,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the field with the given id, as numbered in the schema. Union
+    /// fields take up two ids, the type followed by the value.
+    pub fn field(mut self, id: usize, value: Value) -> Self {
+        if self.fields.len() <= id {
+            self.fields.resize(id + 1, None);
+        }
+        self.fields[id] = Some(value);
+        self
+    }
+}
+
+/// Encodes `root` as a complete FlatBuffer, padded to a multiple of 8 bytes.
+pub fn finish(root: &Table) -> Vec<u8> {
+    let mut serializer = Serializer { buf: vec![0; 4] };
+    let root_pos = serializer.write_table(root);
+    serializer.patch_offset(0, root_pos);
+    serializer.align(8);
+    serializer.buf
+}
+
+struct Serializer {
+    buf: Vec<u8>,
+}
+
+impl Serializer {
+    fn align(&mut self, alignment: usize) {
+        while self.buf.len() % alignment != 0 {
+            self.buf.push(0);
+        }
+    }
+
+    fn patch(&mut self, at: usize, bytes: &[u8]) {
+        self.buf[at..at + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn patch_offset(&mut self, at: usize, target: usize) {
+        let offset = (target - at) as u32;
+        self.patch(at, &le_bytes(u64::from(offset), 4));
+    }
+
+    fn write_table(&mut self, table: &Table) -> usize {
+        self.align(2);
+        let vtable_pos = self.buf.len();
+        let vtable_len = 4 + 2 * table.fields.len();
+        self.buf.resize(vtable_pos + vtable_len, 0);
+
+        let mut present = table.fields
+            .iter()
+            .enumerate()
+            .filter_map(|(id, field)| field.as_ref().map(|value| (id, value)))
+            .collect::<Vec<_>>();
+        present.sort_by_key(|&(_, value)| ::std::cmp::Reverse(value.inline_size()));
+
+        self.align(present.first().map(|&(_, v)| v.inline_size().max(4)).unwrap_or(4));
+        let table_pos = self.buf.len();
+        self.buf.extend_from_slice(&[0; 4]);
+
+        let mut children = Vec::new();
+        for &(id, value) in &present {
+            self.align(value.inline_size());
+            let field_pos = self.buf.len();
+            let field_offset = (field_pos - table_pos) as u64;
+            self.patch(vtable_pos + 4 + 2 * id, &le_bytes(field_offset, 2));
+            match *value {
+                Value::U8(v) => self.buf.push(v),
+                Value::I16(v) => self.buf.extend_from_slice(&le_bytes(v as u64, 2)),
+                Value::I32(v) => self.buf.extend_from_slice(&le_bytes(v as u64, 4)),
+                Value::I64(v) => self.buf.extend_from_slice(&le_bytes(v as u64, 8)),
+                _ => {
+                    self.buf.extend_from_slice(&[0; 4]);
+                    children.push((field_pos, value));
+                }
+            }
+        }
+
+        let table_len = (self.buf.len() - table_pos) as u64;
+        self.patch(vtable_pos, &le_bytes(vtable_len as u64, 2));
+        self.patch(vtable_pos + 2, &le_bytes(table_len, 2));
+        self.patch(table_pos, &le_bytes((table_pos - vtable_pos) as u64, 4));
+
+        for (field_pos, value) in children {
+            let child_pos = self.write_child(value);
+            self.patch_offset(field_pos, child_pos);
+        }
+        table_pos
+    }
+
+    fn write_child(&mut self, value: &Value) -> usize {
+        match *value {
+            Value::Table(ref table) => self.write_table(table),
+            Value::String(ref bytes) => {
+                self.align(4);
+                let pos = self.buf.len();
+                self.buf.extend_from_slice(&le_bytes(bytes.len() as u64, 4));
+                self.buf.extend_from_slice(bytes);
+                self.buf.push(0);
+                pos
+            }
+            Value::Tables(ref tables) => {
+                self.align(4);
+                let pos = self.buf.len();
+                self.buf.extend_from_slice(&le_bytes(tables.len() as u64, 4));
+                let elements_pos = self.buf.len();
+                self.buf.resize(elements_pos + 4 * tables.len(), 0);
+                for (i, table) in tables.iter().enumerate() {
+                    let table_pos = self.write_table(table);
+                    self.patch_offset(elements_pos + 4 * i, table_pos);
+                }
+                pos
+            }
+            Value::Structs {
+                len,
+                alignment,
+                ref bytes,
+            } => {
+                // The elements must be aligned, not the length before them
+                self.align(4);
+                while (self.buf.len() + 4) % alignment != 0 {
+                    self.buf.push(0);
+                }
+                let pos = self.buf.len();
+                self.buf.extend_from_slice(&le_bytes(len as u64, 4));
+                self.buf.extend_from_slice(bytes);
+                pos
+            }
+            _ => unreachable!("scalars are written inline"),
+        }
+    }
+}
+
+/// The low `len` bytes of `value`, little endian.
+pub fn le_bytes(value: u64, len: usize) -> Vec<u8> {
+    (0..len).map(|i| (value >> (i * 8)) as u8).collect()
+}
@@ -0,0 +1,150 @@
+use attribute::Attribute;
+use attribute_set::AttributeSet;
+use ffi;
+use into_ruby::IntoRuby;
+use protect::protect;
+use util::*;
+
+mod ruby_glue;
+
+extern "C" {
+    fn rb_hash_lookup2(hash: ffi::VALUE, key: ffi::VALUE, default: ffi::VALUE) -> ffi::VALUE;
+    fn rb_hash_clear(hash: ffi::VALUE) -> ffi::VALUE;
+}
+
+/// The records loaded during one unit of work, such as a request. Loading a
+/// row which is already in the map returns a copy of the set we built for it
+/// the first time, rather than building (and casting) it all over again.
+///
+/// Rows are looked up by the `Builder` they were built by and the raw value
+/// of their primary key, and are only reused when they were loaded with the
+/// same columns and raw values. Otherwise the row changed in the database
+/// since, and the new set replaces the old one in the map.
+#[derive(Default)]
+pub struct IdentityMap {
+    primary_key: Option<ffi::ID>,
+    /// `{ builder => { primary key => index into entries } }`, where builders
+    /// are compared by identity
+    index: Option<ffi::VALUE>,
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    columns: Vec<ffi::ID>,
+    /// The set as it was loaded from the database
+    loaded: AttributeSet,
+    /// The set we returned the first time the row was loaded
+    set: ffi::VALUE,
+}
+
+impl IdentityMap {
+    unsafe fn initialize(&mut self, primary_key: ffi::VALUE) {
+        let index = ffi::rb_hash_new();
+        protect(|| ffi::rb_funcall(index, id!("compare_by_identity"), 0));
+        self.primary_key = Some(string_or_symbol_to_id(primary_key));
+        self.index = Some(index);
+        self.entries.clear();
+    }
+
+    pub fn primary_key(&self) -> ffi::ID {
+        self.primary_key
+            .unwrap_or_else(|| raise(unsafe { ffi::rb_eRuntimeError }, "uninitialized IdentityMap"))
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn clear(&mut self) {
+        if let Some(index) = self.index {
+            protect(|| unsafe { rb_hash_clear(index) });
+        }
+        self.entries.clear();
+    }
+
+    /// Returns a set for the row of `builder` with the given primary key and
+    /// `columns`. The set from `build` is only returned when the map doesn't
+    /// already have the row with the same raw values. Rows without a primary
+    /// key are never mapped.
+    pub unsafe fn fetch<F>(
+        &mut self,
+        builder: ffi::VALUE,
+        id: ffi::VALUE,
+        columns: &[ffi::ID],
+        build: F,
+    ) -> ffi::VALUE
+    where
+        F: FnOnce() -> AttributeSet,
+    {
+        if ffi::RB_NIL_P(id) {
+            return build().into_ruby();
+        }
+
+        self.primary_key();
+        let index = self.index.unwrap();
+        let mut rows = rb_hash_lookup2(index, builder, ffi::Qnil);
+        if ffi::RB_NIL_P(rows) {
+            rows = ffi::rb_hash_new();
+            protect(|| ffi::rb_hash_aset(index, builder, rows));
+        }
+
+        let position = rb_hash_lookup2(rows, id, ffi::Qnil);
+        let position = if ffi::RB_NIL_P(position) {
+            None
+        } else {
+            Some(ffi::NUM2U64(position) as usize)
+        };
+        // Building doesn't cast anything, so this is much cheaper than what
+        // we save by reusing the entry.
+        let loaded = build();
+        if let Some(position) = position {
+            let entry = &self.entries[position];
+            if entry.columns == columns && entry.loaded.attributes() == loaded.attributes() {
+                return entry.copy().into_ruby();
+            }
+        }
+
+        let set = loaded.clone().into_ruby();
+        let entry = Entry {
+            columns: columns.to_vec(),
+            loaded,
+            set,
+        };
+        match position {
+            Some(position) => self.entries[position] = entry,
+            None => {
+                self.entries.push(entry);
+                let position = ffi::U642NUM(self.entries.len() as u64 - 1);
+                protect(|| ffi::rb_hash_aset(rows, id, position));
+            }
+        }
+        set
+    }
+}
+
+impl Entry {
+    /// A copy of the set we returned the first time. Attributes keep the
+    /// value they were last loaded or saved with, so changes which were never
+    /// saved don't carry over, but values it has already cast do. Like
+    /// `Builder#build_defaults`, cast values which can be mutated are
+    /// duplicated, so the copies never see each other's changes.
+    unsafe fn copy(&self) -> AttributeSet {
+        let current = get_struct::<AttributeSet>(self.set).attributes();
+        let attributes = self.loaded
+            .attributes()
+            .iter()
+            .map(|(&key, loaded)| {
+                let attr = current
+                    .get(&key)
+                    .and_then(Attribute::database_attribute)
+                    .unwrap_or(loaded);
+                (key, attr.dup_default())
+            })
+            .collect();
+        AttributeSet::new(attributes)
+    }
+}
+
+pub unsafe fn init() {
+    ruby_glue::init();
+}
@@ -0,0 +1,58 @@
+use ffi;
+use into_ruby::{Allocate, IntoRuby};
+use protect::ruby_boundary;
+use super::IdentityMap;
+use util::*;
+
+impl IntoRuby for IdentityMap {
+    unsafe fn class() -> ffi::VALUE {
+        IDENTITY_MAP.unwrap()
+    }
+
+    unsafe fn mark(&self) {
+        if let Some(index) = self.index {
+            ffi::rb_gc_mark(index);
+        }
+        for entry in &self.entries {
+            for attr in entry.loaded.attributes().values() {
+                attr.mark();
+            }
+            ffi::rb_gc_mark(entry.set);
+        }
+    }
+}
+
+static mut IDENTITY_MAP: Option<ffi::VALUE> = None;
+
+pub unsafe fn init() {
+    let identity_map =
+        ffi::rb_define_class_under(::module(), cstr!("IdentityMap"), ffi::rb_cObject);
+    IDENTITY_MAP = Some(identity_map);
+
+    ffi::rb_define_alloc_func(identity_map, IdentityMap::allocate);
+
+    ffi::rb_define_method(identity_map, cstr!("initialize"), initialize as *const _, 1);
+    ffi::rb_define_method(identity_map, cstr!("size"), size as *const _, 0);
+    ffi::rb_define_method(identity_map, cstr!("clear"), clear as *const _, 0);
+}
+
+extern "C" fn initialize(this: ffi::VALUE, primary_key: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        get_struct_mut::<IdentityMap>(this).initialize(primary_key);
+        this
+    })
+}
+
+extern "C" fn size(this: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        let this = get_struct::<IdentityMap>(this);
+        ffi::U642NUM(this.len() as u64)
+    })
+}
+
+extern "C" fn clear(this: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| unsafe {
+        get_struct_mut::<IdentityMap>(this).clear();
+        this
+    })
+}
@@ -1,4 +1,5 @@
 use {ffi, libc};
+use protect::{ruby_boundary, silent_boundary};
 use std::mem;
 
 pub trait IntoRuby: Sized {
@@ -7,17 +8,19 @@ pub trait IntoRuby: Sized {
 
     extern "C" fn mark_ptr(this: *mut libc::c_void) {
         let this = this as *mut Self;
-        unsafe {
+        silent_boundary(|| unsafe {
             if let Some(this) = this.as_ref() {
                 this.mark()
             }
-        }
+        })
     }
 
     extern "C" fn destroy_ptr(this: *mut libc::c_void) {
-        if !this.is_null() {
-            let _ = unsafe { Box::from_raw(this as *mut Self) };
-        }
+        silent_boundary(|| {
+            if !this.is_null() {
+                let _ = unsafe { Box::from_raw(this as *mut Self) };
+            }
+        })
     }
 
     fn as_ruby(&'static self) -> ffi::VALUE {
@@ -32,23 +35,22 @@ pub trait IntoRuby: Sized {
     }
 
     fn into_ruby(self) -> ffi::VALUE {
+        unsafe { self.into_ruby_as(Self::class()) }
+    }
+
+    unsafe fn into_ruby_as(self, class: ffi::VALUE) -> ffi::VALUE {
         let ptr = Box::into_raw(Box::new(self));
-        unsafe {
-            ffi::Data_Wrap_Struct(
-                Self::class(),
-                Self::mark_ptr,
-                Self::destroy_ptr,
-                ptr as *mut _,
-            )
-        }
+        ffi::Data_Wrap_Struct(class, Self::mark_ptr, Self::destroy_ptr, ptr as *mut _)
     }
 }
 
 pub trait Allocate: Default + IntoRuby {
     extern "C" fn allocate(class: ffi::VALUE) -> ffi::VALUE {
-        let ptr = Box::into_raw(Box::new(Self::default()));
+        ruby_boundary(|| {
+            let ptr = Box::into_raw(Box::new(Self::default()));
 
-        unsafe { ffi::Data_Wrap_Struct(class, Self::mark_ptr, Self::destroy_ptr, ptr as *mut _) }
+            unsafe { ffi::Data_Wrap_Struct(class, Self::mark_ptr, Self::destroy_ptr, ptr as *mut _) }
+        })
     }
 }
 
@@ -0,0 +1,297 @@
+//! JSON encoding which produces the same output as ActiveSupport's
+//! `as_json` followed by `to_json`, for the values which commonly come out
+//! of a database. Anything else is handed to its own `as_json`.
+
+use std::ffi::CStr;
+
+use ffi;
+use libc;
+use protect::{hash_foreach, protect};
+use util::*;
+
+extern "C" {
+    fn rb_time_timespec(time: ffi::VALUE) -> libc::timespec;
+    fn rb_path2class(path: *const libc::c_char) -> ffi::VALUE;
+    fn rb_const_defined(class: ffi::VALUE, name: ffi::ID) -> libc::c_int;
+}
+
+/// How deeply `as_json` results may nest before we give up, rather than
+/// overflowing the stack on a self referential value.
+const MAX_DEPTH: usize = 1000;
+
+pub struct Encoder {
+    buf: Vec<u8>,
+    escape_html_entities: bool,
+    standard_time_format: bool,
+    time_precision: usize,
+    time: ffi::VALUE,
+    time_with_zone: Option<ffi::VALUE>,
+    big_decimal: Option<ffi::VALUE>,
+}
+
+impl Encoder {
+    /// Creates an encoder using the current settings of
+    /// `ActiveSupport::JSON::Encoding`.
+    pub fn new() -> Self {
+        unsafe {
+            let settings = protect(|| rb_path2class(cstr!("ActiveSupport::JSON::Encoding")));
+            let setting = |name| protect(|| ffi::rb_funcall(settings, name, 0));
+            let precision = setting(id!("time_precision"));
+            Encoder {
+                buf: Vec::new(),
+                escape_html_entities: ffi::RTEST(setting(id!("escape_html_entities_in_json"))),
+                standard_time_format: ffi::RTEST(setting(id!("use_standard_json_time_format"))),
+                time_precision: if ffi::RB_NIL_P(precision) {
+                    3
+                } else {
+                    protect(|| ffi::NUM2I32(precision)).max(0) as usize
+                },
+                time: ffi::rb_const_get(ffi::rb_cObject, id!("Time")),
+                time_with_zone: optional_class(cstr!("ActiveSupport::TimeWithZone")),
+                big_decimal: optional_class(cstr!("BigDecimal")),
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Returns everything written so far as a Ruby String, leaving the
+    /// encoder empty.
+    pub fn take_string(&mut self) -> ffi::VALUE {
+        let result =
+            unsafe { ffi::rb_utf8_str_new(self.buf.as_ptr() as *const _, self.buf.len() as _) };
+        self.buf.clear();
+        result
+    }
+
+    pub fn write_raw(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Writes `key` as an object key, followed by the `:`.
+    pub fn write_key(&mut self, key: ffi::VALUE) {
+        unsafe {
+            let key = if ffi::RB_TYPE_P(key, ffi::T_STRING) {
+                key
+            } else if ffi::RB_TYPE_P(key, ffi::T_SYMBOL) {
+                ffi::rb_id2str(ffi::rb_sym2id(key))
+            } else {
+                protect(|| ffi::rb_funcall(key, id!("to_s"), 0))
+            };
+            self.write_string(key);
+        }
+        self.buf.push(b':');
+    }
+
+    pub fn write_value(&mut self, value: ffi::VALUE) {
+        self.write_nested_value(value, 0)
+    }
+
+    fn write_nested_value(&mut self, value: ffi::VALUE, depth: usize) {
+        if depth > MAX_DEPTH {
+            raise(
+                unsafe { ffi::rb_eArgError },
+                format!("nesting of {} is too deep", depth),
+            );
+        }
+
+        unsafe {
+            if ffi::RB_NIL_P(value) {
+                self.write_raw(b"null");
+            } else if value == ffi::Qtrue {
+                self.write_raw(b"true");
+            } else if value == ffi::Qfalse {
+                self.write_raw(b"false");
+            } else if ffi::RB_TYPE_P(value, ffi::T_FIXNUM) {
+                self.write_raw(ffi::NUM2I64(value).to_string().as_bytes());
+            } else if ffi::RB_TYPE_P(value, ffi::T_BIGNUM) {
+                let string = protect(|| ffi::rb_funcall(value, id!("to_s"), 0));
+                self.write_raw(string_bytes(string));
+            } else if ffi::RB_TYPE_P(value, ffi::T_FLOAT) {
+                self.write_float(ffi::NUM2F64(value));
+            } else if ffi::RB_TYPE_P(value, ffi::T_STRING) {
+                self.write_string(value);
+            } else if ffi::RB_TYPE_P(value, ffi::T_SYMBOL) {
+                self.write_string(ffi::rb_id2str(ffi::rb_sym2id(value)));
+            } else if ffi::RB_TYPE_P(value, ffi::T_ARRAY) {
+                self.buf.push(b'[');
+                for i in 0..ffi::RARRAY_LEN(value) {
+                    if i > 0 {
+                        self.buf.push(b',');
+                    }
+                    self.write_nested_value(ffi::rb_ary_entry(value, i), depth + 1);
+                }
+                self.buf.push(b']');
+            } else if ffi::RB_TYPE_P(value, ffi::T_HASH) {
+                self.buf.push(b'{');
+                let mut first = true;
+                hash_foreach(value, |key, value| {
+                    if !first {
+                        self.buf.push(b',');
+                    }
+                    first = false;
+                    self.write_key(key);
+                    self.write_nested_value(value, depth + 1);
+                });
+                self.buf.push(b'}');
+            } else if self.standard_time_format && self.is_time(value) {
+                self.write_time(value);
+            } else if self.is_big_decimal(value) {
+                let finite = protect(|| ffi::rb_funcall(value, id!("finite?"), 0));
+                if ffi::RTEST(finite) {
+                    self.write_string(protect(|| ffi::rb_funcall(value, id!("to_s"), 0)));
+                } else {
+                    self.write_raw(b"null");
+                }
+            } else {
+                let json = protect(|| ffi::rb_funcall(value, id!("as_json"), 0));
+                self.write_nested_value(json, depth + 1);
+            }
+        }
+    }
+
+    fn write_float(&mut self, value: f64) {
+        if value.is_finite() {
+            self.write_raw(float_to_s(value).as_bytes());
+        } else {
+            self.write_raw(b"null");
+        }
+    }
+
+    unsafe fn write_string(&mut self, value: ffi::VALUE) {
+        let value = to_utf8(value);
+        let string = match ::std::str::from_utf8(string_bytes(value)) {
+            Ok(string) => string,
+            Err(_) => raise(
+                protect(|| rb_path2class(cstr!("JSON::GeneratorError"))),
+                "source sequence is illegal/malformed utf-8",
+            ),
+        };
+
+        self.buf.push(b'"');
+        for c in string.chars() {
+            match c {
+                '"' => self.write_raw(b"\\\""),
+                '\\' => self.write_raw(b"\\\\"),
+                '\u{8}' => self.write_raw(b"\\b"),
+                '\u{c}' => self.write_raw(b"\\f"),
+                '\n' => self.write_raw(b"\\n"),
+                '\r' => self.write_raw(b"\\r"),
+                '\t' => self.write_raw(b"\\t"),
+                '\u{0}'..='\u{1f}' | '\u{2028}' | '\u{2029}' => self.write_unicode_escape(c),
+                '<' | '>' | '&' if self.escape_html_entities => self.write_unicode_escape(c),
+                _ => {
+                    let mut bytes = [0; 4];
+                    self.write_raw(c.encode_utf8(&mut bytes).as_bytes());
+                }
+            }
+        }
+        self.buf.push(b'"');
+    }
+
+    fn write_unicode_escape(&mut self, c: char) {
+        let escaped = format!("\\u{:04x}", c as u32);
+        self.write_raw(escaped.as_bytes());
+    }
+
+    unsafe fn is_time(&self, value: ffi::VALUE) -> bool {
+        is_kind_of(value, self.time)
+            || self.time_with_zone
+                .map(|class| is_kind_of(value, class))
+                .unwrap_or(false)
+    }
+
+    unsafe fn is_big_decimal(&self, value: ffi::VALUE) -> bool {
+        self.big_decimal
+            .map(|class| is_kind_of(value, class))
+            .unwrap_or(false)
+    }
+
+    /// Matches `Time#xmlschema` and `TimeWithZone#xmlschema` with
+    /// `ActiveSupport::JSON::Encoding.time_precision` fractional digits.
+    unsafe fn write_time(&mut self, value: ffi::VALUE) {
+        let offset = protect(|| ffi::rb_funcall(value, id!("utc_offset"), 0));
+        let offset = protect(|| ffi::NUM2I64(offset));
+        // Both write `Z` only for times in UTC itself, not for zones which
+        // happen to have no offset, such as London in winter.
+        let is_utc = ffi::RTEST(protect(|| ffi::rb_funcall(value, id!("utc?"), 0)));
+        let utc = if is_kind_of(value, self.time) {
+            value
+        } else {
+            protect(|| ffi::rb_funcall(value, id!("utc"), 0))
+        };
+        let timespec = protect(|| rb_time_timespec(utc));
+
+        let local = timespec.tv_sec as i64 + offset;
+        let days = local.div_euclid(86_400);
+        let seconds = local.rem_euclid(86_400);
+        let (year, month, day) = civil_from_days(days);
+
+        let mut result = if year < 0 {
+            format!("-{:04}", -year)
+        } else {
+            format!("{:04}", year)
+        };
+        result.push_str(&format!(
+            "-{:02}-{:02}T{:02}:{:02}:{:02}",
+            month,
+            day,
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        ));
+        if self.time_precision > 0 {
+            let nanoseconds = format!("{:09}", timespec.tv_nsec);
+            result.push('.');
+            result.extend(
+                nanoseconds
+                    .chars()
+                    .chain(::std::iter::repeat('0'))
+                    .take(self.time_precision),
+            );
+        }
+        if is_utc {
+            result.push('Z');
+        } else {
+            let sign = if offset < 0 { '-' } else { '+' };
+            let offset = offset.abs();
+            result.push_str(&format!("{}{:02}:{:02}", sign, offset / 3600, offset / 60 % 60));
+        }
+
+        self.buf.push(b'"');
+        self.write_raw(result.as_bytes());
+        self.buf.push(b'"');
+    }
+}
+
+/// Converts days since 1970-01-01 into a proleptic Gregorian date.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+unsafe fn optional_class(path: *const libc::c_char) -> Option<ffi::VALUE> {
+    let mut namespace = ffi::rb_cObject;
+    for name in CStr::from_ptr(path).to_bytes().split(|&b| b == b':') {
+        if name.is_empty() {
+            continue;
+        }
+        let name = ffi::rb_intern_str(ffi::rb_str_new(name.as_ptr() as *const _, name.len() as _));
+        if rb_const_defined(namespace, name) == 0 {
+            return None;
+        }
+        namespace = protect(|| ffi::rb_const_get(namespace, name));
+    }
+    Some(namespace)
+}
@@ -29,10 +29,21 @@ macro_rules! rstr {
     }
 }
 
+pub mod arrow;
 pub mod attribute;
 pub mod attribute_set;
 pub mod builder;
+pub mod csv;
+pub mod flatbuffers;
+pub mod identity_map;
 pub mod into_ruby;
+pub mod json;
+pub mod load;
+pub mod msgpack;
+pub mod protect;
+pub mod raw_bytes;
+pub mod sqlite3;
+pub mod stock;
 pub mod util;
 
 pub fn module() -> ffi::VALUE {
@@ -46,7 +57,11 @@ static mut MODULE: Option<ffi::VALUE> = None;
 pub unsafe extern "C" fn Init_native() {
     ::MODULE = Some(ffi::rb_define_module(cstr!("RailsFastAttributes")));
 
+    protect::init();
+    load::init();
+    stock::init();
     attribute::init();
     attribute_set::init();
     builder::init();
+    identity_map::init();
 }
@@ -0,0 +1,100 @@
+//! Validation for the data given to `_load_data` and `init_with`. It comes
+//! from Marshal and YAML payloads, which may have been produced by another
+//! version of this gem, or be corrupted, so none of it can be trusted.
+
+use attribute::Attribute;
+use ffi;
+use protect::protect;
+use util::*;
+
+/// Raises `RailsFastAttributes::LoadError` with the given message.
+pub fn load_error<S: AsRef<str>>(message: S) -> ! {
+    raise(load_error_class(), message)
+}
+
+/// Returns the length of `value`, raising unless it is an `Array` with
+/// between `min` and `max` elements.
+pub unsafe fn expect_array(value: ffi::VALUE, min: usize, max: usize, what: &str) -> usize {
+    if !ffi::RB_TYPE_P(value, ffi::T_ARRAY) {
+        load_error(format!("expected {} to be an Array, got {}", what, class_name(value)));
+    }
+    let len = ffi::RARRAY_LEN(value) as usize;
+    if len < min || len > max {
+        load_error(format!(
+            "expected {} to have {} to {} elements, got {}",
+            what, min, max, len
+        ));
+    }
+    len
+}
+
+pub unsafe fn expect_hash(value: ffi::VALUE, what: &str) {
+    if !ffi::RB_TYPE_P(value, ffi::T_HASH) {
+        load_error(format!("expected {} to be a Hash, got {}", what, class_name(value)));
+    }
+}
+
+pub unsafe fn expect_attribute<'a>(value: ffi::VALUE, what: &str) -> &'a Attribute {
+    if !is_instance_of::<Attribute>(value) {
+        load_error(format!(
+            "expected {} to be an Attribute, got {}",
+            what,
+            class_name(value)
+        ));
+    }
+    get_struct(value)
+}
+
+pub unsafe fn expect_optional_attribute(value: ffi::VALUE, what: &str) -> Option<Attribute> {
+    if ffi::RB_NIL_P(value) {
+        None
+    } else {
+        Some(expect_attribute(value, what).clone())
+    }
+}
+
+/// Returns `value` as an integer, raising unless it is a `Fixnum` between
+/// `min` and `max`.
+pub unsafe fn expect_integer(value: ffi::VALUE, min: i32, max: i32, what: &str) -> i32 {
+    let in_range = ffi::RB_TYPE_P(value, ffi::T_FIXNUM) && {
+        let (min, max) = (ffi::I322NUM(min), ffi::I322NUM(max));
+        ffi::RTEST(protect(|| ffi::rb_funcall(value, id!("between?"), 2, min, max)))
+    };
+    if !in_range {
+        load_error(format!(
+            "expected {} to be an Integer from {} to {}, got {}",
+            what,
+            min,
+            max,
+            class_name(value)
+        ));
+    }
+    protect(|| ffi::NUM2I32(value))
+}
+
+pub unsafe fn expect_key(value: ffi::VALUE, what: &str) -> ffi::ID {
+    if !ffi::RB_TYPE_P(value, ffi::T_STRING) && !ffi::RB_TYPE_P(value, ffi::T_SYMBOL) {
+        load_error(format!(
+            "expected {} to be a String or Symbol, got {}",
+            what,
+            class_name(value)
+        ));
+    }
+    string_or_symbol_to_id(value)
+}
+
+pub unsafe fn class_name(value: ffi::VALUE) -> String {
+    to_rust_string(protect(|| ffi::rb_funcall(ffi::rb_obj_class(value), id!("to_s"), 0)))
+}
+
+pub fn load_error_class() -> ffi::VALUE {
+    unsafe { LOAD_ERROR }.unwrap()
+}
+
+static mut LOAD_ERROR: Option<ffi::VALUE> = None;
+
+pub unsafe fn init() {
+    let standard_error = ffi::rb_const_get(ffi::rb_cObject, id!("StandardError"));
+    let load_error = ffi::rb_define_class_under(::module(), cstr!("LoadError"), standard_error);
+    LOAD_ERROR = Some(load_error);
+}
@@ -0,0 +1,239 @@
+//! A minimal MessagePack encoder and decoder, covering the types which can
+//! come back from a database adapter as raw values.
+
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Writer { buf: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn write_nil(&mut self) {
+        self.buf.push(0xc0);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.buf.push(if value { 0xc3 } else { 0xc2 });
+    }
+
+    pub fn write_int(&mut self, value: i64) {
+        if value >= 0 && value < 0x80 {
+            self.buf.push(value as u8);
+        } else if value < 0 && value >= -32 {
+            self.buf.push(value as i8 as u8);
+        } else if value >= 0 {
+            self.write_uint(value as u64);
+        } else if value >= i64::from(i8::min_value()) {
+            self.buf.push(0xd0);
+            self.buf.push(value as i8 as u8);
+        } else if value >= i64::from(i16::min_value()) {
+            self.buf.push(0xd1);
+            self.write_be(value as u64, 2);
+        } else if value >= i64::from(i32::min_value()) {
+            self.buf.push(0xd2);
+            self.write_be(value as u64, 4);
+        } else {
+            self.buf.push(0xd3);
+            self.write_be(value as u64, 8);
+        }
+    }
+
+    pub fn write_uint(&mut self, value: u64) {
+        if value < 0x80 {
+            self.buf.push(value as u8);
+        } else if value <= u64::from(u8::max_value()) {
+            self.buf.push(0xcc);
+            self.buf.push(value as u8);
+        } else if value <= u64::from(u16::max_value()) {
+            self.buf.push(0xcd);
+            self.write_be(value, 2);
+        } else if value <= u64::from(u32::max_value()) {
+            self.buf.push(0xce);
+            self.write_be(value, 4);
+        } else {
+            self.buf.push(0xcf);
+            self.write_be(value, 8);
+        }
+    }
+
+    pub fn write_float(&mut self, value: f64) {
+        self.buf.push(0xcb);
+        self.write_be(value.to_bits(), 8);
+    }
+
+    pub fn write_str(&mut self, value: &[u8]) {
+        let len = value.len();
+        if len < 32 {
+            self.buf.push(0xa0 | len as u8);
+        } else {
+            self.write_len(len, [0xd9, 0xda, 0xdb]);
+        }
+        self.buf.extend_from_slice(value);
+    }
+
+    pub fn write_bin(&mut self, value: &[u8]) {
+        self.write_len(value.len(), [0xc4, 0xc5, 0xc6]);
+        self.buf.extend_from_slice(value);
+    }
+
+    pub fn write_array_len(&mut self, len: usize) {
+        if len < 16 {
+            self.buf.push(0x90 | len as u8);
+        } else if len <= u16::max_value() as usize {
+            self.buf.push(0xdc);
+            self.write_be(len as u64, 2);
+        } else {
+            self.buf.push(0xdd);
+            self.write_be(len as u64, 4);
+        }
+    }
+
+    /// Writes an extension value with no payload other than `data`.
+    pub fn write_ext(&mut self, ty: i8, data: u8) {
+        self.buf.push(0xd4);
+        self.buf.push(ty as u8);
+        self.buf.push(data);
+    }
+
+    fn write_len(&mut self, len: usize, markers: [u8; 3]) {
+        if len <= u8::max_value() as usize {
+            self.buf.push(markers[0]);
+            self.buf.push(len as u8);
+        } else if len <= u16::max_value() as usize {
+            self.buf.push(markers[1]);
+            self.write_be(len as u64, 2);
+        } else {
+            self.buf.push(markers[2]);
+            self.write_be(len as u64, 4);
+        }
+    }
+
+    fn write_be(&mut self, value: u64, bytes: usize) {
+        for i in (0..bytes).rev() {
+            self.buf.push((value >> (i * 8)) as u8);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value<'a> {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Str(&'a [u8]),
+    Bin(&'a [u8]),
+    Array(usize),
+    Ext(i8, &'a [u8]),
+}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+pub type Result<T> = ::std::result::Result<T, &'static str>;
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, pos: 0 }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    pub fn read(&mut self) -> Result<Value<'a>> {
+        use self::Value::*;
+
+        let marker = self.take(1)?[0];
+        let value = match marker {
+            0x00..=0x7f => Int(i64::from(marker)),
+            0x80..=0x8f => return Err("maps are not supported"),
+            0x90..=0x9f => Array((marker & 0x0f) as usize),
+            0xa0..=0xbf => Str(self.take((marker & 0x1f) as usize)?),
+            0xc0 => Nil,
+            0xc2 => Bool(false),
+            0xc3 => Bool(true),
+            0xc4 => Bin(self.take_sized(1)?),
+            0xc5 => Bin(self.take_sized(2)?),
+            0xc6 => Bin(self.take_sized(4)?),
+            0xca => {
+                let bits = self.read_be(4)? as u32;
+                Float(f64::from(f32::from_bits(bits)))
+            }
+            0xcb => Float(f64::from_bits(self.read_be(8)?)),
+            0xcc => UInt(self.read_be(1)?),
+            0xcd => UInt(self.read_be(2)?),
+            0xce => UInt(self.read_be(4)?),
+            0xcf => UInt(self.read_be(8)?),
+            0xd0 => Int(i64::from(self.read_be(1)? as u8 as i8)),
+            0xd1 => Int(i64::from(self.read_be(2)? as u16 as i16)),
+            0xd2 => Int(i64::from(self.read_be(4)? as u32 as i32)),
+            0xd3 => Int(self.read_be(8)? as i64),
+            0xd4 => {
+                let ty = self.take(1)?[0] as i8;
+                Ext(ty, self.take(1)?)
+            }
+            0xd9 => Str(self.take_sized(1)?),
+            0xda => Str(self.take_sized(2)?),
+            0xdb => Str(self.take_sized(4)?),
+            0xdc => Array(self.read_be(2)? as usize),
+            0xdd => Array(self.read_be(4)? as usize),
+            0xe0..=0xff => Int(i64::from(marker as i8)),
+            _ => return Err("unsupported MessagePack type"),
+        };
+        Ok(value)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() - self.pos < len {
+            return Err("unexpected end of MessagePack data");
+        }
+        let result = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(result)
+    }
+
+    fn take_sized(&mut self, len_bytes: usize) -> Result<&'a [u8]> {
+        let len = self.read_be(len_bytes)? as usize;
+        self.take(len)
+    }
+
+    fn read_be(&mut self, bytes: usize) -> Result<u64> {
+        Ok(self.take(bytes)?
+            .iter()
+            .fold(0, |acc, &byte| (acc << 8) | u64::from(byte)))
+    }
+}
+
+/// A stable 64 bit FNV-1a hash. Unlike `DefaultHasher`, the result is the
+/// same across processes and Rust versions.
+pub struct Fingerprint(u64);
+
+impl Fingerprint {
+    pub fn new() -> Self {
+        Fingerprint(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+        // Separate consecutive writes, so ("ab", "c") != ("a", "bc")
+        self.0 ^= 0xff;
+        self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
@@ -0,0 +1,215 @@
+//! Ruby reports exceptions by `longjmp`ing to the nearest `rescue`, which
+//! skips over any Rust frames in between without running their destructors.
+//! Rust panics, on the other hand, must never unwind into C.
+//!
+//! To keep the two apart, every call into Ruby which might raise goes through
+//! `protect`, which catches the exception with `rb_protect` and resumes it as
+//! a Rust unwind. Every `extern "C"` function Ruby calls into wraps its body in
+//! `ruby_boundary`, which turns the unwind back into a Ruby exception once all
+//! Rust frames have been cleaned up.
+
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+
+use {ffi, libc};
+use util::is_kind_of;
+
+extern "C" {
+    fn rb_protect(
+        func: extern "C" fn(*mut libc::c_void) -> ffi::VALUE,
+        arg: *mut libc::c_void,
+        state: *mut libc::c_int,
+    ) -> ffi::VALUE;
+    fn rb_jump_tag(state: libc::c_int) -> !;
+    fn rb_errinfo() -> ffi::VALUE;
+    fn rb_set_errinfo(error: ffi::VALUE);
+}
+
+/// The state `rb_protect` reports for an exception, as opposed to `throw`,
+/// `break`, etc.
+const TAG_RAISE: libc::c_int = 6;
+
+/// The payload of an unwind caused by a Ruby exception (or `break`, `throw`,
+/// etc). The exception itself is still stored in `$!`, so all we need to
+/// re-raise it is the tag.
+struct RubyException(libc::c_int);
+
+/// Calls `f`, which may raise a Ruby exception. If it does, the exception is
+/// resumed as a Rust unwind, which will be turned back into a Ruby exception
+/// by the enclosing `ruby_boundary`.
+///
+/// Ruby will `longjmp` out of `f` when it raises, so `f` should be a thin
+/// wrapper around the call into Ruby, and not own anything that needs to be
+/// dropped.
+pub fn protect<T, F>(f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    struct Data<T, F> {
+        func: Option<F>,
+        result: Option<T>,
+        panic: Option<Box<dyn Any + Send>>,
+    }
+
+    extern "C" fn call<T, F>(data: *mut libc::c_void) -> ffi::VALUE
+    where
+        F: FnOnce() -> T,
+    {
+        let data = unsafe { &mut *(data as *mut Data<T, F>) };
+        if let Some(func) = data.func.take() {
+            match panic::catch_unwind(AssertUnwindSafe(func)) {
+                Ok(result) => data.result = Some(result),
+                Err(payload) => data.panic = Some(payload),
+            }
+        }
+        unsafe { ffi::Qnil }
+    }
+
+    let mut data = Data {
+        func: Some(f),
+        result: None,
+        panic: None,
+    };
+    let mut state = 0;
+    unsafe {
+        rb_protect(call::<T, F>, &mut data as *mut _ as *mut _, &mut state);
+    }
+
+    if let Some(payload) = data.panic {
+        panic::resume_unwind(payload);
+    }
+    match data.result {
+        Some(result) if state == 0 => result,
+        _ => panic::resume_unwind(Box::new(RubyException(state))),
+    }
+}
+
+/// Runs the body of a function called from Ruby. Ruby exceptions raised
+/// inside of `f` are re-raised once every Rust frame has been dropped, and
+/// panics are raised as `RailsFastAttributes::PanicError`.
+pub fn ruby_boundary<F>(f: F) -> ffi::VALUE
+where
+    F: FnOnce() -> ffi::VALUE,
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(payload) => reraise(payload),
+    }
+}
+
+/// Calls `f`, returning the exception instead of unwinding if it raises a
+/// `StandardError`, like `rescue => e` would. Anything else, including
+/// panics, continues to unwind.
+pub fn rescue<T, F>(f: F) -> Result<T, ffi::VALUE>
+where
+    F: FnOnce() -> T,
+{
+    let payload = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => return Ok(result),
+        Err(payload) => payload,
+    };
+
+    if let Some(&RubyException(TAG_RAISE)) = payload.downcast_ref::<RubyException>() {
+        unsafe {
+            let exception = rb_errinfo();
+            if is_kind_of(exception, ffi::rb_eStandardError) {
+                rb_set_errinfo(ffi::Qnil);
+                return Err(exception);
+            }
+        }
+    }
+    panic::resume_unwind(payload)
+}
+
+/// Like `ruby_boundary`, for functions which are called by Ruby when raising
+/// is not an option (e.g. during GC). Panics are swallowed.
+pub fn silent_boundary<F>(f: F)
+where
+    F: FnOnce(),
+{
+    let _ = panic::catch_unwind(AssertUnwindSafe(f));
+}
+
+/// Iterates over a Ruby `Hash`. Exceptions and panics raised by `f` stop the
+/// iteration and are resumed after `rb_hash_foreach` has returned, so they
+/// never unwind through Ruby's C frames.
+pub fn hash_foreach<F>(hash: ffi::VALUE, f: F)
+where
+    F: FnMut(ffi::VALUE, ffi::VALUE),
+{
+    struct Data<F> {
+        func: F,
+        panic: Option<Box<dyn Any + Send>>,
+    }
+
+    extern "C" fn call<F>(
+        key: ffi::VALUE,
+        value: ffi::VALUE,
+        data: *mut libc::c_void,
+    ) -> ffi::st_retval
+    where
+        F: FnMut(ffi::VALUE, ffi::VALUE),
+    {
+        let data = unsafe { &mut *(data as *mut Data<F>) };
+        let func = &mut data.func;
+        match panic::catch_unwind(AssertUnwindSafe(|| func(key, value))) {
+            Ok(()) => ffi::st_retval::ST_CONTINUE,
+            Err(payload) => {
+                data.panic = Some(payload);
+                ffi::st_retval::ST_STOP
+            }
+        }
+    }
+
+    let mut data = Data {
+        func: f,
+        panic: None,
+    };
+    protect(|| unsafe {
+        ffi::rb_hash_foreach(hash, call::<F>, &mut data as *mut _ as *mut _);
+    });
+
+    if let Some(payload) = data.panic {
+        panic::resume_unwind(payload);
+    }
+}
+
+fn reraise(payload: Box<dyn Any + Send>) -> ! {
+    let message = match payload.downcast::<RubyException>() {
+        Ok(exception) => {
+            let state = exception.0;
+            drop(exception);
+            unsafe { rb_jump_tag(state) }
+        }
+        Err(payload) => panic_message(&*payload),
+    };
+    unsafe {
+        let message_str =
+            ffi::rb_utf8_str_new(message.as_ptr() as *const _, message.len() as _);
+        drop(message);
+        ffi::rb_raise(panic_error(), cstr!("%s"), ffi::RSTRING_PTR(message_str))
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    let message = if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.as_str()
+    } else {
+        "unknown error"
+    };
+    format!("rails_fast_attributes panicked: {}", message)
+}
+
+fn panic_error() -> ffi::VALUE {
+    unsafe { PANIC_ERROR }.unwrap()
+}
+
+static mut PANIC_ERROR: Option<ffi::VALUE> = None;
+
+pub unsafe fn init() {
+    let panic_error =
+        ffi::rb_define_class_under(::module(), cstr!("PanicError"), ffi::rb_eRuntimeError);
+    PANIC_ERROR = Some(panic_error);
+}
@@ -0,0 +1,98 @@
+//! Raw database values which are still in the buffer the adapter read the
+//! result into. Most raw values are never read as strings, only cast, so we
+//! only create a Ruby `String` for one when something asks for it.
+
+use std::rc::Rc;
+use std::str;
+
+use ffi;
+use libc;
+use util::{is_utf8_compatible, string_bytes};
+
+/// A slice of a buffer shared by every row of one result.
+#[derive(Clone)]
+pub struct RawBytes {
+    buffer: Rc<Vec<u8>>,
+    start: usize,
+    len: usize,
+    /// Whether this is a blob, which becomes a binary string rather than a
+    /// UTF-8 one
+    binary: bool,
+}
+
+/// A raw value of a row, which is either an object the adapter already
+/// created, such as `nil` or an `Integer`, or bytes in a buffer.
+pub enum RawValue {
+    Value(ffi::VALUE),
+    Bytes(RawBytes),
+}
+
+impl RawBytes {
+    /// Panics if `start..start + len` is out of bounds.
+    pub fn new(buffer: &Rc<Vec<u8>>, start: usize, len: usize, binary: bool) -> Self {
+        assert!(start + len <= buffer.len(), "raw value out of bounds");
+        RawBytes {
+            buffer: buffer.clone(),
+            start,
+            len,
+            binary,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[self.start..self.start + self.len]
+    }
+
+    pub fn is_binary(&self) -> bool {
+        self.binary
+    }
+
+    /// A new Ruby `String` with these bytes.
+    pub fn to_ruby(&self) -> ffi::VALUE {
+        let bytes = self.as_bytes();
+        let ptr = bytes.as_ptr() as *const libc::c_char;
+        let len = bytes.len() as libc::c_long;
+        unsafe {
+            if self.binary {
+                ffi::rb_str_new(ptr, len)
+            } else {
+                ffi::rb_utf8_str_new(ptr, len)
+            }
+        }
+    }
+
+    /// The value of a decimal integer which fits in an `i64`, such as the
+    /// text of an integer column. Anything else, including leading zeroes
+    /// or whitespace which `String#to_i` would accept, is `None`, and left
+    /// for Ruby to parse.
+    pub fn parse_integer(&self) -> Option<i64> {
+        let bytes = self.as_bytes();
+        let digits = if bytes.first() == Some(&b'-') {
+            &bytes[1..]
+        } else {
+            bytes
+        };
+        let canonical = !digits.is_empty()
+            && digits.iter().all(|b| b.is_ascii_digit())
+            && (digits == b"0" || digits[0] != b'0');
+        if !canonical || self.binary {
+            return None;
+        }
+        str::from_utf8(bytes).ok().and_then(|s| s.parse().ok())
+    }
+}
+
+impl PartialEq for RawBytes {
+    fn eq(&self, other: &Self) -> bool {
+        self.binary == other.binary && self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl Eq for RawBytes {}
+
+/// Copies a Ruby `String` into a new buffer, returning it and whether values
+/// in it should be binary strings, which they are unless it is UTF-8.
+pub unsafe fn buffer_from_ruby(value: ffi::VALUE) -> (Rc<Vec<u8>>, bool) {
+    let bytes = string_bytes(value).to_vec();
+    (Rc::new(bytes), !is_utf8_compatible(value))
+}
@@ -0,0 +1,274 @@
+//! Reading rows straight out of a prepared statement of the `sqlite3` gem,
+//! without the arrays and strings the gem would create for every row.
+//!
+//! We don't link SQLite ourselves. The statement has to be stepped by the
+//! very same copy of SQLite the gem uses, so its functions are looked up in
+//! the gem's extension (and the libraries it links) when first needed.
+
+use std::ffi::{CStr, CString};
+use std::rc::Rc;
+use std::{mem, slice};
+
+use ffi;
+use libc::{self, c_char, c_double, c_int, c_void};
+use load::class_name;
+use protect::protect;
+use raw_bytes::{RawBytes, RawValue};
+use util::*;
+
+extern "C" {
+    fn rb_gv_get(name: *const c_char) -> ffi::VALUE;
+}
+
+const SQLITE_ROW: c_int = 100;
+const SQLITE_DONE: c_int = 101;
+
+const SQLITE_INTEGER: c_int = 1;
+const SQLITE_FLOAT: c_int = 2;
+const SQLITE_TEXT: c_int = 3;
+const SQLITE_BLOB: c_int = 4;
+
+/// The struct the `sqlite3` gem wraps a statement in. Later versions have
+/// more fields, but the statement is always the first one.
+#[repr(C)]
+struct StatementData {
+    st: *mut c_void,
+}
+
+/// The functions of SQLite we need
+#[derive(Clone, Copy)]
+struct Sqlite {
+    step: unsafe extern "C" fn(*mut c_void) -> c_int,
+    column_count: unsafe extern "C" fn(*mut c_void) -> c_int,
+    column_name: unsafe extern "C" fn(*mut c_void, c_int) -> *const c_char,
+    column_type: unsafe extern "C" fn(*mut c_void, c_int) -> c_int,
+    column_int64: unsafe extern "C" fn(*mut c_void, c_int) -> i64,
+    column_double: unsafe extern "C" fn(*mut c_void, c_int) -> c_double,
+    column_text: unsafe extern "C" fn(*mut c_void, c_int) -> *const u8,
+    column_blob: unsafe extern "C" fn(*mut c_void, c_int) -> *const c_void,
+    column_bytes: unsafe extern "C" fn(*mut c_void, c_int) -> c_int,
+    db_handle: unsafe extern "C" fn(*mut c_void) -> *mut c_void,
+    errmsg: unsafe extern "C" fn(*mut c_void) -> *const c_char,
+}
+
+static mut SQLITE: Option<Sqlite> = None;
+
+/// The result of stepping through a statement. `cells` holds the values of
+/// every row in order, and `keep_alive` the objects among them, which are
+/// otherwise only referenced from Rust.
+pub struct Rows {
+    pub columns: ffi::VALUE,
+    pub cells: Vec<RawValue>,
+    pub keep_alive: ffi::VALUE,
+}
+
+/// Steps through the remaining rows of `statement`, a `SQLite3::Statement`
+/// whose parameters have already been bound. Integers, reals and `NULL`
+/// become Ruby objects, while text and blobs are kept in one buffer for the
+/// whole result.
+///
+/// The gem doesn't see us step, so afterwards (or after an error) we call
+/// `reset!`, which keeps the bound parameters. The statement is then in the
+/// same state as before it was first stepped, rather than one where the gem
+/// thinks there are rows left. Errors raise the subclass of
+/// `SQLite3::Exception` the gem would raise for the same result code.
+pub unsafe fn read_statement(statement: ffi::VALUE) -> Rows {
+    let statement_class = sqlite3_class(cstr!("Statement"));
+    if !is_kind_of(statement, statement_class) {
+        raise(
+            ffi::rb_eTypeError,
+            format!("expected a SQLite3::Statement, got {}", class_name(statement)),
+        );
+    }
+    if ffi::RTEST(protect(|| ffi::rb_funcall(statement, id!("closed?"), 0))) {
+        raise(sqlite3_class(cstr!("Exception")), "cannot use a closed statement");
+    }
+    let st = (*(ffi::Data_Get_Struct_Value(statement) as *const StatementData)).st;
+    if st.is_null() {
+        raise(sqlite3_class(cstr!("Exception")), "cannot use a closed statement");
+    }
+    let sqlite = sqlite();
+
+    let column_count = (sqlite.column_count)(st);
+    let columns = ffi::rb_ary_new_capa(column_count as isize);
+    for i in 0..column_count {
+        let name = CStr::from_ptr((sqlite.column_name)(st, i)).to_bytes();
+        ffi::rb_ary_push(
+            columns,
+            ffi::rb_utf8_str_new(name.as_ptr() as *const _, name.len() as _),
+        );
+    }
+
+    // Text and blobs are copied into `buffer` as we go, and only turned into
+    // `RawBytes` once it's complete.
+    enum Cell {
+        Value(ffi::VALUE),
+        Bytes(usize, usize, bool),
+    }
+    let keep_alive = ffi::rb_ary_new();
+    let mut buffer = Vec::new();
+    let mut cells = Vec::new();
+    loop {
+        match (sqlite.step)(st) {
+            SQLITE_ROW => {}
+            SQLITE_DONE => break,
+            code => {
+                let message = CStr::from_ptr((sqlite.errmsg)((sqlite.db_handle)(st)))
+                    .to_string_lossy()
+                    .into_owned();
+                protect(|| ffi::rb_funcall(statement, id!("reset!"), 0));
+                raise(sqlite3_class(exception_name(code)), message);
+            }
+        }
+        for i in 0..column_count {
+            let cell = match (sqlite.column_type)(st, i) {
+                SQLITE_INTEGER => Cell::Value(ffi::I642NUM((sqlite.column_int64)(st, i))),
+                SQLITE_FLOAT => Cell::Value(ffi::F642NUM((sqlite.column_double)(st, i))),
+                ty @ SQLITE_TEXT | ty @ SQLITE_BLOB => {
+                    // The pointer has to be fetched before the length
+                    let ptr = if ty == SQLITE_TEXT {
+                        (sqlite.column_text)(st, i)
+                    } else {
+                        (sqlite.column_blob)(st, i) as *const u8
+                    };
+                    let len = (sqlite.column_bytes)(st, i) as usize;
+                    let start = buffer.len();
+                    if len > 0 {
+                        buffer.extend_from_slice(slice::from_raw_parts(ptr, len));
+                    }
+                    Cell::Bytes(start, len, ty == SQLITE_BLOB)
+                }
+                _ => Cell::Value(ffi::Qnil),
+            };
+            if let Cell::Value(value) = cell {
+                if !ffi::RB_NIL_P(value) {
+                    ffi::rb_ary_push(keep_alive, value);
+                }
+            }
+            cells.push(cell);
+        }
+    }
+
+    protect(|| ffi::rb_funcall(statement, id!("reset!"), 0));
+
+    let buffer = Rc::new(buffer);
+    let cells = cells
+        .into_iter()
+        .map(|cell| match cell {
+            Cell::Value(value) => RawValue::Value(value),
+            Cell::Bytes(start, len, binary) => {
+                RawValue::Bytes(RawBytes::new(&buffer, start, len, binary))
+            }
+        })
+        .collect();
+    Rows {
+        columns,
+        cells,
+        keep_alive,
+    }
+}
+
+fn sqlite() -> Sqlite {
+    unsafe {
+        if let Some(sqlite) = SQLITE {
+            return sqlite;
+        }
+        let sqlite = load_sqlite();
+        SQLITE = Some(sqlite);
+        sqlite
+    }
+}
+
+/// Looks SQLite's functions up in the `sqlite3` gem's extension, which
+/// `$LOADED_FEATURES` has the path of.
+unsafe fn load_sqlite() -> Sqlite {
+    let features = protect(|| rb_gv_get(cstr!("$LOADED_FEATURES")));
+    let extension = (0..ffi::RARRAY_LEN(features))
+        .map(|i| ffi::rb_ary_entry(features, i))
+        .find(|&path| {
+            let path = string_bytes(path);
+            path.ends_with(b"/sqlite3_native.so") || path.ends_with(b"/sqlite3_native.bundle")
+        });
+    let extension = match extension {
+        Some(path) => CString::new(string_bytes(path)).unwrap_or_else(|_| {
+            raise(ffi::rb_eRuntimeError, "the sqlite3 gem's path contains a NUL byte")
+        }),
+        None => raise(
+            ffi::rb_eRuntimeError,
+            "the sqlite3 gem has to be loaded to read from its statements",
+        ),
+    };
+    let handle = libc::dlopen(extension.as_ptr(), libc::RTLD_LAZY | libc::RTLD_NOLOAD);
+    if handle.is_null() {
+        raise(ffi::rb_eRuntimeError, "couldn't open the sqlite3 gem's extension");
+    }
+
+    macro_rules! function {
+        ($name:expr) => {{
+            let function = libc::dlsym(handle, cstr!($name));
+            if function.is_null() {
+                raise(
+                    ffi::rb_eRuntimeError,
+                    concat!("the sqlite3 gem doesn't export ", $name),
+                );
+            }
+            mem::transmute(function)
+        }};
+    }
+
+    Sqlite {
+        step: function!("sqlite3_step"),
+        column_count: function!("sqlite3_column_count"),
+        column_name: function!("sqlite3_column_name"),
+        column_type: function!("sqlite3_column_type"),
+        column_int64: function!("sqlite3_column_int64"),
+        column_double: function!("sqlite3_column_double"),
+        column_text: function!("sqlite3_column_text"),
+        column_blob: function!("sqlite3_column_blob"),
+        column_bytes: function!("sqlite3_column_bytes"),
+        db_handle: function!("sqlite3_db_handle"),
+        errmsg: function!("sqlite3_errmsg"),
+    }
+}
+
+/// The class the gem raises for a result code, as in its `exception.c`.
+/// Extended result codes are grouped by their primary code.
+fn exception_name(code: c_int) -> *const c_char {
+    match code & 0xff {
+        1 => cstr!("SQLException"),
+        2 => cstr!("InternalException"),
+        3 => cstr!("PermissionException"),
+        4 => cstr!("AbortException"),
+        5 => cstr!("BusyException"),
+        6 => cstr!("LockedException"),
+        7 => cstr!("MemoryException"),
+        8 => cstr!("ReadOnlyException"),
+        9 => cstr!("InterruptException"),
+        10 => cstr!("IOException"),
+        11 => cstr!("CorruptException"),
+        12 => cstr!("NotFoundException"),
+        13 => cstr!("FullException"),
+        14 => cstr!("CantOpenException"),
+        15 => cstr!("ProtocolException"),
+        16 => cstr!("EmptyException"),
+        17 => cstr!("SchemaChangedException"),
+        18 => cstr!("TooBigException"),
+        19 => cstr!("ConstraintException"),
+        20 => cstr!("MismatchException"),
+        21 => cstr!("MisuseException"),
+        22 => cstr!("UnsupportedException"),
+        23 => cstr!("AuthorizationException"),
+        24 => cstr!("FormatException"),
+        25 => cstr!("RangeException"),
+        26 => cstr!("NotADatabaseException"),
+        _ => cstr!("Exception"),
+    }
+}
+
+/// `SQLite3::<name>`
+unsafe fn sqlite3_class(name: *const c_char) -> ffi::VALUE {
+    protect(|| {
+        let sqlite3 = ffi::rb_const_get(ffi::rb_cObject, id!("SQLite3"));
+        ffi::rb_const_get(sqlite3, ffi::rb_intern(name))
+    })
+}
@@ -0,0 +1,131 @@
+//! Marshal compatibility with stock ActiveModel.
+//!
+//! Stock `ActiveModel::AttributeSet` and `ActiveModel::Attribute` are plain
+//! Ruby objects, so Marshal writes them as a class name and a list of
+//! instance variables. Our objects are `T_DATA`, which Marshal refuses to
+//! load instance variables into.
+//!
+//! Ruby handles this case for its own classes with
+//! `rb_marshal_define_compat`: when loading an object of one of our stock
+//! classes, Marshal allocates a plain `StockObject` instead, loads the
+//! instance variables (or calls `marshal_load`) on that, and then hands both
+//! objects to our loader. When dumping, our dumper returns a `StockObject`
+//! holding the instance variables stock ActiveModel expects, which Marshal
+//! writes under the name of our class.
+//!
+//! The stock classes are anonymous subclasses of `Attribute` and
+//! `AttributeSet`, which are given the names of their stock counterparts in
+//! `lib/rails_fast_attributes.rb`. Marshal finds the compat entry by the
+//! allocator function, so they use `allocate` from this module rather than
+//! `Allocate::allocate`, which would make our own format unloadable.
+//!
+//! `AttributeSet` itself is loaded this way too, since stock dumps refer to
+//! it by the name `ActiveModel::AttributeSet`. Its dumper writes our own
+//! format into the `StockObject` unless the set is an instance of the stock
+//! class, which `AttributeSet#to_stock` returns a copy as. Marshal won't load
+//! `T_DATA` into a class with a compat entry, so sets dumped by versions of
+//! this gem which wrote them with `_dump_data` can no longer be loaded.
+
+use ffi;
+use into_ruby::IntoRuby;
+use protect::ruby_boundary;
+
+extern "C" {
+    fn rb_marshal_define_compat(
+        newclass: ffi::VALUE,
+        oldclass: ffi::VALUE,
+        dumper: extern "C" fn(ffi::VALUE) -> ffi::VALUE,
+        loader: extern "C" fn(ffi::VALUE, ffi::VALUE) -> ffi::VALUE,
+    );
+    fn rb_class_new(superclass: ffi::VALUE) -> ffi::VALUE;
+    fn rb_obj_alloc(class: ffi::VALUE) -> ffi::VALUE;
+    fn rb_ivar_get(object: ffi::VALUE, name: ffi::ID) -> ffi::VALUE;
+    fn rb_ivar_set(object: ffi::VALUE, name: ffi::ID, value: ffi::VALUE) -> ffi::VALUE;
+    fn rb_ivar_defined(object: ffi::VALUE, name: ffi::ID) -> ffi::VALUE;
+}
+
+/// Defines an anonymous subclass of `superclass` which is loaded from and
+/// dumped to the stock ActiveModel format by `loader` and `dumper`.
+pub unsafe fn define_class<T: Default + IntoRuby>(
+    superclass: ffi::VALUE,
+    dumper: extern "C" fn(ffi::VALUE) -> ffi::VALUE,
+    loader: extern "C" fn(ffi::VALUE, ffi::VALUE) -> ffi::VALUE,
+) -> ffi::VALUE {
+    let class = rb_class_new(superclass);
+    define_compat::<T>(class, dumper, loader);
+    class
+}
+
+/// Has Marshal load and dump instances of `class` and its subclasses with
+/// `loader` and `dumper`.
+pub unsafe fn define_compat<T: Default + IntoRuby>(
+    class: ffi::VALUE,
+    dumper: extern "C" fn(ffi::VALUE) -> ffi::VALUE,
+    loader: extern "C" fn(ffi::VALUE, ffi::VALUE) -> ffi::VALUE,
+) {
+    ffi::rb_define_alloc_func(class, allocate::<T>);
+    rb_marshal_define_compat(class, stock_object(), dumper, loader);
+}
+
+/// Defines an anonymous subclass of `superclass` which shares its allocator,
+/// and so its compat entry.
+pub unsafe fn define_subclass(superclass: ffi::VALUE) -> ffi::VALUE {
+    rb_class_new(superclass)
+}
+
+extern "C" fn allocate<T: Default + IntoRuby>(class: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| unsafe { T::default().into_ruby_as(class) })
+}
+
+/// Allocates the object a dumper should fill in and return.
+pub fn new_object() -> ffi::VALUE {
+    unsafe { rb_obj_alloc(stock_object()) }
+}
+
+pub fn ivar(object: ffi::VALUE, name: ffi::ID) -> Option<ffi::VALUE> {
+    unsafe {
+        if ffi::RTEST(rb_ivar_defined(object, name)) {
+            Some(rb_ivar_get(object, name))
+        } else {
+            None
+        }
+    }
+}
+
+pub fn set_ivar(object: ffi::VALUE, name: ffi::ID, value: ffi::VALUE) {
+    unsafe {
+        rb_ivar_set(object, name, value);
+    }
+}
+
+/// The data given to `marshal_load`, for stock classes which define
+/// `marshal_dump`, or our own format as written by `AttributeSet`'s dumper.
+pub fn marshal_data(object: ffi::VALUE) -> Option<ffi::VALUE> {
+    ivar(object, id!("@marshal_data"))
+}
+
+fn stock_object() -> ffi::VALUE {
+    unsafe { STOCK_OBJECT }.unwrap()
+}
+
+static mut STOCK_OBJECT: Option<ffi::VALUE> = None;
+
+pub unsafe fn init() {
+    let stock_object =
+        ffi::rb_define_class_under(::module(), cstr!("StockObject"), ffi::rb_cObject);
+    STOCK_OBJECT = Some(stock_object);
+
+    ffi::rb_define_method(
+        stock_object,
+        cstr!("marshal_load"),
+        marshal_load as *const _,
+        1,
+    );
+}
+
+extern "C" fn marshal_load(this: ffi::VALUE, data: ffi::VALUE) -> ffi::VALUE {
+    ruby_boundary(|| {
+        set_ivar(this, id!("@marshal_data"), data);
+        unsafe { ffi::Qnil }
+    })
+}
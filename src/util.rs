@@ -1,19 +1,175 @@
-use ffi;
+use {ffi, libc};
+use into_ruby::IntoRuby;
+use protect::protect;
 
-pub unsafe fn get_struct<'a, T>(ptr: ffi::VALUE) -> &'a T {
+extern "C" {
+    fn rb_enc_get_index(value: ffi::VALUE) -> libc::c_int;
+    fn rb_utf8_encindex() -> libc::c_int;
+    fn rb_usascii_encindex() -> libc::c_int;
+    fn rb_obj_is_kind_of(value: ffi::VALUE, class: ffi::VALUE) -> ffi::VALUE;
+}
+
+pub unsafe fn get_struct<'a, T: IntoRuby>(ptr: ffi::VALUE) -> &'a T {
+    check_class::<T>(ptr);
     (ffi::Data_Get_Struct_Value(ptr) as *mut T)
         .as_ref()
-        .unwrap_or_else(|| ffi::rb_raise(ffi::rb_eRuntimeError, cstr!("Expected a T_DATA")))
+        .unwrap_or_else(|| raise(ffi::rb_eRuntimeError, "Expected a T_DATA"))
 }
 
-pub unsafe fn get_struct_mut<'a, T>(ptr: ffi::VALUE) -> &'a mut T {
+pub unsafe fn get_struct_mut<'a, T: IntoRuby>(ptr: ffi::VALUE) -> &'a mut T {
+    check_class::<T>(ptr);
     if ffi::OBJ_FROZEN(ptr) {
-        ffi::rb_raise(ffi::rb_eRuntimeError, cstr!("Can't modify frozen object"));
+        raise(ffi::rb_eRuntimeError, "Can't modify frozen object");
     }
 
     (ffi::Data_Get_Struct_Value(ptr) as *mut T)
         .as_mut()
-        .unwrap_or_else(|| ffi::rb_raise(ffi::rb_eRuntimeError, cstr!("Expected a T_DATA")))
+        .unwrap_or_else(|| raise(ffi::rb_eRuntimeError, "Expected a T_DATA"))
+}
+
+/// Raises `TypeError` unless `value` is an instance of `T::class()` or one of
+/// its subclasses. Calling `Data_Get_Struct_Value` on anything else will
+/// happily hand us a pointer to some other struct.
+pub unsafe fn check_class<T: IntoRuby>(value: ffi::VALUE) {
+    if !is_instance_of::<T>(value) {
+        wrong_argument_type(value, T::class());
+    }
+}
+
+/// Whether `value` is an instance of `T::class()` or one of its subclasses,
+/// such as the stock-named `ActiveModel::AttributeSet`. This checks the class
+/// hierarchy directly rather than calling `is_a?`, which Ruby code can
+/// override to claim to be something it isn't.
+pub unsafe fn is_instance_of<T: IntoRuby>(value: ffi::VALUE) -> bool {
+    ffi::RB_TYPE_P(value, ffi::T_DATA) && is_kind_of(value, T::class())
+}
+
+pub unsafe fn check_type(value: ffi::VALUE, ty: isize, class: ffi::VALUE) {
+    if !ffi::RB_TYPE_P(value, ty) {
+        wrong_argument_type(value, class);
+    }
+}
+
+pub unsafe fn check_hash(value: ffi::VALUE) {
+    check_type(value, ffi::T_HASH, ffi::rb_const_get(ffi::rb_cObject, id!("Hash")));
+}
+
+fn wrong_argument_type(value: ffi::VALUE, expected: ffi::VALUE) -> ! {
+    unsafe {
+        let actual = protect(|| ffi::rb_funcall(ffi::rb_obj_class(value), id!("to_s"), 0));
+        let expected = protect(|| ffi::rb_funcall(expected, id!("to_s"), 0));
+        let message = format!(
+            "wrong argument type {} (expected {})",
+            to_rust_string(actual),
+            to_rust_string(expected),
+        );
+        raise(ffi::rb_eTypeError, message)
+    }
+}
+
+/// Raises `class` with the given message. Like any other exception raised
+/// through `protect`, it will unwind to the enclosing `ruby_boundary`.
+pub fn raise<S: AsRef<str>>(class: ffi::VALUE, message: S) -> ! {
+    unsafe {
+        let message = {
+            let message = message.as_ref();
+            ffi::rb_utf8_str_new(message.as_ptr() as *const _, message.len() as _)
+        };
+        protect(|| {
+            ffi::rb_raise(class, cstr!("%s"), ffi::RSTRING_PTR(message));
+        });
+    }
+    unreachable!()
+}
+
+/// Copies the contents of a Ruby `String` into a Rust `String`.
+pub unsafe fn to_rust_string(value: ffi::VALUE) -> String {
+    use std::slice;
+
+    let bytes = slice::from_raw_parts(
+        ffi::RSTRING_PTR(value) as *const u8,
+        ffi::RSTRING_LEN(value) as usize,
+    );
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+/// Formats a `Float` the way `Float#to_s` does, which switches to exponent
+/// notation outside of `1e-4...1e16`.
+pub fn float_to_s(value: f64) -> String {
+    if value.is_nan() {
+        return "NaN".to_string();
+    } else if value.is_infinite() {
+        return if value > 0.0 { "Infinity" } else { "-Infinity" }.to_string();
+    }
+
+    let formatted = format!("{:e}", value.abs());
+    let mut parts = formatted.split('e');
+    let digits = parts.next().unwrap().replace('.', "");
+    let exponent = parts.next().unwrap().parse::<i32>().unwrap();
+    let decimal_point = exponent + 1;
+
+    let mut result = String::new();
+    if value.is_sign_negative() {
+        result.push('-');
+    }
+    if decimal_point < -3 || decimal_point > 16 {
+        result.push_str(&digits[..1]);
+        result.push('.');
+        result.push_str(if digits.len() > 1 { &digits[1..] } else { "0" });
+        let sign = if exponent < 0 { '-' } else { '+' };
+        result.push_str(&format!("e{}{:02}", sign, exponent.abs()));
+    } else if decimal_point <= 0 {
+        result.push_str("0.");
+        result.extend((0..-decimal_point).map(|_| '0'));
+        result.push_str(&digits);
+    } else if decimal_point as usize >= digits.len() {
+        result.push_str(&digits);
+        result.extend((digits.len()..decimal_point as usize).map(|_| '0'));
+        result.push_str(".0");
+    } else {
+        let (whole, fraction) = digits.split_at(decimal_point as usize);
+        result.push_str(whole);
+        result.push('.');
+        result.push_str(fraction);
+    }
+    result
+}
+
+/// The bytes of a Ruby `String`, without copying them. The slice must not be
+/// used after the string could have been modified or collected.
+pub unsafe fn string_bytes<'a>(value: ffi::VALUE) -> &'a [u8] {
+    use std::slice;
+
+    slice::from_raw_parts(ffi::RSTRING_PTR(value) as *const u8, ffi::RSTRING_LEN(value) as _)
+}
+
+/// Whether the bytes of a Ruby `String` can be used as UTF-8 as they are.
+pub unsafe fn is_utf8_compatible(value: ffi::VALUE) -> bool {
+    let encoding = rb_enc_get_index(value);
+    encoding == rb_utf8_encindex() || encoding == rb_usascii_encindex()
+}
+
+/// Returns `value`, or a copy of it transcoded to UTF-8 if it's in some
+/// other encoding.
+pub unsafe fn to_utf8(value: ffi::VALUE) -> ffi::VALUE {
+    if is_utf8_compatible(value) {
+        value
+    } else {
+        protect(|| ffi::rb_funcall(value, id!("encode"), 1, rstr!("UTF-8")))
+    }
+}
+
+/// `value.kind_of?(class)`, without calling any Ruby methods.
+pub unsafe fn is_kind_of(value: ffi::VALUE, class: ffi::VALUE) -> bool {
+    ffi::RTEST(rb_obj_is_kind_of(value, class))
+}
+
+/// Keeps `value` on the stack up to this point, where Ruby's conservative GC
+/// will find it, like `RB_GC_GUARD`.
+pub fn gc_guard(value: &ffi::VALUE) {
+    unsafe {
+        ::std::ptr::read_volatile(value);
+    }
 }
 
 pub fn to_ruby_bool(test: bool) -> ffi::VALUE {
@@ -40,7 +196,7 @@ pub fn string_or_symbol_to_id(sym_or_string: ffi::VALUE) -> ffi::ID {
         if ffi::RB_TYPE_P(sym_or_string, ffi::T_STRING) {
             ffi::rb_intern_str(sym_or_string)
         } else {
-            ffi::rb_sym2id(sym_or_string)
+            protect(|| ffi::rb_sym2id(sym_or_string))
         }
     }
 }